@@ -0,0 +1,51 @@
+use std::{
+	fmt::{self, Display, Formatter},
+	net::{IpAddr, SocketAddr},
+	path::PathBuf,
+};
+
+// an end of a connection, or the sender of a datagram, clients of a unix socket that did not bind a path are unnamed
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Address {
+	Ip(SocketAddr),
+	Unix(Option<PathBuf>),
+}
+
+impl Address {
+	pub(crate) const fn ip(&self) -> Option<IpAddr> {
+		match *self {
+			Self::Ip(addr) => Some(addr.ip()),
+			Self::Unix(_) => None,
+		}
+	}
+}
+
+impl From<SocketAddr> for Address {
+	fn from(addr: SocketAddr) -> Self {
+		Self::Ip(addr)
+	}
+}
+
+#[cfg(unix)]
+impl From<std::os::unix::net::SocketAddr> for Address {
+	fn from(addr: std::os::unix::net::SocketAddr) -> Self {
+		Self::Unix(addr.as_pathname().map(PathBuf::from))
+	}
+}
+
+#[cfg(unix)]
+impl From<tokio::net::unix::SocketAddr> for Address {
+	fn from(addr: tokio::net::unix::SocketAddr) -> Self {
+		Self::Unix(addr.as_pathname().map(PathBuf::from))
+	}
+}
+
+impl Display for Address {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		match *self {
+			Self::Ip(addr) => write!(f, "{addr}"),
+			Self::Unix(Some(ref path)) => write!(f, "{}", path.display()),
+			Self::Unix(None) => write!(f, "unnamed"),
+		}
+	}
+}
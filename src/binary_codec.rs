@@ -0,0 +1,389 @@
+use std::{
+	error,
+	fmt::{self, Display, Formatter},
+	io::Read,
+	mem::size_of,
+};
+
+use anyhow::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const READ_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum FieldErrorKind {
+	// more data is needed to decode the field, which the frame codec waits for
+	Incomplete,
+	TooLong,
+	InvalidUtf8,
+	Invalid(String),
+}
+
+// a field that cannot be decoded or encoded, named with the message it is part of, e.g. Insert.timestamp
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FieldError {
+	field: &'static str,
+	kind: FieldErrorKind,
+}
+
+impl FieldError {
+	pub(crate) const fn new(field: &'static str, kind: FieldErrorKind) -> Self {
+		Self { field, kind }
+	}
+
+	pub(crate) fn invalid(field: &'static str, reason: String) -> Self {
+		Self::new(field, FieldErrorKind::Invalid(reason))
+	}
+
+	pub(crate) fn is_incomplete(&self) -> bool {
+		self.kind == FieldErrorKind::Incomplete
+	}
+}
+
+impl Display for FieldError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		match self.kind {
+			FieldErrorKind::Incomplete => write!(f, "{}: incomplete", self.field),
+			FieldErrorKind::TooLong => write!(f, "{}: longer than the length prefix allows", self.field),
+			FieldErrorKind::InvalidUtf8 => write!(f, "{}: not valid UTF-8", self.field),
+			FieldErrorKind::Invalid(ref reason) => write!(f, "{}: {reason}", self.field),
+		}
+	}
+}
+
+impl error::Error for FieldError {}
+
+// reads the fields of a frame in order from the start of the data
+#[derive(Debug)]
+pub(crate) struct Decoder<'data> {
+	data: &'data [u8],
+	position: usize,
+}
+
+impl<'data> Decoder<'data> {
+	pub(crate) const fn new(data: &'data [u8]) -> Self {
+		Self { data, position: 0 }
+	}
+
+	fn take(&mut self, field: &'static str, size: usize) -> Result<&'data [u8], FieldError> {
+		let end = self.position + size;
+		if end > self.data.len() {
+			return Err(FieldError::new(field, FieldErrorKind::Incomplete));
+		}
+		let bytes = &self.data[self.position..end];
+		self.position = end;
+		Ok(bytes)
+	}
+
+	// whether every byte of the data has been read
+	pub(crate) fn is_empty(&self) -> bool {
+		self.position == self.data.len()
+	}
+
+	pub(crate) fn read<F: Field>(&mut self, field: &'static str) -> Result<F, FieldError> {
+		F::decode(self, field)
+	}
+
+	// bytes after their length
+	pub(crate) fn bytes<L: LengthPrefix>(&mut self, field: &'static str) -> Result<&'data [u8], FieldError> {
+		let length = self
+			.read::<L>(field)?
+			.try_into()
+			.map_err(|_e| FieldError::new(field, FieldErrorKind::TooLong))?;
+		self.take(field, length)
+	}
+
+	pub(crate) fn string<L: LengthPrefix>(&mut self, field: &'static str) -> Result<String, FieldError> {
+		let bytes = self.bytes::<L>(field)?;
+		String::from_utf8(bytes.to_vec()).map_err(|_e| FieldError::new(field, FieldErrorKind::InvalidUtf8))
+	}
+}
+
+// a value of a message, integers are big-endian and strings have a u8 length prefix
+pub(crate) trait Field: Sized {
+	fn decode(decoder: &mut Decoder<'_>, field: &'static str) -> Result<Self, FieldError>;
+
+	fn encode(&self, field: &'static str, buffer: &mut Vec<u8>) -> Result<(), FieldError>;
+}
+
+macro_rules! int_fields {
+	($($int:ty),*) => {
+		$(
+			impl Field for $int {
+				fn decode(decoder: &mut Decoder<'_>, field: &'static str) -> Result<Self, FieldError> {
+					let bytes = decoder.take(field, size_of::<Self>())?;
+					Ok(Self::from_be_bytes(bytes.try_into().expect("Decoder took the size of the integer")))
+				}
+
+				fn encode(&self, _field: &'static str, buffer: &mut Vec<u8>) -> Result<(), FieldError> {
+					buffer.extend_from_slice(&self.to_be_bytes());
+					Ok(())
+				}
+			}
+		)*
+	};
+}
+
+int_fields!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+// an integer the length of a string or of bytes is sent as
+pub(crate) trait LengthPrefix: Field + TryInto<usize> + TryFrom<usize> {}
+
+impl LengthPrefix for u8 {}
+
+impl LengthPrefix for u16 {}
+
+impl LengthPrefix for u32 {}
+
+pub(crate) fn encode_bytes<L: LengthPrefix>(
+	value: &[u8],
+	field: &'static str,
+	buffer: &mut Vec<u8>,
+) -> Result<(), FieldError> {
+	let length = L::try_from(value.len()).map_err(|_e| FieldError::new(field, FieldErrorKind::TooLong))?;
+	length.encode(field, buffer)?;
+	buffer.extend_from_slice(value);
+	Ok(())
+}
+
+pub(crate) fn encode_string<L: LengthPrefix>(
+	value: &str,
+	field: &'static str,
+	buffer: &mut Vec<u8>,
+) -> Result<(), FieldError> {
+	encode_bytes::<L>(value.as_bytes(), field, buffer)
+}
+
+impl Field for String {
+	fn decode(decoder: &mut Decoder<'_>, field: &'static str) -> Result<Self, FieldError> {
+		decoder.string::<u8>(field)
+	}
+
+	fn encode(&self, field: &'static str, buffer: &mut Vec<u8>) -> Result<(), FieldError> {
+		encode_string::<u8>(self, field, buffer)
+	}
+}
+
+// a message sent as a whole, a decode only returns Incomplete errors while the data ends before the message does
+pub(crate) trait Frame: Sized {
+	fn decode(decoder: &mut Decoder<'_>) -> Result<Self, FieldError>;
+
+	fn encode(&self, buffer: &mut Vec<u8>) -> Result<(), FieldError>;
+}
+
+// declares a message with its fields in the order they are sent, errors name the message and the field
+macro_rules! message {
+	(
+		$(#[$meta:meta])*
+		$vis:vis struct $name:ident {
+			$($field:ident: $kind:ty),* $(,)?
+		}
+	) => {
+		$(#[$meta])*
+		$vis struct $name {
+			$($vis $field: $kind,)*
+		}
+
+		impl $crate::binary_codec::Frame for $name {
+			fn decode(
+				decoder: &mut $crate::binary_codec::Decoder<'_>,
+			) -> ::std::result::Result<Self, $crate::binary_codec::FieldError> {
+				Ok(Self {
+					$($field: decoder.read(concat!(stringify!($name), ".", stringify!($field)))?,)*
+				})
+			}
+
+			fn encode(&self, buffer: &mut Vec<u8>) -> ::std::result::Result<(), $crate::binary_codec::FieldError> {
+				$(
+					$crate::binary_codec::Field::encode(
+						&self.$field,
+						concat!(stringify!($name), ".", stringify!($field)),
+						buffer,
+					)?;
+				)*
+				Ok(())
+			}
+		}
+	};
+}
+
+pub(crate) use message;
+
+pub(crate) fn encode<F: Frame>(frame: &F) -> Result<Vec<u8>, FieldError> {
+	let mut buffer = vec![];
+	frame.encode(&mut buffer)?;
+	Ok(buffer)
+}
+
+// splits the data read from a connection into frames, the start of a frame that is not yet complete is kept until the
+// rest of the frame is read, and dropped at the end of the stream
+#[derive(Debug, Default)]
+pub(crate) struct FrameCodec {
+	buffer: Vec<u8>,
+}
+
+impl FrameCodec {
+	pub(crate) const fn new() -> Self {
+		Self { buffer: vec![] }
+	}
+
+	pub(crate) fn push(&mut self, data: &[u8]) {
+		self.buffer.extend_from_slice(data);
+	}
+
+	pub(crate) fn next_frame<F: Frame>(&mut self) -> Result<Option<F>, FieldError> {
+		let mut decoder = Decoder::new(&self.buffer);
+		match F::decode(&mut decoder) {
+			Ok(frame) => {
+				self.buffer = self.buffer.split_off(decoder.position);
+				Ok(Some(frame))
+			},
+			Err(err) if err.is_incomplete() => Ok(None),
+			Err(err) => Err(err),
+		}
+	}
+
+	// reads until a frame is complete, None at the end of the stream, errors are a FieldError or an error reading
+	pub(crate) fn read_frame<F: Frame, R: Read + ?Sized>(&mut self, reader: &mut R) -> Result<Option<F>, Error> {
+		let mut buffer = [0; READ_SIZE];
+		loop {
+			if let Some(frame) = self.next_frame()? {
+				return Ok(Some(frame));
+			}
+			let size = reader.read(&mut buffer)?;
+			if size == 0 {
+				return Ok(None);
+			}
+			self.push(&buffer[..size]);
+		}
+	}
+
+	pub(crate) async fn read_frame_async<F: Frame, R: AsyncRead + Unpin + ?Sized>(
+		&mut self,
+		reader: &mut R,
+	) -> Result<Option<F>, Error> {
+		let mut buffer = [0; READ_SIZE];
+		loop {
+			if let Some(frame) = self.next_frame()? {
+				return Ok(Some(frame));
+			}
+			let size = reader.read(&mut buffer).await?;
+			if size == 0 {
+				return Ok(None);
+			}
+			self.push(&buffer[..size]);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	message! {
+		#[derive(Debug, Clone, PartialEq, Eq)]
+		struct Plate {
+			plate: String,
+			timestamp: u32,
+		}
+	}
+
+	message! {
+		#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+		struct Numbers {
+			small: u8,
+			medium: i16,
+			large: u64,
+		}
+	}
+
+	#[test]
+	fn big_endian_integers() {
+		let numbers = Numbers {
+			small: 0x12,
+			medium: -2,
+			large: 0x0102_0304_0506_0708,
+		};
+		let data = encode(&numbers).unwrap();
+		assert_eq!(data, [0x12, 0xFF, 0xFE, 1, 2, 3, 4, 5, 6, 7, 8]);
+		assert_eq!(Numbers::decode(&mut Decoder::new(&data)).unwrap(), numbers);
+	}
+
+	#[test]
+	fn length_prefixed_strings() {
+		let plate = Plate {
+			plate: String::from("UN1X"),
+			timestamp: 1000,
+		};
+		let data = encode(&plate).unwrap();
+		assert_eq!(data, [4, b'U', b'N', b'1', b'X', 0, 0, 0x03, 0xE8]);
+		assert_eq!(Plate::decode(&mut Decoder::new(&data)).unwrap(), plate);
+
+		let mut data = vec![];
+		encode_string::<u16>("abc", "field", &mut data).unwrap();
+		assert_eq!(data, [0, 3, b'a', b'b', b'c']);
+		assert_eq!(Decoder::new(&data).string::<u16>("field").unwrap(), "abc");
+	}
+
+	#[test]
+	fn errors_name_the_field() {
+		let long = Plate {
+			plate: "x".repeat(256),
+			timestamp: 0,
+		};
+		assert_eq!(
+			encode(&long).unwrap_err().to_string(),
+			"Plate.plate: longer than the length prefix allows"
+		);
+		assert_eq!(
+			Plate::decode(&mut Decoder::new(&[2, 0xFF, 0xFE, 0, 0, 0, 0]))
+				.unwrap_err()
+				.to_string(),
+			"Plate.plate: not valid UTF-8"
+		);
+		assert_eq!(
+			Plate::decode(&mut Decoder::new(&[1, b'a', 0, 0])).unwrap_err(),
+			FieldError::new("Plate.timestamp", FieldErrorKind::Incomplete)
+		);
+	}
+
+	#[test]
+	fn fragmented_frames() {
+		let plates = [
+			Plate {
+				plate: String::from("RE05BKG"),
+				timestamp: 123_456,
+			},
+			Plate {
+				plate: String::new(),
+				timestamp: 7,
+			},
+		];
+		let data = [encode(&plates[0]).unwrap(), encode(&plates[1]).unwrap()].concat();
+
+		let mut codec = FrameCodec::new();
+		let mut frames = vec![];
+		for byte in &data {
+			codec.push(&[*byte]);
+			while let Some(frame) = codec.next_frame::<Plate>().unwrap() {
+				frames.push(frame);
+			}
+		}
+		assert_eq!(frames, plates);
+	}
+
+	#[test]
+	fn read_frame_drops_partial_frame_at_end() {
+		let mut codec = FrameCodec::new();
+		let mut reader: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 0xFF];
+		assert_eq!(
+			codec.read_frame::<Numbers, _>(&mut reader).unwrap(),
+			Some(Numbers {
+				small: 1,
+				medium: 0x0203,
+				large: 0x0405_0607_0809_0A0B,
+			})
+		);
+		assert_eq!(codec.read_frame::<Numbers, _>(&mut reader).unwrap(), None);
+	}
+}
@@ -1,47 +1,94 @@
 use std::{
 	collections::HashMap,
-	io::{Read, Write},
-	net::{Shutdown, TcpStream},
-	sync::Arc,
-	thread::{scope, Scope, ScopedJoinHandle},
+	io::{self, BufRead, BufReader, Write},
+	net::Shutdown,
+	sync::{Arc, Barrier},
+	thread::{scope, sleep, Scope, ScopedJoinHandle},
+	time::{Duration, Instant},
 };
 
-use anyhow::Error;
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use anyhow::{anyhow, Error};
 use parking_lot::Mutex;
+use tokio::{
+	io::{split, AsyncWriteExt, WriteHalf},
+	spawn,
+	sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+	task::JoinHandle,
+};
 
-use crate::handler::TcpHandler;
+use crate::{
+	address::Address,
+	client::{self, Load, LoadReport, ProblemClient},
+	context::{ConnectionContext, CountedStream},
+	handler::{AsyncTcpHandler, HandlerFuture, HandlerPanic, TcpHandler},
+	line_codec::{self, LineCodec, LineFormat},
+	logger::{debug, info, payload, trace},
+	metrics::{self, SampledGauge},
+	options::{OptionKind, Options, ProblemOption},
+	registry::{Handler, Problem, Transport},
+	stream::{AsyncStream, Stream},
+	timeouts,
+};
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+// messages carry the name of the user they are from, who may have left by the time they are delivered
 pub(crate) enum Message {
-	Join(usize),
+	Join(usize, String),
 	Leave(usize, String),
-	Message(usize, String),
+	Message(usize, String, String),
 	Shutdown,
 }
 
+#[derive(Debug, PartialEq, Eq)]
+enum Delivery {
+	Write(String),
+	Skip,
+	Stop,
+}
+
 #[derive(Debug)]
 struct User {
 	name: String,
-	sender: Sender<Message>,
-	receiver: Receiver<Message>,
+	sender: UnboundedSender<Message>,
+	receiver: Option<UnboundedReceiver<Message>>,
 }
 
 impl User {
 	fn new(name: &str) -> Self {
-		let (sender, receiver) = unbounded();
+		let (sender, receiver) = unbounded_channel();
 		Self {
 			name: String::from(name),
 			sender,
-			receiver,
+			receiver: Some(receiver),
 		}
 	}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct NameRules {
+	ascii_only: bool,
+	max_length: usize,
+}
 
-	fn is_valid_name(name: &str) -> bool {
+impl NameRules {
+	fn from_options(options: &Options) -> Result<Self, Error> {
+		Ok(Self {
+			ascii_only: options.choice("name-charset")? == "ascii",
+			max_length: options.count("max-name-length")?,
+		})
+	}
+
+	fn is_valid_name(self, name: &str) -> bool {
 		if name.is_empty() {
 			return false;
 		}
+		if self.max_length != 0 && name.chars().count() > self.max_length {
+			return false;
+		}
 		for char in name.chars() {
+			if self.ascii_only && !char.is_ascii_alphanumeric() {
+				return false;
+			}
 			if !char.is_alphanumeric() {
 				return false;
 			}
@@ -50,17 +97,170 @@ impl User {
 	}
 }
 
+pub(crate) const PROBLEM: Problem = Problem {
+	name: "budgetchat",
+	aliases: &["3", "chat"],
+	transport: Transport::Tcp,
+	description: "Line based chat room (problem 3)",
+	options: &[
+		ProblemOption {
+			name: "name-charset",
+			kind: OptionKind::Choice(&["alphanumeric", "ascii"]),
+			default: "alphanumeric",
+			description: "Characters allowed in a name, ascii limits names to A-Z, a-z and 0-9",
+		},
+		ProblemOption {
+			name: "max-name-length",
+			kind: OptionKind::Count,
+			default: "0",
+			description: "Maximum number of characters in a name, 0 for no limit",
+		},
+		line_codec::max_length_option("4096"),
+		line_codec::UTF8_OPTION,
+	],
+	// people in a chat room read more than they write
+	timeouts: &timeouts::options("600s", "10s", "3600s"),
+	create: |options| {
+		Ok(Handler::tcp(BudgetChat::new(
+			NameRules::from_options(options)?,
+			LineFormat::from_options(options)?,
+		)))
+	},
+	client: ProblemClient {
+		interactive: client,
+		load,
+		requests: "a name to join the room, then messages, load joins --connections users",
+	},
+};
+
+// how long a simulated user waits for the messages of the other users once it has sent its own
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// what the room sends is written to the output as it arrives, while the input is sent
+fn client(address: &Address, input: &mut dyn BufRead, output: &mut (dyn Write + Send)) -> Result<(), Error> {
+	let mut stream = client::connect(address)?;
+	let mut room = stream.try_clone()?;
+	scope(|s| {
+		let printer = s.spawn(move || io::copy(&mut room, output));
+		for line in input.lines() {
+			if stream.write_all(format!("{}\n", line?).as_bytes()).is_err() {
+				// the server closed the connection, which the printer reports
+				break;
+			}
+		}
+		let _result = stream.shutdown(Shutdown::Write);
+		let _size = printer.join().unwrap()?;
+		Ok(())
+	})
+}
+
+// a user in the room, and what the room sends them
+type RoomConnection = (Box<dyn Stream>, BufReader<Box<dyn Stream>>);
+
+fn join_room(address: &Address, name: &str) -> Result<RoomConnection, Error> {
+	let mut stream = client::connect(address)?;
+	let mut room = BufReader::new(stream.try_clone()?);
+	let mut line = String::new();
+	let _size = room.read_line(&mut line)?;
+	stream.write_all(format!("{name}\n").as_bytes())?;
+	line.clear();
+	let _size = room.read_line(&mut line)?;
+	if !line.starts_with("* ") {
+		return Err(anyhow!("Unable to join as {name}: {}", line.trim_end()));
+	}
+	Ok((stream, room))
+}
+
+// every user sends its messages to the room and reads the messages of the others, a message is the time it was sent,
+// so the latency of every delivery is known
+fn load(address: &Address, load: &Load) -> LoadReport {
+	let users = load.connections.get();
+	let joined = Barrier::new(users);
+	let started = Instant::now();
+	client::load_connections(load, |index, samples| {
+		let connection = join_room(address, &format!("user{index}"));
+		// everyone is in the room before anyone speaks, so every message reaches every other user
+		let _leader = joined.wait();
+		let (mut stream, mut room) = connection?;
+		let expected = (users - 1) * load.requests.get();
+
+		let latencies = scope(|s| {
+			let receiver = s.spawn(move || {
+				let mut latencies = vec![];
+				let mut line = String::new();
+				while latencies.len() < expected {
+					line.clear();
+					if !matches!(room.read_line(&mut line), Ok(size) if size > 0) {
+						break;
+					}
+					let sent = line
+						.split_once("] ")
+						.and_then(|(_, message)| message.trim_end().parse::<u64>().ok());
+					if let Some(sent) = sent {
+						latencies.push(started.elapsed().saturating_sub(Duration::from_micros(sent)));
+					}
+				}
+				(latencies, room)
+			});
+			for _ in 0..load.requests.get() {
+				let sent = u64::try_from(started.elapsed().as_micros()).unwrap_or(u64::MAX);
+				stream.write_all(format!("{sent}\n").as_bytes())?;
+			}
+			let deadline = Instant::now() + CLIENT_TIMEOUT;
+			while !receiver.is_finished() && Instant::now() < deadline {
+				sleep(Duration::from_millis(10));
+			}
+			// a user that has every message leaves the room and reads until the server closes the connection, so the
+			// server is not writing to a closed connection
+			let how = if receiver.is_finished() {
+				Shutdown::Write
+			}
+			else {
+				Shutdown::Both
+			};
+			let _result = stream.shutdown(how);
+			let (latencies, mut room) = receiver.join().unwrap();
+			let _result = io::copy(&mut room, &mut io::sink());
+			Ok::<_, Error>(latencies)
+		})?;
+
+		for _ in latencies.len()..expected {
+			samples.failed();
+		}
+		for latency in latencies {
+			samples.responded(latency);
+		}
+		Ok(())
+	})
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct BudgetChat {
+	name_rules: NameRules,
+	line_format: LineFormat,
 	next_id: Arc<Mutex<usize>>,
 	users: Arc<Mutex<HashMap<usize, User>>>,
+	// the room leaves the metrics once every clone of the problem is dropped
+	#[allow(dead_code)]
+	room_size: Arc<SampledGauge>,
 }
 
 impl BudgetChat {
-	pub(crate) fn new() -> Self {
+	pub(crate) fn new(name_rules: NameRules, line_format: LineFormat) -> Self {
+		let users = Arc::new(Mutex::new(HashMap::<usize, User>::new()));
+		let room = Arc::clone(&users);
+		let room_size = metrics::sampled_gauge(
+			"protohackers_budgetchat_room_size",
+			"Users that have joined the chat rooms",
+			&[],
+			move || room.lock().len() as u64,
+		);
 		Self {
+			name_rules,
+			line_format,
 			next_id: Arc::new(Mutex::new(1)),
-			users: Arc::new(Mutex::new(HashMap::new())),
+			users,
+			room_size: Arc::new(room_size),
 		}
 	}
 
@@ -77,7 +277,7 @@ impl BudgetChat {
 		let mut users = self.users.lock();
 		let _prev = (*users).insert(user_id, user);
 		drop(users);
-		self.broadcast(&Message::Join(user_id));
+		self.broadcast(&Message::Join(user_id, String::from(name)));
 		user_id
 	}
 
@@ -109,7 +309,8 @@ impl BudgetChat {
 		let users = self.users.lock();
 
 		for user in (*users).values() {
-			user.sender.send(message.clone()).unwrap();
+			// a closed receiver means the user is already on the way out of the room
+			let _sent = user.sender.send(message.clone());
 		}
 	}
 
@@ -117,141 +318,394 @@ impl BudgetChat {
 		let users = self.users.lock();
 
 		if let Some(user) = (*users).get(&user_id) {
-			user.sender.send(message).unwrap();
+			let _sent = user.sender.send(message);
+		}
+	}
+
+	fn take_receiver(&self, user_id: usize) -> UnboundedReceiver<Message> {
+		let mut users = self.users.lock();
+
+		(*users)
+			.get_mut(&user_id)
+			.and_then(|user| user.receiver.take())
+			.expect("Message receiver already taken")
+	}
+
+	fn deliver(context: &ConnectionContext, user_id: usize, name: &str, message: Message) -> Delivery {
+		match message {
+			Message::Join(joined_user_id, joined_name) => {
+				if joined_user_id == user_id {
+					return Delivery::Skip;
+				}
+				debug!(connection: context.id(), "({joined_name}) Entered: {name}");
+				Delivery::Write(format!("* {} has entered the room\n", joined_name))
+			},
+			Message::Leave(left_user_id, name) => {
+				trace!(connection: context.id(), "{left_user_id} {user_id}");
+				if left_user_id == user_id {
+					return Delivery::Stop;
+				}
+				debug!(connection: context.id(), "({user_id}) Left: {name}");
+				Delivery::Write(format!("* {} has left the room\n", name))
+			},
+			Message::Message(from_user_id, from_name, msg) => {
+				if from_user_id == user_id {
+					return Delivery::Skip;
+				}
+				debug!(connection: context.id(), "({from_name}) --> ({name}) Sending: {msg}");
+				Delivery::Write(format!("[{from_name}] {msg}\n"))
+			},
+			Message::Shutdown => Delivery::Stop,
 		}
 	}
 
 	fn start_message_thread<'scope>(
-		self,
+		&self,
 		scope: &'scope Scope<'scope, '_>,
-		id: u32,
-		mut stream: TcpStream,
+		context: ConnectionContext,
+		mut stream: CountedStream<Box<dyn Stream>>,
 		user_id: usize,
-	) -> ScopedJoinHandle<'scope, ()> {
-		let users = self.users.lock();
-		let receiver = (*users)[&user_id].receiver.clone();
-		drop(users);
+	) -> ScopedJoinHandle<'scope, io::Result<()>> {
+		let mut receiver = self.take_receiver(user_id);
+		let name = self.name(user_id);
 		scope.spawn(move || {
-			'main: loop {
-				while let Ok(message) = receiver.recv() {
-					match message {
-						Message::Join(joined_user_id) => {
-							if joined_user_id != user_id {
-								let joined_name = self.name(joined_user_id);
-								let name = self.name(user_id);
-								eprintln!("({id}) ({joined_name}) Entered: {name}");
-								stream
-									.write_all(format!("* {} has entered the room\n", joined_name).as_bytes())
-									.unwrap();
-							}
-						},
-						Message::Leave(left_user_id, name) => {
-							eprintln!("{left_user_id} {user_id}");
-							if left_user_id == user_id {
-								break 'main;
-							}
-							eprintln!("({id}) ({user_id}) Left: {name}");
-							stream
-								.write_all(format!("* {} has left the room\n", name).as_bytes())
-								.unwrap();
-						},
-						Message::Message(from_user_id, msg) => {
-							if from_user_id != user_id {
-								let from_name = self.name(from_user_id);
-								let name = self.name(user_id);
-								eprintln!("({id}) ({from_name}) --> ({name}) Sending: {msg}");
-								stream.write_all(format!("[{from_name}] {msg}\n").as_bytes()).unwrap();
-							}
-						},
-						Message::Shutdown => break 'main,
-					}
+			while let Some(message) = receiver.blocking_recv() {
+				match Self::deliver(&context, user_id, &name, message) {
+					Delivery::Write(text) => stream.write_all(text.as_bytes())?,
+					Delivery::Skip => {},
+					Delivery::Stop => break,
 				}
 			}
+			Ok(())
+		})
+	}
+
+	fn start_message_task(
+		&self,
+		context: ConnectionContext,
+		mut stream: CountedStream<WriteHalf<Box<dyn AsyncStream>>>,
+		user_id: usize,
+	) -> JoinHandle<io::Result<()>> {
+		let mut receiver = self.take_receiver(user_id);
+		let name = self.name(user_id);
+		spawn(async move {
+			while let Some(message) = receiver.recv().await {
+				match Self::deliver(&context, user_id, &name, message) {
+					Delivery::Write(text) => stream.write_all(text.as_bytes()).await?,
+					Delivery::Skip => {},
+					Delivery::Stop => break,
+				}
+			}
+			Ok(())
 		})
 	}
 }
 
 impl TcpHandler for BudgetChat {
-	fn handler(&self, mut stream: TcpStream, id: u32) -> Result<(), Error> {
+	fn handler(&self, stream: Box<dyn Stream>, context: ConnectionContext) -> Result<(), Error> {
+		let mut stream = context.counted(stream);
 		stream.write_all("Welcome to budgetchat! What shall I call you?\n".as_bytes())?;
 
-		let mut recv_steam = stream.try_clone()?;
-		scope(move |s| {
+		let mut recv_steam = context.counted(stream.get_ref().try_clone()?);
+		scope(|s| {
 			let mut message_thread_handle = None;
 			let mut user_id = 0;
-			let mut read_buffer = [0; 128];
-			let mut buffer = String::new();
-			'main: while let Ok(size) = recv_steam.read(&mut read_buffer) {
-				if size == 0 {
-					break;
-				}
-				buffer.push_str(String::from_utf8_lossy(&read_buffer[0..size]).as_ref());
-
-				eprintln!("({id}) Buffer: {}", buffer.replace('\n', "\\n"));
-				let last_message_complete = buffer.ends_with('\n');
-				let mut messages = buffer.lines().map(String::from).collect::<Vec<String>>();
-
-				if !last_message_complete && !messages.is_empty() {
-					buffer = messages.remove(messages.len() - 1);
-				}
-				else {
-					buffer.clear();
-				}
-
-				for message in messages {
-					eprintln!("({id}) Message: {message}");
-					if user_id == 0 {
-						let name = message.trim();
-						if !User::is_valid_name(name) {
-							self.send_message(user_id, Message::Shutdown);
-							recv_steam
-								.write_all("Name must be provided and must be alphanumeric\n".as_bytes())
-								.unwrap();
-							recv_steam.shutdown(Shutdown::Read).unwrap();
-							break 'main;
-						}
-						let room_list = self.room_list();
-						user_id = self.add_user(name);
-						eprintln!("({id}) Joined: {name}, ID: {user_id}, Room: {room_list}");
-						recv_steam
-							.write_all(format!("* The room contains: {room_list}\n").as_bytes())
-							.unwrap();
-						message_thread_handle =
-							Some(
-								self.clone()
-									.start_message_thread(s, id, recv_steam.try_clone().unwrap(), user_id),
-							);
-						continue;
+			let mut user_name = String::new();
+			let mut lines = LineCodec::new(self.line_format);
+			// a failed write ends the connection, after the user has left the room
+			let mut written = Ok(());
+			loop {
+				let message = match lines.read_line(&mut recv_steam) {
+					Ok(Some(message)) => message,
+					Ok(None) => break,
+					Err(err) => {
+						debug!(connection: context.id(), "Read failed: {err}");
+						break;
+					},
+				};
+
+				payload!(connection: context.id(), "Message: {message}");
+				if user_id == 0 {
+					let name = message.trim();
+					if !self.name_rules.is_valid_name(name) {
+						self.send_message(user_id, Message::Shutdown);
+						written = recv_steam
+							.write_all("Name must be provided and must be alphanumeric\n".as_bytes())
+							.and_then(|()| recv_steam.get_ref().shutdown(Shutdown::Read));
+						break;
 					}
-					if !message.starts_with('*') {
-						eprintln!("({id}) ({user_id}) Sending: {message}");
-						self.broadcast(&Message::Message(user_id, message));
+					let room_list = self.room_list();
+					user_id = self.add_user(name);
+					user_name = String::from(name);
+					info!(connection: context.id(), "Joined: {name}, ID: {user_id}, Room: {room_list}");
+					let send_stream = recv_steam
+						.write_all(format!("* The room contains: {room_list}\n").as_bytes())
+						.and_then(|()| recv_steam.get_ref().try_clone());
+					match send_stream {
+						Ok(send_stream) => {
+							message_thread_handle = Some(self.start_message_thread(
+								s,
+								context.clone(),
+								context.counted(send_stream),
+								user_id,
+							));
+						},
+						Err(err) => {
+							written = Err(err);
+							break;
+						},
 					}
+					continue;
+				}
+				if !message.starts_with('*') {
+					debug!(connection: context.id(), "({user_id}) Sending: {message}");
+					self.broadcast(&Message::Message(user_id, user_name.clone(), message));
 				}
 			}
 
 			if user_id != 0 {
-				let name = self.name(user_id);
-				eprintln!("({id}) Disconnected: {name} ({user_id})");
+				info!(connection: context.id(), "Disconnected: {user_name} ({user_id})");
 				self.remove_user(user_id);
 			}
 
 			if let Some(handle) = message_thread_handle {
-				handle.join().unwrap();
+				handle
+					.join()
+					.map_err(|payload| HandlerPanic::error(payload.as_ref()))??;
 			}
-		});
+			Ok::<_, Error>(written?)
+		})?;
 
-		eprintln!("({id}) Shutdown");
-		stream.shutdown(Shutdown::Read)?;
+		debug!(connection: context.id(), "Shutdown");
+		// the client may already be gone
+		let _result = stream.get_ref().shutdown(Shutdown::Read);
 
 		Ok(())
 	}
 
+	fn busy_response(&self) -> Option<&'static [u8]> {
+		Some(b"* The room is full, try again later\n")
+	}
+
 	fn shutdown(&self) {
 		let users = self.users.lock();
 
 		for user in (*users).values() {
-			user.sender.send(Message::Shutdown).unwrap();
+			let _sent = user.sender.send(Message::Shutdown);
 		}
 	}
 }
+
+impl AsyncTcpHandler for BudgetChat {
+	fn handler(&self, stream: Box<dyn AsyncStream>, context: ConnectionContext) -> HandlerFuture<'_> {
+		Box::pin(async move {
+			let (recv_stream, send_stream) = split(stream);
+			let mut recv_stream = context.counted(recv_stream);
+			let mut send_stream = context.counted(send_stream);
+			send_stream
+				.write_all("Welcome to budgetchat! What shall I call you?\n".as_bytes())
+				.await?;
+
+			let mut send_stream = Some(send_stream);
+			let mut message_task_handle = None;
+			let mut user_id = 0;
+			let mut user_name = String::new();
+			let mut lines = LineCodec::new(self.line_format);
+			// a failed write ends the connection, after the user has left the room
+			let mut written = Ok(());
+			loop {
+				let message = match lines.read_line_async(&mut recv_stream).await {
+					Ok(Some(message)) => message,
+					Ok(None) => break,
+					Err(err) => {
+						debug!(connection: context.id(), "Read failed: {err}");
+						break;
+					},
+				};
+
+				payload!(connection: context.id(), "Message: {message}");
+				if let Some(mut stream) = send_stream.take() {
+					let name = message.trim();
+					if !self.name_rules.is_valid_name(name) {
+						stream
+							.write_all("Name must be provided and must be alphanumeric\n".as_bytes())
+							.await?;
+						break;
+					}
+					let room_list = self.room_list();
+					user_id = self.add_user(name);
+					user_name = String::from(name);
+					info!(connection: context.id(), "Joined: {name}, ID: {user_id}, Room: {room_list}");
+					if let Err(err) = stream
+						.write_all(format!("* The room contains: {room_list}\n").as_bytes())
+						.await
+					{
+						written = Err(err);
+						break;
+					}
+					message_task_handle = Some(self.start_message_task(context.clone(), stream, user_id));
+					continue;
+				}
+				if !message.starts_with('*') {
+					debug!(connection: context.id(), "({user_id}) Sending: {message}");
+					self.broadcast(&Message::Message(user_id, user_name.clone(), message));
+				}
+			}
+
+			if user_id != 0 {
+				info!(connection: context.id(), "Disconnected: {user_name} ({user_id})");
+				self.remove_user(user_id);
+			}
+
+			if let Some(handle) = message_task_handle {
+				handle.await.map_err(|err| {
+					match err.try_into_panic() {
+						Ok(payload) => HandlerPanic::error(payload.as_ref()),
+						Err(err) => Error::from(err),
+					}
+				})??;
+			}
+
+			debug!(connection: context.id(), "Shutdown");
+			Ok(written?)
+		})
+	}
+
+	fn shutdown(&self) {
+		TcpHandler::shutdown(self);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		io::{BufRead, BufReader},
+		thread::{self, JoinHandle},
+	};
+
+	use tokio::io::{duplex, AsyncReadExt};
+
+	use super::*;
+	use crate::{
+		line_codec::Utf8,
+		stream::testing::{block_on, context, fragmented, pair, MemoryStream},
+	};
+
+	const RULES: NameRules = NameRules {
+		ascii_only: false,
+		max_length: 0,
+	};
+
+	const FORMAT: LineFormat = LineFormat {
+		max_length: 1000,
+		utf8: Utf8::Lossy,
+	};
+
+	struct Client {
+		reader: BufReader<MemoryStream>,
+		writer: Box<dyn Stream>,
+		handler: JoinHandle<()>,
+	}
+
+	impl Client {
+		fn connect(chat: &BudgetChat, (client, server): (MemoryStream, MemoryStream)) -> Self {
+			let chat = chat.clone();
+			let handler = thread::spawn(move || TcpHandler::handler(&chat, Box::new(server), context()).unwrap());
+			Self {
+				writer: client.try_clone().unwrap(),
+				reader: BufReader::new(client),
+				handler,
+			}
+		}
+
+		fn send(&mut self, line: &str) {
+			self.writer.write_all(line.as_bytes()).unwrap();
+		}
+
+		fn line(&mut self) -> String {
+			let mut line = String::new();
+			let _size = self.reader.read_line(&mut line).unwrap();
+			line
+		}
+
+		fn leave(self) {
+			self.writer.shutdown(Shutdown::Write).unwrap();
+			self.handler.join().unwrap();
+		}
+	}
+
+	#[test]
+	fn chat() {
+		let chat = BudgetChat::new(RULES, FORMAT);
+		let mut alice = Client::connect(&chat, pair());
+		assert_eq!(alice.line(), "Welcome to budgetchat! What shall I call you?\n");
+		alice.send("alice\n");
+		assert_eq!(alice.line(), "* The room contains: \n");
+
+		let mut bob = Client::connect(&chat, pair());
+		assert_eq!(bob.line(), "Welcome to budgetchat! What shall I call you?\n");
+		bob.send("bob\n");
+		assert_eq!(bob.line(), "* The room contains: alice\n");
+		assert_eq!(alice.line(), "* bob has entered the room\n");
+
+		// a message may arrive in pieces
+		bob.send("hello ");
+		bob.send("alice\n");
+		assert_eq!(alice.line(), "[bob] hello alice\n");
+		alice.send("hi bob\n");
+		assert_eq!(bob.line(), "[alice] hi bob\n");
+
+		bob.leave();
+		assert_eq!(alice.line(), "* bob has left the room\n");
+		alice.leave();
+	}
+
+	#[test]
+	fn fragmented_lines() {
+		let chat = BudgetChat::new(RULES, FORMAT);
+		let mut alice = Client::connect(&chat, pair());
+		assert_eq!(alice.line(), "Welcome to budgetchat! What shall I call you?\n");
+		alice.send("alice\n");
+		assert_eq!(alice.line(), "* The room contains: \n");
+
+		// every read by the server returns a single byte, and the lines end with \r\n
+		let mut bob = Client::connect(&chat, fragmented(1));
+		assert_eq!(bob.line(), "Welcome to budgetchat! What shall I call you?\n");
+		bob.send("bob\r\nhello alice\r\n");
+		assert_eq!(bob.line(), "* The room contains: alice\n");
+		assert_eq!(alice.line(), "* bob has entered the room\n");
+		assert_eq!(alice.line(), "[bob] hello alice\n");
+
+		bob.leave();
+		assert_eq!(alice.line(), "* bob has left the room\n");
+		alice.leave();
+	}
+
+	#[test]
+	fn invalid_name() {
+		let (mut client, server) = pair();
+		client.write_all(b"not valid\n").unwrap();
+		TcpHandler::handler(&BudgetChat::new(RULES, FORMAT), Box::new(server), context()).unwrap();
+		assert_eq!(
+			client.read_string(),
+			"Welcome to budgetchat! What shall I call you?\nName must be provided and must be alphanumeric\n"
+		);
+	}
+
+	#[test]
+	fn invalid_name_async() {
+		block_on(async {
+			let (mut client, server) = duplex(1024);
+			client.write_all(b"\n").await.unwrap();
+			AsyncTcpHandler::handler(&BudgetChat::new(RULES, FORMAT), Box::new(server), context())
+				.await
+				.unwrap();
+			let mut data = String::new();
+			let _size = client.read_to_string(&mut data).await.unwrap();
+			assert_eq!(
+				data,
+				"Welcome to budgetchat! What shall I call you?\nName must be provided and must be alphanumeric\n"
+			);
+		});
+	}
+}
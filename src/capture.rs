@@ -0,0 +1,501 @@
+use std::{
+	collections::HashMap,
+	fs::{self, OpenOptions},
+	io::{self, ErrorKind, Read, Write},
+	net::Shutdown,
+	path::{Path, PathBuf},
+	pin::Pin,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	task::{Context, Poll},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+	address::Address,
+	binary_codec::{self, message, Decoder, Field, FieldError, Frame},
+	context::ConnectionContext,
+	logger::{info, warning},
+	stream::{AsyncStream, Stream},
+};
+
+// "PHSN", the start of every session file
+const MAGIC: u32 = 0x5048_534E;
+const VERSION: u8 = 1;
+// datagram peers quiet for this long are closed and forgotten, once this many are remembered
+const PRUNE_PEERS: usize = 1024;
+const PEER_IDLE: Duration = Duration::from_secs(10);
+
+message! {
+	#[derive(Debug, Clone, PartialEq, Eq)]
+	struct Header {
+		magic: u32,
+		version: u8,
+		problem: String,
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EventKind {
+	// the address of the client
+	Opened(String),
+	Read(Vec<u8>),
+	Written(Vec<u8>),
+	Closed,
+}
+
+// something that happened on a connection, or to the datagrams of a peer, timed from the start of the capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Event {
+	pub(crate) connection: u64,
+	pub(crate) time: Duration,
+	pub(crate) kind: EventKind,
+}
+
+impl Frame for Event {
+	fn decode(decoder: &mut Decoder<'_>) -> Result<Self, FieldError> {
+		let tag = decoder.read::<u8>("Event.type")?;
+		let connection = decoder.read("Event.connection")?;
+		let time = Duration::from_micros(decoder.read("Event.time")?);
+		let kind = match tag {
+			b'O' => EventKind::Opened(decoder.read("Event.peer")?),
+			b'R' => EventKind::Read(decoder.bytes::<u32>("Event.data")?.to_vec()),
+			b'W' => EventKind::Written(decoder.bytes::<u32>("Event.data")?.to_vec()),
+			b'C' => EventKind::Closed,
+			tag => return Err(FieldError::invalid("Event.type", format!("unknown type {tag:#04x}"))),
+		};
+		Ok(Self { connection, time, kind })
+	}
+
+	fn encode(&self, buffer: &mut Vec<u8>) -> Result<(), FieldError> {
+		let tag = match self.kind {
+			EventKind::Opened(_) => b'O',
+			EventKind::Read(_) => b'R',
+			EventKind::Written(_) => b'W',
+			EventKind::Closed => b'C',
+		};
+		tag.encode("Event.type", buffer)?;
+		self.connection.encode("Event.connection", buffer)?;
+		u64::try_from(self.time.as_micros())
+			.unwrap_or(u64::MAX)
+			.encode("Event.time", buffer)?;
+		match self.kind {
+			EventKind::Opened(ref peer) => peer.encode("Event.peer", buffer),
+			EventKind::Read(ref data) | EventKind::Written(ref data) => {
+				binary_codec::encode_bytes::<u32>(data, "Event.data", buffer)
+			},
+			EventKind::Closed => Ok(()),
+		}
+	}
+}
+
+// the events captured from the server of a problem
+#[derive(Debug)]
+pub(crate) struct Session {
+	pub(crate) problem: String,
+	pub(crate) events: Vec<Event>,
+}
+
+impl Session {
+	pub(crate) fn read(path: &Path) -> Result<Self, Error> {
+		let data = fs::read(path).map_err(|e| anyhow!("Unable to read session {}: {e}", path.display()))?;
+		let invalid = |e: FieldError| anyhow!("Invalid session {}: {e}", path.display());
+		let mut decoder = Decoder::new(&data);
+		let header = Header::decode(&mut decoder).map_err(invalid)?;
+		if header.magic != MAGIC {
+			return Err(anyhow!("Invalid session {}: not a session file", path.display()));
+		}
+		if header.version != VERSION {
+			return Err(anyhow!(
+				"Invalid session {}: version {} is not supported",
+				path.display(),
+				header.version
+			));
+		}
+
+		let mut events = vec![];
+		while !decoder.is_empty() {
+			match Event::decode(&mut decoder) {
+				Ok(event) => events.push(event),
+				// the server stopped while writing the last event
+				Err(ref err) if err.is_incomplete() => {
+					warning!("Ignoring the incomplete last event of {}", path.display());
+					break;
+				},
+				Err(err) => return Err(invalid(err)),
+			}
+		}
+		Ok(Self {
+			problem: header.problem,
+			events,
+		})
+	}
+}
+
+#[derive(Debug)]
+struct Peer {
+	connection: u64,
+	last_seen: Instant,
+}
+
+// writes what the clients of a server send and are sent to a session file, which the replay command reads
+#[derive(Debug)]
+pub(crate) struct Capture {
+	path: PathBuf,
+	// the start of the capture is the time of every event written
+	file: Mutex<(fs::File, Instant)>,
+	// datagrams have no connection, so the datagrams of a peer are captured as a connection of their own, with the
+	// connection of the next new peer
+	peers: Mutex<(HashMap<Address, Peer>, u64)>,
+	failed: AtomicBool,
+}
+
+impl Capture {
+	// a new session file in the directory, named after the problem and the time the capture started, with a counter
+	// when a problem served on several binds, or restarted, already has a session file from the same second
+	pub(crate) fn create(directory: &Path, problem: &str) -> Result<Arc<Self>, Error> {
+		fs::create_dir_all(directory)
+			.map_err(|e| anyhow!("Unable to create capture directory {}: {e}", directory.display()))?;
+		let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+		let mut path = directory.join(format!("{problem}-{}.session", started.as_secs()));
+		let mut count = 1;
+		let mut file = loop {
+			match OpenOptions::new().write(true).create_new(true).open(&path) {
+				Ok(file) => break file,
+				Err(ref err) if err.kind() == ErrorKind::AlreadyExists => {
+					count += 1;
+					path = directory.join(format!("{problem}-{}-{count}.session", started.as_secs()));
+				},
+				Err(e) => return Err(anyhow!("Unable to create session file {}: {e}", path.display())),
+			}
+		};
+		let header = Header {
+			magic: MAGIC,
+			version: VERSION,
+			problem: String::from(problem),
+		};
+		file.write_all(&binary_codec::encode(&header)?)
+			.map_err(|e| anyhow!("Unable to write session file {}: {e}", path.display()))?;
+		info!("Capturing {problem} sessions to {}", path.display());
+		Ok(Arc::new(Self {
+			path,
+			file: Mutex::new((file, Instant::now())),
+			peers: Mutex::new((HashMap::new(), 1)),
+			failed: AtomicBool::new(false),
+		}))
+	}
+
+	// the time is taken with the file locked, so the events of a session file are in order
+	fn record(&self, connection: u64, kind: EventKind) {
+		let mut file = self.file.lock();
+		let event = Event {
+			connection,
+			time: file.1.elapsed(),
+			kind,
+		};
+		let result = binary_codec::encode(&event)
+			.map_err(Error::from)
+			.and_then(|data| file.0.write_all(&data).map_err(Error::from));
+		if let Err(e) = result {
+			// the server keeps running without the capture, which is only reported once
+			if !self.failed.swap(true, Ordering::Relaxed) {
+				warning!("Unable to write session file {}: {e}", self.path.display());
+			}
+		}
+	}
+
+	pub(crate) fn stream(self: &Arc<Self>, stream: Box<dyn Stream>, context: &ConnectionContext) -> Box<dyn Stream> {
+		self.record(context.id(), EventKind::Opened(context.peer_addr().to_string()));
+		Box::new(CapturedStream {
+			inner: stream,
+			capture: Arc::clone(self),
+			connection: context.id(),
+		})
+	}
+
+	pub(crate) fn async_stream(
+		self: &Arc<Self>,
+		stream: Box<dyn AsyncStream>,
+		context: &ConnectionContext,
+	) -> Box<dyn AsyncStream> {
+		self.record(context.id(), EventKind::Opened(context.peer_addr().to_string()));
+		Box::new(CapturedStream {
+			inner: stream,
+			capture: Arc::clone(self),
+			connection: context.id(),
+		})
+	}
+
+	pub(crate) fn closed(&self, context: &ConnectionContext) {
+		self.record(context.id(), EventKind::Closed);
+	}
+
+	// a peer that is forgotten and sends again is captured as a new connection
+	fn peer_connection(&self, peer: &Address) -> u64 {
+		let now = Instant::now();
+		let mut peers = self.peers.lock();
+		let (ref mut known, ref mut next_connection) = *peers;
+		if let Some(known_peer) = known.get_mut(peer) {
+			known_peer.last_seen = now;
+			return known_peer.connection;
+		}
+		if known.len() >= PRUNE_PEERS {
+			known.retain(|_, known_peer| {
+				let quiet = now.duration_since(known_peer.last_seen) >= PEER_IDLE;
+				if quiet {
+					self.record(known_peer.connection, EventKind::Closed);
+				}
+				!quiet
+			});
+		}
+		let connection = *next_connection;
+		*next_connection += 1;
+		let _previous = known.insert(peer.clone(), Peer {
+			connection,
+			last_seen: now,
+		});
+		// recorded with the peers locked, so the peer is opened before anything else is recorded for it
+		self.record(connection, EventKind::Opened(peer.to_string()));
+		connection
+	}
+
+	pub(crate) fn received(&self, peer: &Address, data: &[u8]) {
+		self.record(self.peer_connection(peer), EventKind::Read(data.to_vec()));
+	}
+
+	pub(crate) fn sent(&self, peer: &Address, data: &[u8]) {
+		self.record(self.peer_connection(peer), EventKind::Written(data.to_vec()));
+	}
+}
+
+// a stream that records what is read from and written to it
+#[derive(Debug)]
+struct CapturedStream<S> {
+	inner: S,
+	capture: Arc<Capture>,
+	connection: u64,
+}
+
+impl<S: Read> Read for CapturedStream<S> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let size = self.inner.read(buf)?;
+		if size > 0 {
+			self.capture
+				.record(self.connection, EventKind::Read(buf[..size].to_vec()));
+		}
+		Ok(size)
+	}
+}
+
+impl<S: Write> Write for CapturedStream<S> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let size = self.inner.write(buf)?;
+		self.capture
+			.record(self.connection, EventKind::Written(buf[..size].to_vec()));
+		Ok(size)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl Stream for CapturedStream<Box<dyn Stream>> {
+	fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+		Ok(Box::new(Self {
+			inner: self.inner.try_clone()?,
+			capture: Arc::clone(&self.capture),
+			connection: self.connection,
+		}))
+	}
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+		self.inner.shutdown(how)
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CapturedStream<S> {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		let filled = buf.filled().len();
+		let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+		if let Poll::Ready(Ok(())) = result {
+			if buf.filled().len() > filled {
+				this.capture
+					.record(this.connection, EventKind::Read(buf.filled()[filled..].to_vec()));
+			}
+		}
+		result
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CapturedStream<S> {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+		if let Poll::Ready(Ok(size)) = result {
+			this.capture
+				.record(this.connection, EventKind::Written(buf[..size].to_vec()));
+		}
+		result
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+	}
+}
+
+impl AsyncStream for CapturedStream<Box<dyn AsyncStream>> {
+	fn shutdown_socket(&self, how: Shutdown) -> io::Result<()> {
+		self.inner.shutdown_socket(how)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{env, net::SocketAddr, process};
+
+	use super::*;
+
+	fn directory(name: &str) -> PathBuf {
+		let directory = env::temp_dir().join(format!("mitmaro-protohackers-{name}-{}", process::id()));
+		let _result = fs::remove_dir_all(&directory);
+		directory
+	}
+
+	#[test]
+	fn events_round_trip() {
+		let kinds = [
+			EventKind::Opened(String::from("127.0.0.1:1234")),
+			EventKind::Read(b"request\n".to_vec()),
+			EventKind::Written(vec![]),
+			EventKind::Closed,
+		];
+		for kind in kinds {
+			let event = Event {
+				connection: 7,
+				time: Duration::from_micros(1_500),
+				kind,
+			};
+			let data = binary_codec::encode(&event).unwrap();
+			let mut decoder = Decoder::new(&data);
+			assert_eq!(Event::decode(&mut decoder).unwrap(), event);
+			assert!(decoder.is_empty());
+		}
+	}
+
+	#[test]
+	fn datagrams_of_a_peer_are_one_connection() {
+		let directory = directory("capture");
+		let capture = Capture::create(&directory, "unusualdatabaseprogram").unwrap();
+		let first = Address::from(SocketAddr::from(([127, 0, 0, 1], 1000)));
+		let second = Address::from(SocketAddr::from(([127, 0, 0, 1], 2000)));
+		capture.received(&first, b"version");
+		capture.sent(&first, b"version=1");
+		capture.received(&second, b"key=value");
+
+		let session = Session::read(&capture.path).unwrap();
+		let events = session
+			.events
+			.into_iter()
+			.map(|event| (event.connection, event.kind))
+			.collect::<Vec<_>>();
+		assert_eq!(session.problem, "unusualdatabaseprogram");
+		assert_eq!(events, vec![
+			(1, EventKind::Opened(String::from("127.0.0.1:1000"))),
+			(1, EventKind::Read(b"version".to_vec())),
+			(1, EventKind::Written(b"version=1".to_vec())),
+			(2, EventKind::Opened(String::from("127.0.0.1:2000"))),
+			(2, EventKind::Read(b"key=value".to_vec())),
+		]);
+		fs::remove_dir_all(directory).unwrap();
+	}
+
+	#[test]
+	fn quiet_peers_are_closed_and_forgotten() {
+		let directory = directory("prune");
+		let capture = Capture::create(&directory, "unusualdatabaseprogram").unwrap();
+		let peer = |port: u16| Address::from(SocketAddr::from(([127, 0, 0, 1], port)));
+		for port in 1..=1024 {
+			capture.received(&peer(port), b"version");
+		}
+		assert_eq!(capture.peers.lock().0.len(), PRUNE_PEERS);
+		let quiet = Instant::now().checked_sub(PEER_IDLE).unwrap();
+		for (address, known_peer) in &mut capture.peers.lock().0 {
+			if *address != peer(1) {
+				known_peer.last_seen = quiet;
+			}
+		}
+
+		capture.received(&peer(2000), b"version");
+		capture.received(&peer(2), b"version");
+		assert_eq!(capture.peers.lock().0.len(), 3);
+
+		let events = Session::read(&capture.path)
+			.unwrap()
+			.events
+			.into_iter()
+			.skip(2 * PRUNE_PEERS)
+			.map(|event| (event.connection, event.kind))
+			.collect::<Vec<_>>();
+		let (closed, opened) = events.split_at(PRUNE_PEERS - 1);
+		assert!(closed
+			.iter()
+			.all(|&(connection, ref kind)| { (2..=1024).contains(&connection) && *kind == EventKind::Closed }));
+		assert_eq!(opened, [
+			(1025, EventKind::Opened(String::from("127.0.0.1:2000"))),
+			(1025, EventKind::Read(b"version".to_vec())),
+			(1026, EventKind::Opened(String::from("127.0.0.1:2"))),
+			(1026, EventKind::Read(b"version".to_vec())),
+		]);
+		fs::remove_dir_all(directory).unwrap();
+	}
+
+	#[test]
+	fn captures_of_one_problem_get_their_own_file() {
+		let directory = directory("several");
+		let first = Capture::create(&directory, "primetime").unwrap();
+		let second = Capture::create(&directory, "primetime").unwrap();
+		assert_ne!(first.path, second.path);
+		assert_eq!(Session::read(&second.path).unwrap().problem, "primetime");
+		fs::remove_dir_all(directory).unwrap();
+	}
+
+	#[test]
+	fn incomplete_last_event_is_ignored() {
+		let directory = directory("incomplete");
+		let capture = Capture::create(&directory, "primetime").unwrap();
+		let peer = Address::from(SocketAddr::from(([127, 0, 0, 1], 1000)));
+		capture.received(&peer, b"request");
+		let mut data = fs::read(&capture.path).unwrap();
+		let complete = data.len();
+		capture.sent(&peer, b"response");
+		data = fs::read(&capture.path).unwrap();
+		fs::write(&capture.path, &data[..complete + 5]).unwrap();
+
+		assert_eq!(Session::read(&capture.path).unwrap().events.len(), 2);
+		fs::remove_dir_all(directory).unwrap();
+	}
+
+	#[test]
+	fn not_a_session() {
+		let directory = directory("invalid");
+		fs::create_dir_all(&directory).unwrap();
+		let path = directory.join("invalid.session");
+		fs::write(&path, b"\x00\x00\x00\x00\x01\x00").unwrap();
+
+		let error = Session::read(&path).unwrap_err().to_string();
+		assert!(error.ends_with("not a session file"), "{error}");
+		fs::remove_dir_all(directory).unwrap();
+	}
+}
@@ -0,0 +1,1013 @@
+use std::{
+	env,
+	net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+	num::{NonZeroU32, NonZeroUsize},
+	path::PathBuf,
+	time::Duration,
+};
+
+use anyhow::{anyhow, Error};
+
+use crate::{
+	address::Address,
+	client::Load,
+	limits::{IpNetwork, SourceLimits},
+	logger::{Filter, Format},
+	options::{parse_count, parse_duration, parse_size, Options},
+	registry::{self, Problem, Transport},
+	socket::Bind,
+	thread_pool::{Overflow, PoolSize},
+	timeouts::Timeouts,
+	tls::TlsFiles,
+};
+
+const USAGE: &str = "\
+Solutions to the Protohackers challenge
+
+Usage:
+  mitmaro-protohackers list
+  mitmaro-protohackers serve [OPTIONS] <problem>
+  mitmaro-protohackers serve [OPTIONS] <problem>=<port>...
+  mitmaro-protohackers serve [OPTIONS] <problem>=unix:<path>...
+  mitmaro-protohackers replay [OPTIONS] <session>
+  mitmaro-protohackers client [OPTIONS] <problem> [<address>]
+  mitmaro-protohackers help
+
+Commands:
+  list     List the available problems and their options
+  serve    Start one or more problem servers
+  replay   Feed a session captured with serve --capture back into its problem and compare the responses
+  client   Send the requests of a problem, one a line from standard input, to a server at an address and port, or
+           unix:<path>, and print the responses, see list for the requests of each problem [default: 127.0.0.1:7878]
+  help     Print this help
+
+Serve options:
+  -b, --bind <address>         Address to bind listeners to, repeat or comma separate to bind several, [::] binds
+                               IPv6 and IPv4 when no IPv4 address is bound on the same port [default: 0.0.0.0]
+  -p, --port <port>            Port for a single problem, or unix:<path> to serve it on a unix socket, a stale socket
+                               file is replaced and the file is removed on shutdown [env: PORT] [default: 7878]
+  -w, --workers <count>        Most thread pool workers for each TCP problem, threadpool backend only
+                               [env: CONCURRENCY] [default: 10]
+      --min-workers <count>    Thread pool workers kept running while idle, threadpool backend only [default: 1]
+      --worker-idle-timeout <time>
+                               Time a worker above the minimum waits for a connection before it stops, threadpool
+                               backend only [default: 60s]
+      --client-workers <count> Most workers handling connections from one IP address, waiting connections are
+                               taken in turn from each address, 0 for no limit, threadpool backend only [default: 0]
+      --queue-capacity <count> Connections that wait for a free worker before the overflow policy applies, 0 for no
+                               limit, threadpool backend only [default: 100]
+      --overflow <policy>      What to do with connections when the queue is full, block stops accepting them,
+                               reject sends the problem's busy response and close closes them, threadpool backend
+                               only [default: block]
+      --max-connections-per-ip <count>
+                               Connections open at once from one IP address, more are closed on accept, 0 for no
+                               limit [default: 0]
+      --connection-rate <count>
+                               New connections a second from one IP address, more are closed on accept, 0 for no
+                               limit [default: 0]
+      --datagram-rate <count>  UDP datagrams a second from one IP address, more are dropped, 0 for no limit
+                               [default: 0]
+      --allow <network>        IP address or CIDR network exempt from the per address limits, repeat or comma
+                               separate to allow several, e.g. 10.0.0.0/8
+      --tls-cert <path>        PEM certificate chain, serves every TCP problem over TLS with --tls-key, the reject
+                               overflow policy closes connections without a busy response, TCP problems cannot be
+                               on a unix socket
+      --tls-key <path>         PEM private key for --tls-cert
+      --backend <backend>      Connection backend, threadpool or async [env: BACKEND] [default: threadpool]
+      --async-threads <count>  Worker threads of the async runtime [default: number of CPUs]
+      --drain-timeout <time>   Time to wait for open connections to close on shutdown [default: 10s]
+      --log <filter>           Log level, followed by optional per module levels, e.g. \
+                     info,budget_chat=debug,worker=off,
+                               levels are off, error, warn, info, debug and trace [env: LOG] [default: info]
+      --log-format <format>    Log output format, text or json [env: LOG_FORMAT] [default: text]
+      --log-payloads           Log received payloads, toggle while running with SIGUSR1
+      --metrics <address>      Serve Prometheus metrics over HTTP on /metrics, e.g. 127.0.0.1:9100 [env: METRICS]
+      --capture <directory>    Write what every connection reads and writes, with timestamps, to a session file
+                               for each problem in the directory, which is created if missing
+  -o, --option <key=value>     Set a problem option, use <problem>.<key>=<value> to target a single problem, every
+                               TCP problem has idle-timeout, write-timeout and session-timeout options, set to off
+                               for no timeout, see list
+  -h, --help                   Print this help
+
+Replay options:
+  -o, --option <key=value>     Set an option of the problem of the session, see list
+      --wait <time>            Time to wait for the problem to finish responding once the session is replayed
+                               [default: 5s]
+  -h, --help                   Print this help
+
+Client options:
+      --script <path>          Send the requests in a file instead of those from standard input
+      --load                   Send generated requests on several connections at once and report the throughput
+                               and the latency of the responses
+  -c, --connections <count>    Connections of a load test, the users joining a budgetchat room [default: 10]
+  -n, --requests <count>       Requests each connection of a load test sends [default: 1000]
+  -h, --help                   Print this help
+
+Signals:
+  SIGUSR1  Toggle payload logging
+  SIGUSR2  Print the state of every thread pool worker and the connection it is handling
+";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Backend {
+	ThreadPool,
+	Async,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ServerArgs {
+	pub(crate) problem: &'static Problem,
+	pub(crate) bind: Bind,
+	pub(crate) options: Options,
+	pub(crate) timeouts: Timeouts,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ServeArgs {
+	pub(crate) servers: Vec<ServerArgs>,
+	pub(crate) pool_size: PoolSize,
+	pub(crate) queue_capacity: Option<NonZeroUsize>,
+	pub(crate) overflow: Overflow,
+	pub(crate) limits: SourceLimits,
+	pub(crate) tls: Option<TlsFiles>,
+	pub(crate) backend: Backend,
+	pub(crate) async_threads: Option<NonZeroUsize>,
+	pub(crate) drain_timeout: Duration,
+	pub(crate) log_filter: Filter,
+	pub(crate) log_format: Format,
+	pub(crate) log_payloads: bool,
+	pub(crate) metrics: Option<SocketAddr>,
+	pub(crate) capture: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ReplayArgs {
+	pub(crate) session: PathBuf,
+	// resolved once the session is read, the problem is only known from the session
+	pub(crate) options: Vec<(String, String)>,
+	pub(crate) wait: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ClientArgs {
+	pub(crate) problem: &'static Problem,
+	pub(crate) address: Address,
+	pub(crate) script: Option<PathBuf>,
+	// a load test instead of the requests of the script or standard input
+	pub(crate) load: Option<Load>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Command {
+	Help,
+	List,
+	Serve(Box<ServeArgs>),
+	Replay(ReplayArgs),
+	Client(ClientArgs),
+}
+
+pub(crate) const fn usage() -> &'static str {
+	USAGE
+}
+
+pub(crate) fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Command, Error> {
+	match args.next().as_deref() {
+		None | Some("help" | "-h" | "--help") => Ok(Command::Help),
+		Some("list") => Ok(Command::List),
+		Some("serve") => parse_serve(args),
+		Some("replay") => parse_replay(args),
+		Some("client") => parse_client(args),
+		Some(command) => Err(anyhow!("Unknown command: {command}, see help for usage")),
+	}
+}
+
+#[derive(Debug, Default)]
+struct RawServeArgs {
+	problems: Vec<String>,
+	bind: Vec<String>,
+	port: Option<String>,
+	workers: Option<String>,
+	min_workers: Option<String>,
+	worker_idle_timeout: Option<String>,
+	client_workers: Option<String>,
+	queue_capacity: Option<String>,
+	overflow: Option<String>,
+	max_connections_per_ip: Option<String>,
+	connection_rate: Option<String>,
+	datagram_rate: Option<String>,
+	allow: Vec<String>,
+	backend: Option<String>,
+	async_threads: Option<String>,
+	drain_timeout: Option<String>,
+	tls_cert: Option<String>,
+	tls_key: Option<String>,
+	log: Option<String>,
+	log_format: Option<String>,
+	log_payloads: bool,
+	metrics: Option<String>,
+	capture: Option<String>,
+	options: Vec<String>,
+}
+
+fn flag_value<I: Iterator<Item = String>>(flag: &str, inline: Option<&str>, args: &mut I) -> Result<String, Error> {
+	inline
+		.map(String::from)
+		.or_else(|| args.next())
+		.ok_or_else(|| anyhow!("Missing value for {flag}"))
+}
+
+fn parse_serve<I: Iterator<Item = String>>(mut args: I) -> Result<Command, Error> {
+	let mut raw = RawServeArgs::default();
+
+	while let Some(arg) = args.next() {
+		let (flag, inline) = match arg.split_once('=') {
+			Some((flag, value)) if arg.starts_with("--") => (flag, Some(value)),
+			_ => (arg.as_str(), None),
+		};
+
+		match flag {
+			"-h" | "--help" => return Ok(Command::Help),
+			"-b" | "--bind" => raw.bind.push(flag_value(flag, inline, &mut args)?),
+			"-p" | "--port" => raw.port = Some(flag_value(flag, inline, &mut args)?),
+			"-w" | "--workers" => raw.workers = Some(flag_value(flag, inline, &mut args)?),
+			"--min-workers" => raw.min_workers = Some(flag_value(flag, inline, &mut args)?),
+			"--worker-idle-timeout" => raw.worker_idle_timeout = Some(flag_value(flag, inline, &mut args)?),
+			"--client-workers" => raw.client_workers = Some(flag_value(flag, inline, &mut args)?),
+			"--queue-capacity" => raw.queue_capacity = Some(flag_value(flag, inline, &mut args)?),
+			"--overflow" => raw.overflow = Some(flag_value(flag, inline, &mut args)?),
+			"--max-connections-per-ip" => raw.max_connections_per_ip = Some(flag_value(flag, inline, &mut args)?),
+			"--connection-rate" => raw.connection_rate = Some(flag_value(flag, inline, &mut args)?),
+			"--datagram-rate" => raw.datagram_rate = Some(flag_value(flag, inline, &mut args)?),
+			"--allow" => raw.allow.push(flag_value(flag, inline, &mut args)?),
+			"--backend" => raw.backend = Some(flag_value(flag, inline, &mut args)?),
+			"--async-threads" => raw.async_threads = Some(flag_value(flag, inline, &mut args)?),
+			"--drain-timeout" => raw.drain_timeout = Some(flag_value(flag, inline, &mut args)?),
+			"--tls-cert" => raw.tls_cert = Some(flag_value(flag, inline, &mut args)?),
+			"--tls-key" => raw.tls_key = Some(flag_value(flag, inline, &mut args)?),
+			"--log" => raw.log = Some(flag_value(flag, inline, &mut args)?),
+			"--log-format" => raw.log_format = Some(flag_value(flag, inline, &mut args)?),
+			"--log-payloads" => raw.log_payloads = true,
+			"--metrics" => raw.metrics = Some(flag_value(flag, inline, &mut args)?),
+			"--capture" => raw.capture = Some(flag_value(flag, inline, &mut args)?),
+			"-o" | "--option" => raw.options.push(flag_value(flag, inline, &mut args)?),
+			_ if flag.starts_with('-') => return Err(anyhow!("Unknown option: {flag}, see help for usage")),
+			_ => raw.problems.push(arg),
+		}
+	}
+
+	let backend = parse_backend(raw.backend.take())?;
+	check_thread_pool_flags(&raw, backend)?;
+	let servers = parse_servers(&raw, &parse_bind(&raw.bind)?)?;
+	Ok(Command::Serve(Box::new(ServeArgs {
+		tls: parse_tls(&raw, &servers)?,
+		servers,
+		pool_size: parse_pool_size(&raw)?,
+		queue_capacity: NonZeroUsize::new(
+			parse_count(raw.queue_capacity.as_deref().unwrap_or("100"))
+				.map_err(|e| anyhow!("Invalid value for --queue-capacity: {e}"))?,
+		),
+		overflow: Overflow::parse(raw.overflow.as_deref().unwrap_or("block"))
+			.map_err(|e| anyhow!("Invalid value for --overflow: {e}"))?,
+		limits: parse_limits(&raw)?,
+		backend,
+		async_threads: raw
+			.async_threads
+			.map(|value| parse_size(value.as_str()).map_err(|e| anyhow!("Invalid value for --async-threads: {e}")))
+			.transpose()?,
+		drain_timeout: parse_duration(raw.drain_timeout.as_deref().unwrap_or("10s"))
+			.map_err(|e| anyhow!("Invalid value for --drain-timeout: {e}"))?,
+		log_filter: parse_env_flag("--log", "LOG", raw.log, "info", Filter::parse)?,
+		log_format: parse_env_flag("--log-format", "LOG_FORMAT", raw.log_format, "text", Format::parse)?,
+		log_payloads: raw.log_payloads,
+		metrics: raw
+			.metrics
+			.or_else(|| env::var("METRICS").ok())
+			.map(|value| parse_metrics_address(value.as_str()))
+			.transpose()?,
+		capture: raw.capture.map(PathBuf::from),
+	})))
+}
+
+fn parse_replay<I: Iterator<Item = String>>(mut args: I) -> Result<Command, Error> {
+	let mut sessions = vec![];
+	let mut options = vec![];
+	let mut wait = None;
+
+	while let Some(arg) = args.next() {
+		let (flag, inline) = match arg.split_once('=') {
+			Some((flag, value)) if arg.starts_with("--") => (flag, Some(value)),
+			_ => (arg.as_str(), None),
+		};
+
+		match flag {
+			"-h" | "--help" => return Ok(Command::Help),
+			"-o" | "--option" => {
+				let option = flag_value(flag, inline, &mut args)?;
+				let (key, value) = option
+					.split_once('=')
+					.ok_or_else(|| anyhow!("Invalid value for --option: expected <key>=<value>, found: {option}"))?;
+				options.push((String::from(key), String::from(value)));
+			},
+			"--wait" => wait = Some(flag_value(flag, inline, &mut args)?),
+			_ if flag.starts_with('-') => return Err(anyhow!("Unknown option: {flag}, see help for usage")),
+			_ => sessions.push(arg),
+		}
+	}
+
+	let session = match sessions.len() {
+		0 => return Err(anyhow!("No session given, see help for usage")),
+		1 => PathBuf::from(sessions.remove(0)),
+		_ => return Err(anyhow!("Only one session can be replayed at a time")),
+	};
+	Ok(Command::Replay(ReplayArgs {
+		session,
+		options,
+		wait: parse_duration(wait.as_deref().unwrap_or("5s")).map_err(|e| anyhow!("Invalid value for --wait: {e}"))?,
+	}))
+}
+
+fn parse_client<I: Iterator<Item = String>>(mut args: I) -> Result<Command, Error> {
+	let mut positional = vec![];
+	let mut script = None;
+	let mut load = false;
+	let mut connections = None;
+	let mut requests = None;
+
+	while let Some(arg) = args.next() {
+		let (flag, inline) = match arg.split_once('=') {
+			Some((flag, value)) if arg.starts_with("--") => (flag, Some(value)),
+			_ => (arg.as_str(), None),
+		};
+
+		match flag {
+			"-h" | "--help" => return Ok(Command::Help),
+			"--script" => script = Some(flag_value(flag, inline, &mut args)?),
+			"--load" => load = true,
+			"-c" | "--connections" => connections = Some(flag_value(flag, inline, &mut args)?),
+			"-n" | "--requests" => requests = Some(flag_value(flag, inline, &mut args)?),
+			_ if flag.starts_with('-') => return Err(anyhow!("Unknown option: {flag}, see help for usage")),
+			_ => positional.push(arg),
+		}
+	}
+
+	let mut positional = positional.into_iter();
+	let name = positional.next().ok_or_else(|| {
+		anyhow!(
+			"No problem selected, available problems:\n{}",
+			registry::problem_list().trim_end()
+		)
+	})?;
+	let problem = registry::find(&name).ok_or_else(|| {
+		anyhow!(
+			"Unknown problem: {name}, available problems:\n{}",
+			registry::problem_list().trim_end()
+		)
+	})?;
+	let address = parse_address(positional.next().as_deref().unwrap_or("127.0.0.1:7878"))?;
+	if let Some(extra) = positional.next() {
+		return Err(anyhow!("Unexpected argument: {extra}, see help for usage"));
+	}
+	if load && script.is_some() {
+		return Err(anyhow!("--script and --load cannot be used together"));
+	}
+	if !load && (connections.is_some() || requests.is_some()) {
+		return Err(anyhow!("--connections and --requests are only used with --load"));
+	}
+
+	Ok(Command::Client(ClientArgs {
+		problem,
+		address,
+		script: script.map(PathBuf::from),
+		load: load
+			.then(|| {
+				Ok::<_, Error>(Load {
+					connections: parse_size(connections.as_deref().unwrap_or("10"))
+						.map_err(|e| anyhow!("Invalid value for --connections: {e}"))?,
+					requests: parse_size(requests.as_deref().unwrap_or("1000"))
+						.map_err(|e| anyhow!("Invalid value for --requests: {e}"))?,
+				})
+			})
+			.transpose()?,
+	}))
+}
+
+// the address of a server, a host and port or unix:<path>
+fn parse_address(value: &str) -> Result<Address, Error> {
+	if let Some(path) = value.strip_prefix("unix:") {
+		if !cfg!(unix) {
+			return Err(anyhow!(
+				"Invalid unix socket: unix sockets are not supported on this platform"
+			));
+		}
+		if path.is_empty() {
+			return Err(anyhow!("Invalid unix socket: expected unix:<path>, found: {value}"));
+		}
+		return Ok(Address::Unix(Some(PathBuf::from(path))));
+	}
+	value
+		.to_socket_addrs()
+		.map_err(|e| anyhow!("Invalid address: '{value}' must be a host and port or unix:<path>, {e}"))?
+		.next()
+		.map(Address::Ip)
+		.ok_or_else(|| anyhow!("Invalid address: '{value}' did not resolve to an IP address"))
+}
+
+fn parse_bind(bind: &[String]) -> Result<Vec<IpAddr>, Error> {
+	if bind.is_empty() {
+		return Ok(vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)]);
+	}
+
+	let mut addresses = vec![];
+	for address in bind.iter().flat_map(|value| value.split(',')) {
+		let address = address.trim();
+		let ip = address
+			.strip_prefix('[')
+			.and_then(|ip| ip.strip_suffix(']'))
+			.unwrap_or(address)
+			.parse::<IpAddr>()
+			.map_err(|_e| anyhow!("Invalid value for --bind: '{address}' is not an IP address"))?;
+
+		if addresses.contains(&ip) {
+			return Err(anyhow!("Invalid value for --bind: '{address}' is given more than once"));
+		}
+		addresses.push(ip);
+	}
+	Ok(addresses)
+}
+
+fn parse_limits(raw: &RawServeArgs) -> Result<SourceLimits, Error> {
+	Ok(SourceLimits {
+		connections: NonZeroUsize::new(
+			parse_count(raw.max_connections_per_ip.as_deref().unwrap_or("0"))
+				.map_err(|e| anyhow!("Invalid value for --max-connections-per-ip: {e}"))?,
+		),
+		connection_rate: parse_rate("--connection-rate", raw.connection_rate.as_deref())?,
+		datagram_rate: parse_rate("--datagram-rate", raw.datagram_rate.as_deref())?,
+		allow: raw
+			.allow
+			.iter()
+			.flat_map(|value| value.split(','))
+			.map(|network| IpNetwork::parse(network.trim()).map_err(|e| anyhow!("Invalid value for --allow: {e}")))
+			.collect::<Result<Vec<IpNetwork>, Error>>()?,
+	})
+}
+
+// the async backend has no thread pool, so the flags that tune it would be ignored
+fn check_thread_pool_flags(raw: &RawServeArgs, backend: Backend) -> Result<(), Error> {
+	if backend == Backend::ThreadPool {
+		return Ok(());
+	}
+	let flags = [
+		("--workers", raw.workers.is_some()),
+		("--min-workers", raw.min_workers.is_some()),
+		("--worker-idle-timeout", raw.worker_idle_timeout.is_some()),
+		("--client-workers", raw.client_workers.is_some()),
+		("--queue-capacity", raw.queue_capacity.is_some()),
+		("--overflow", raw.overflow.is_some()),
+	];
+	match flags.iter().find(|&&(_, set)| set) {
+		Some(&(flag, _)) => Err(anyhow!("{flag} is only supported by the threadpool backend")),
+		None => Ok(()),
+	}
+}
+
+fn parse_tls(raw: &RawServeArgs, servers: &[ServerArgs]) -> Result<Option<TlsFiles>, Error> {
+	match (raw.tls_cert.as_deref(), raw.tls_key.as_deref()) {
+		(None, None) => Ok(None),
+		(Some(cert), Some(key)) => {
+			if let Some(server) = servers
+				.iter()
+				.find(|server| server.problem.transport == Transport::Tcp && matches!(server.bind, Bind::Unix(_)))
+			{
+				return Err(anyhow!(
+					"--tls-cert and --tls-key cannot be used with {} on a unix socket",
+					server.problem.name
+				));
+			}
+			Ok(Some(TlsFiles {
+				cert: PathBuf::from(cert),
+				key: PathBuf::from(key),
+			}))
+		},
+		_ => Err(anyhow!("--tls-cert and --tls-key must be used together")),
+	}
+}
+
+fn parse_rate(flag: &str, rate: Option<&str>) -> Result<Option<NonZeroU32>, Error> {
+	let rate = rate.unwrap_or("0");
+	Ok(NonZeroU32::new(rate.parse::<u32>().map_err(|_e| {
+		anyhow!("Invalid value for {flag}: '{rate}' must be zero or a positive integer")
+	})?))
+}
+
+fn parse_metrics_address(address: &str) -> Result<SocketAddr, Error> {
+	address.parse::<SocketAddr>().map_err(|_e| {
+		anyhow!("Invalid value for --metrics: '{address}' must be an address and port, e.g. 127.0.0.1:9100")
+	})
+}
+
+fn parse_port(port: &str) -> Result<u16, Error> {
+	port.parse::<u16>()
+		.map_err(|_e| anyhow!("Invalid port: '{port}' must be between 0 and 65535"))
+}
+
+// a port, bound on every bind address, or unix:<path>
+fn parse_listen(value: &str, bind: &[IpAddr]) -> Result<Bind, Error> {
+	if let Some(path) = value.strip_prefix("unix:") {
+		if !cfg!(unix) {
+			return Err(anyhow!(
+				"Invalid unix socket: unix sockets are not supported on this platform"
+			));
+		}
+		if path.is_empty() {
+			return Err(anyhow!("Invalid unix socket: expected unix:<path>, found: {value}"));
+		}
+		return Ok(Bind::Unix(PathBuf::from(path)));
+	}
+	let port = parse_port(value)?;
+	Ok(Bind::Ip(bind.iter().map(|&ip| SocketAddr::new(ip, port)).collect()))
+}
+
+fn parse_pool_size(raw: &RawServeArgs) -> Result<PoolSize, Error> {
+	let max_workers = parse_workers(raw.workers.clone())?;
+	let min_workers = parse_count(raw.min_workers.as_deref().unwrap_or("1"))
+		.map_err(|e| anyhow!("Invalid value for --min-workers: {e}"))?;
+	if min_workers > max_workers.get() {
+		return Err(anyhow!(
+			"Invalid value for --min-workers: {min_workers} is more than the {max_workers} workers allowed"
+		));
+	}
+
+	Ok(PoolSize {
+		min_workers,
+		max_workers,
+		idle_timeout: parse_duration(raw.worker_idle_timeout.as_deref().unwrap_or("60s"))
+			.map_err(|e| anyhow!("Invalid value for --worker-idle-timeout: {e}"))?,
+		client_workers: NonZeroUsize::new(
+			parse_count(raw.client_workers.as_deref().unwrap_or("0"))
+				.map_err(|e| anyhow!("Invalid value for --client-workers: {e}"))?,
+		),
+	})
+}
+
+fn parse_workers(workers: Option<String>) -> Result<NonZeroUsize, Error> {
+	let (source, value) = if let Some(value) = workers {
+		("--workers", value)
+	}
+	else {
+		(
+			"Environment variable CONCURRENCY",
+			env::var("CONCURRENCY").unwrap_or_else(|_| String::from("10")),
+		)
+	};
+
+	value
+		.parse::<NonZeroUsize>()
+		.map_err(|_e| anyhow!("{source} must be a positive integer"))
+}
+
+fn parse_env_flag<T>(
+	flag: &str,
+	variable: &str,
+	value: Option<String>,
+	default: &str,
+	parse: fn(&str) -> Result<T, Error>,
+) -> Result<T, Error> {
+	if let Some(value) = value {
+		parse(value.as_str()).map_err(|e| anyhow!("Invalid value for {flag}: {e}"))
+	}
+	else {
+		let value = env::var(variable).unwrap_or_else(|_| String::from(default));
+		parse(value.as_str()).map_err(|e| anyhow!("Invalid value for environment variable {variable}: {e}"))
+	}
+}
+
+fn parse_backend(backend: Option<String>) -> Result<Backend, Error> {
+	let (source, value) = if let Some(value) = backend {
+		("--backend", value)
+	}
+	else {
+		(
+			"Environment variable BACKEND",
+			env::var("BACKEND").unwrap_or_else(|_| String::from("threadpool")),
+		)
+	};
+
+	match value.to_lowercase().as_str() {
+		"threadpool" | "thread_pool" => Ok(Backend::ThreadPool),
+		"async" => Ok(Backend::Async),
+		_ => Err(anyhow!("{source} must be one of: threadpool, async")),
+	}
+}
+
+fn parse_servers(raw: &RawServeArgs, bind: &[IpAddr]) -> Result<Vec<ServerArgs>, Error> {
+	if raw.problems.is_empty() {
+		return Err(anyhow!(
+			"No problem selected, available problems:\n{}",
+			registry::problem_list().trim_end()
+		));
+	}
+
+	let options = parse_options(&raw.options)?;
+	let mut servers = vec![];
+
+	for arg in &raw.problems {
+		let (name, listen) = if let Some((name, listen)) = arg.split_once('=') {
+			(name, parse_listen(listen, bind)?)
+		}
+		else if raw.problems.len() > 1 {
+			return Err(anyhow!(
+				"Serving multiple problems requires <problem>=<port> or <problem>=unix:<path>, found: {arg}"
+			));
+		}
+		else if let Some(port) = raw.port.as_deref() {
+			(arg.as_str(), parse_listen(port, bind)?)
+		}
+		else {
+			let port = env::var("PORT").unwrap_or_else(|_| String::from("7878"));
+			(arg.as_str(), parse_listen(port.as_str(), bind)?)
+		};
+
+		let problem = registry::find(name).ok_or_else(|| {
+			anyhow!(
+				"Unknown problem: {name}, available problems:\n{}",
+				registry::problem_list().trim_end()
+			)
+		})?;
+
+		let declared = problem.options().collect::<Vec<_>>();
+		let options = Options::resolve(problem.name, &declared, &options_for(problem, &options))?;
+		servers.push(ServerArgs {
+			problem,
+			bind: listen,
+			timeouts: if problem.transport == Transport::Tcp {
+				Timeouts::from_options(&options)?
+			}
+			else {
+				Timeouts::default()
+			},
+			options,
+		});
+	}
+
+	for (target, name, _) in &options {
+		let applies = servers.iter().any(|server| {
+			target.map_or(true, |target| target.name == server.problem.name)
+				&& server.problem.options().any(|option| option.name == name)
+		});
+		if !applies {
+			return Err(anyhow!("Option {name} does not apply to any selected problem"));
+		}
+	}
+
+	Ok(servers)
+}
+
+type ProblemOptionValue = (Option<&'static Problem>, String, String);
+
+fn parse_options(options: &[String]) -> Result<Vec<ProblemOptionValue>, Error> {
+	let mut parsed = vec![];
+	for option in options {
+		let (key, value) = option
+			.split_once('=')
+			.ok_or_else(|| anyhow!("Invalid value for --option: expected <key>=<value>, found: {option}"))?;
+
+		if let Some((problem, name)) = key.split_once('.') {
+			let target =
+				registry::find(problem).ok_or_else(|| anyhow!("Unknown problem in option {key}: {problem}"))?;
+			parsed.push((Some(target), String::from(name), String::from(value)));
+		}
+		else {
+			parsed.push((None, String::from(key), String::from(value)));
+		}
+	}
+	Ok(parsed)
+}
+
+fn options_for(problem: &Problem, options: &[ProblemOptionValue]) -> Vec<(String, String)> {
+	options
+		.iter()
+		.filter(|&&(target, ref name, _)| {
+			target.map_or_else(
+				|| problem.options().any(|option| option.name == name.as_str()),
+				|target| target.name == problem.name,
+			)
+		})
+		.map(|(_, name, value)| (name.clone(), value.clone()))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Mutex, PoisonError};
+
+	use super::*;
+
+	// the environment is shared by every test, so the tests that read it take turns
+	static ENVIRONMENT: Mutex<()> = Mutex::new(());
+	const VARIABLES: [&str; 6] = ["PORT", "CONCURRENCY", "BACKEND", "LOG", "LOG_FORMAT", "METRICS"];
+
+	fn with_env<T>(variables: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+		let _lock = ENVIRONMENT.lock().unwrap_or_else(PoisonError::into_inner);
+		for name in VARIABLES {
+			env::remove_var(name);
+		}
+		for &(name, value) in variables {
+			env::set_var(name, value);
+		}
+		let result = f();
+		for name in VARIABLES {
+			env::remove_var(name);
+		}
+		result
+	}
+
+	fn args(args: &[&str]) -> impl Iterator<Item = String> {
+		args.iter()
+			.map(|&arg| String::from(arg))
+			.collect::<Vec<_>>()
+			.into_iter()
+	}
+
+	fn serve(variables: &[(&str, &str)], arguments: &[&str]) -> Result<ServeArgs, Error> {
+		with_env(variables, || {
+			let Command::Serve(serve) = parse(args(&["serve"]).chain(args(arguments)))?
+			else {
+				return Err(anyhow!("Not a serve command"));
+			};
+			Ok(*serve)
+		})
+	}
+
+	fn serve_error(arguments: &[&str]) -> String {
+		serve(&[], arguments).unwrap_err().to_string()
+	}
+
+	fn replay(arguments: &[&str]) -> Result<ReplayArgs, Error> {
+		let Command::Replay(replay) = parse(args(&["replay"]).chain(args(arguments)))?
+		else {
+			return Err(anyhow!("Not a replay command"));
+		};
+		Ok(replay)
+	}
+
+	fn client(arguments: &[&str]) -> Result<ClientArgs, Error> {
+		let Command::Client(client) = parse(args(&["client"]).chain(args(arguments)))?
+		else {
+			return Err(anyhow!("Not a client command"));
+		};
+		Ok(client)
+	}
+
+	fn port(port: u16) -> Bind {
+		Bind::Ip(vec![SocketAddr::from(([0, 0, 0, 0], port))])
+	}
+
+	#[test]
+	fn serve_defaults() {
+		let serve = serve(&[], &["smoketest"]).unwrap();
+		assert_eq!(serve.servers.len(), 1);
+		assert_eq!(serve.servers[0].problem.name, "smoketest");
+		assert_eq!(serve.servers[0].bind, port(7878));
+		assert_eq!(serve.pool_size.max_workers.get(), 10);
+		assert_eq!(serve.backend, Backend::ThreadPool);
+		assert_eq!(serve.log_filter, Filter::parse("info").unwrap());
+		assert_eq!(serve.log_format, Format::Text);
+		assert_eq!(serve.metrics, None);
+	}
+
+	#[test]
+	fn serve_environment_fallbacks() {
+		let serve = serve(
+			&[
+				("PORT", "9000"),
+				("CONCURRENCY", "3"),
+				("BACKEND", "async"),
+				("LOG", "debug"),
+				("LOG_FORMAT", "json"),
+				("METRICS", "127.0.0.1:9100"),
+			],
+			&["smoketest"],
+		)
+		.unwrap();
+		assert_eq!(serve.servers[0].bind, port(9000));
+		assert_eq!(serve.pool_size.max_workers.get(), 3);
+		assert_eq!(serve.backend, Backend::Async);
+		assert_eq!(serve.log_filter, Filter::parse("debug").unwrap());
+		assert_eq!(serve.log_format, Format::Json);
+		assert_eq!(serve.metrics, Some(SocketAddr::from(([127, 0, 0, 1], 9100))));
+	}
+
+	#[test]
+	fn serve_flags_override_environment() {
+		let serve = serve(
+			&[
+				("PORT", "9000"),
+				("CONCURRENCY", "3"),
+				("BACKEND", "async"),
+				("LOG", "debug"),
+				("LOG_FORMAT", "json"),
+				("METRICS", "127.0.0.1:9100"),
+			],
+			&[
+				"smoketest",
+				"-p",
+				"9001",
+				"-w",
+				"4",
+				"--backend=threadpool",
+				"--log",
+				"warn",
+				"--log-format",
+				"text",
+				"--metrics",
+				"127.0.0.1:9101",
+			],
+		)
+		.unwrap();
+		assert_eq!(serve.servers[0].bind, port(9001));
+		assert_eq!(serve.pool_size.max_workers.get(), 4);
+		assert_eq!(serve.backend, Backend::ThreadPool);
+		assert_eq!(serve.log_filter, Filter::parse("warn").unwrap());
+		assert_eq!(serve.log_format, Format::Text);
+		assert_eq!(serve.metrics, Some(SocketAddr::from(([127, 0, 0, 1], 9101))));
+	}
+
+	#[test]
+	fn serve_several_problems() {
+		let serve = serve(&[], &[
+			"echo=7001",
+			"primetime=unix:/tmp/primetime.sock",
+			"-o",
+			"1.idle-timeout=off",
+			"-o",
+			"0.idle-timeout=5s",
+		])
+		.unwrap();
+		assert_eq!(serve.servers[0].problem.name, "smoketest");
+		assert_eq!(serve.servers[0].bind, port(7001));
+		assert_eq!(serve.servers[0].timeouts.idle, Some(Duration::from_secs(5)));
+		assert_eq!(serve.servers[1].problem.name, "primetime");
+		assert_eq!(serve.servers[1].bind, Bind::Unix(PathBuf::from("/tmp/primetime.sock")));
+		assert_eq!(serve.servers[1].timeouts.idle, None);
+	}
+
+	#[test]
+	fn serve_unknown_problem() {
+		assert!(serve_error(&["nope"]).starts_with("Unknown problem: nope, available problems:\n"));
+	}
+
+	#[test]
+	fn serve_invalid_port() {
+		assert_eq!(
+			serve_error(&["smoketest=70000"]),
+			"Invalid port: '70000' must be between 0 and 65535"
+		);
+		assert_eq!(
+			serve_error(&["smoketest=7001", "primetime"]),
+			"Serving multiple problems requires <problem>=<port> or <problem>=unix:<path>, found: primetime"
+		);
+	}
+
+	#[test]
+	fn serve_invalid_option() {
+		assert_eq!(
+			serve_error(&["primetime", "-o", "idle-timeout=soon"]),
+			"Invalid value for option primetime.idle-timeout: 'soon' is not a duration, expected seconds or \
+			 milliseconds, e.g. 5s or 500ms"
+		);
+		assert_eq!(
+			serve_error(&["smoketest", "-o", "max-line-length=10"]),
+			"Option max-line-length does not apply to any selected problem"
+		);
+		assert_eq!(
+			serve_error(&["smoketest", "-o", "idle-timeout"]),
+			"Invalid value for --option: expected <key>=<value>, found: idle-timeout"
+		);
+	}
+
+	#[test]
+	fn serve_thread_pool_flags_with_async_backend() {
+		assert_eq!(
+			serve_error(&["smoketest", "--backend", "async", "--queue-capacity", "5"]),
+			"--queue-capacity is only supported by the threadpool backend"
+		);
+		let serve = serve(&[], &["smoketest", "--backend", "threadpool", "--queue-capacity", "5"]).unwrap();
+		assert_eq!(serve.queue_capacity, NonZeroUsize::new(5));
+	}
+
+	#[test]
+	fn serve_tls() {
+		let tls = Some(TlsFiles {
+			cert: PathBuf::from("cert.pem"),
+			key: PathBuf::from("key.pem"),
+		});
+		let parsed = serve(&[], &["smoketest", "--tls-cert", "cert.pem", "--tls-key=key.pem"]).unwrap();
+		assert_eq!(parsed.tls, tls);
+		let parsed = serve(&[], &[
+			"smoketest",
+			"--backend",
+			"async",
+			"--tls-cert",
+			"cert.pem",
+			"--tls-key",
+			"key.pem",
+		])
+		.unwrap();
+		assert_eq!(parsed.tls, tls);
+		// UDP problems are never served over TLS, so they may be on a unix socket
+		let parsed = serve(&[], &[
+			"smoketest=7000",
+			"kv=unix:/tmp/kv.sock",
+			"--tls-cert",
+			"cert.pem",
+			"--tls-key",
+			"key.pem",
+		])
+		.unwrap();
+		assert_eq!(parsed.tls, tls);
+	}
+
+	#[test]
+	fn serve_tls_errors() {
+		assert_eq!(
+			serve_error(&["smoketest", "--tls-cert", "cert.pem"]),
+			"--tls-cert and --tls-key must be used together"
+		);
+		assert_eq!(
+			serve_error(&[
+				"smoketest=7000",
+				"primetime=unix:/tmp/primetime.sock",
+				"--tls-cert",
+				"cert.pem",
+				"--tls-key",
+				"key.pem"
+			]),
+			"--tls-cert and --tls-key cannot be used with primetime on a unix socket"
+		);
+	}
+
+	#[test]
+	fn replay_session() {
+		let replay = replay(&["a.session", "-o", "idle-timeout=1s", "--wait=1s"]).unwrap();
+		assert_eq!(replay.session, PathBuf::from("a.session"));
+		assert_eq!(replay.options, vec![(String::from("idle-timeout"), String::from("1s"))]);
+		assert_eq!(replay.wait, Duration::from_secs(1));
+	}
+
+	#[test]
+	fn replay_errors() {
+		let error = |arguments: &[&str]| parse(args(arguments)).unwrap_err().to_string();
+		assert_eq!(error(&["replay"]), "No session given, see help for usage");
+		assert_eq!(
+			error(&["replay", "a", "b"]),
+			"Only one session can be replayed at a time"
+		);
+		assert_eq!(
+			error(&["replay", "a", "-o", "wait"]),
+			"Invalid value for --option: expected <key>=<value>, found: wait"
+		);
+	}
+
+	#[test]
+	fn client_defaults() {
+		let client = client(&["primetime"]).unwrap();
+		assert_eq!(client.problem.name, "primetime");
+		assert_eq!(client.address, Address::Ip(SocketAddr::from(([127, 0, 0, 1], 7878))));
+		assert_eq!(client.load, None);
+	}
+
+	#[test]
+	fn client_load() {
+		let client = client(&["primetime", "unix:/tmp/primetime.sock", "--load", "-c", "2"]).unwrap();
+		assert_eq!(
+			client.address,
+			Address::Unix(Some(PathBuf::from("/tmp/primetime.sock")))
+		);
+		assert_eq!(
+			client.load,
+			Some(Load {
+				connections: NonZeroUsize::new(2).unwrap(),
+				requests: NonZeroUsize::new(1000).unwrap(),
+			})
+		);
+	}
+
+	#[test]
+	fn client_errors() {
+		let error = |arguments: &[&str]| client(arguments).unwrap_err().to_string();
+		assert!(error(&["nope"]).starts_with("Unknown problem: nope"));
+		assert_eq!(
+			error(&["echo", "--load", "--script", "requests.txt"]),
+			"--script and --load cannot be used together"
+		);
+		assert_eq!(
+			error(&["echo", "-n", "5"]),
+			"--connections and --requests are only used with --load"
+		);
+		assert_eq!(
+			error(&["echo", "127.0.0.1:1", "extra"]),
+			"Unexpected argument: extra, see help for usage"
+		);
+	}
+}
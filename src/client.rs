@@ -0,0 +1,272 @@
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::{
+	fmt::{self, Display, Formatter},
+	fs::File,
+	io::{self, stdin, stdout, BufRead, BufReader, ErrorKind, Write},
+	net::{SocketAddr, TcpStream, UdpSocket},
+	num::NonZeroUsize,
+	thread,
+	time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+
+#[cfg(unix)]
+use crate::unix::{self, SocketFile};
+use crate::{address::Address, cli::ClientArgs, logger::warning, stream::Stream};
+
+// how long a client waits for the response to a datagram, which may have been lost
+pub(crate) const DATAGRAM_TIMEOUT: Duration = Duration::from_secs(1);
+
+// the client of a problem, which sends the requests read from the input and writes the responses to the output, or
+// sends generated requests for a load test
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct ProblemClient {
+	pub(crate) interactive: fn(&Address, &mut dyn BufRead, &mut (dyn Write + Send)) -> Result<(), Error>,
+	pub(crate) load: fn(&Address, &Load) -> LoadReport,
+	// the requests interactive takes, one a line, shown in the help
+	pub(crate) requests: &'static str,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Load {
+	pub(crate) connections: NonZeroUsize,
+	pub(crate) requests: NonZeroUsize,
+}
+
+// the latency of the responses a connection received, and the requests that failed
+#[derive(Debug, Default)]
+pub(crate) struct Samples {
+	latencies: Vec<Duration>,
+	errors: usize,
+}
+
+impl Samples {
+	pub(crate) fn responded(&mut self, latency: Duration) {
+		self.latencies.push(latency);
+	}
+
+	pub(crate) fn failed(&mut self) {
+		self.errors += 1;
+	}
+}
+
+#[derive(Debug)]
+pub(crate) struct LoadReport {
+	connections: usize,
+	elapsed: Duration,
+	// sorted, fastest first
+	latencies: Vec<Duration>,
+	errors: usize,
+}
+
+impl LoadReport {
+	// the latency a percentage of the responses were at or under
+	fn percentile(&self, percent: usize) -> Duration {
+		let rank = (percent * self.latencies.len()).div_ceil(100);
+		self.latencies.get(rank.saturating_sub(1)).copied().unwrap_or_default()
+	}
+}
+
+impl Display for LoadReport {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		let responses = u32::try_from(self.latencies.len()).unwrap_or(u32::MAX);
+		writeln!(
+			f,
+			"{} responses over {} connections in {:.2}s, {:.1} responses a second, {} errors",
+			self.latencies.len(),
+			self.connections,
+			self.elapsed.as_secs_f64(),
+			f64::from(responses) / self.elapsed.as_secs_f64(),
+			self.errors
+		)?;
+		writeln!(
+			f,
+			"Latency: p50 {}, p90 {}, p99 {}, max {}",
+			milliseconds(self.percentile(50)),
+			milliseconds(self.percentile(90)),
+			milliseconds(self.percentile(99)),
+			milliseconds(self.latencies.last().copied().unwrap_or_default())
+		)
+	}
+}
+
+fn milliseconds(duration: Duration) -> String {
+	format!("{:.3}ms", duration.as_secs_f64() * 1000.0)
+}
+
+pub(crate) fn run(args: &ClientArgs) -> Result<(), Error> {
+	let client = args.problem.client;
+	if let Some(ref load) = args.load {
+		let report = (client.load)(&args.address, load);
+		return stdout()
+			.lock()
+			.write_all(report.to_string().as_bytes())
+			.map_err(Error::from);
+	}
+	let mut output = stdout();
+	if let Some(ref script) = args.script {
+		let file = File::open(script).map_err(|e| anyhow!("Unable to open script {}: {e}", script.display()))?;
+		(client.interactive)(&args.address, &mut BufReader::new(file), &mut output)
+	}
+	else {
+		(client.interactive)(&args.address, &mut stdin().lock(), &mut output)
+	}
+}
+
+// runs a connection of a load test on a thread of its own, a connection that fails counts as an error
+pub(crate) fn load_connections<F>(load: &Load, connection: F) -> LoadReport
+where F: Fn(usize, &mut Samples) -> Result<(), Error> + Sync {
+	let started = Instant::now();
+	let samples = thread::scope(|s| {
+		let handles = (0..load.connections.get())
+			.map(|index| {
+				let connection = &connection;
+				s.spawn(move || {
+					let mut samples = Samples::default();
+					if let Err(e) = connection(index, &mut samples) {
+						warning!("Connection {index}: {e}");
+						samples.failed();
+					}
+					samples
+				})
+			})
+			.collect::<Vec<_>>();
+		handles
+			.into_iter()
+			.map(|handle| handle.join().unwrap())
+			.collect::<Vec<_>>()
+	});
+
+	let mut latencies = vec![];
+	let mut errors = 0;
+	for mut connection in samples {
+		latencies.append(&mut connection.latencies);
+		errors += connection.errors;
+	}
+	latencies.sort_unstable();
+	LoadReport {
+		connections: load.connections.get(),
+		elapsed: started.elapsed(),
+		latencies,
+		errors,
+	}
+}
+
+pub(crate) fn connect(address: &Address) -> Result<Box<dyn Stream>, Error> {
+	match *address {
+		Address::Ip(addr) => {
+			let stream = TcpStream::connect(addr).map_err(|e| anyhow!("Unable to connect to {addr}: {e}"))?;
+			stream.set_nodelay(true)?;
+			Ok(Box::new(stream))
+		},
+		#[cfg(unix)]
+		Address::Unix(Some(ref path)) => {
+			Ok(Box::new(UnixStream::connect(path).map_err(|e| {
+				anyhow!("Unable to connect to unix socket {}: {e}", path.display())
+			})?))
+		},
+		_ => Err(anyhow!("Unable to connect to {address}")),
+	}
+}
+
+// a datagram socket sending to a single server, a unix socket is bound to a temporary path so the server can reply
+#[derive(Debug)]
+pub(crate) enum DatagramClient {
+	Udp(UdpSocket),
+	#[cfg(unix)]
+	Unix(UnixDatagram, #[allow(dead_code)] SocketFile),
+}
+
+impl DatagramClient {
+	pub(crate) fn connect(address: &Address) -> Result<Self, Error> {
+		let client = match *address {
+			Address::Ip(addr) => {
+				let local = if addr.is_ipv4() {
+					SocketAddr::from(([0, 0, 0, 0], 0))
+				}
+				else {
+					SocketAddr::from(([0; 8], 0))
+				};
+				let socket = UdpSocket::bind(local)?;
+				socket.connect(addr)?;
+				Self::Udp(socket)
+			},
+			#[cfg(unix)]
+			Address::Unix(Some(ref path)) => {
+				let (socket, file) = unix::bind_client_datagram()?;
+				socket
+					.connect(path)
+					.map_err(|e| anyhow!("Unable to connect to unix socket {}: {e}", path.display()))?;
+				Self::Unix(socket, file)
+			},
+			_ => return Err(anyhow!("Unable to connect to {address}")),
+		};
+		client.set_read_timeout(DATAGRAM_TIMEOUT)?;
+		Ok(client)
+	}
+
+	fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+		match *self {
+			Self::Udp(ref socket) => socket.set_read_timeout(Some(timeout)),
+			#[cfg(unix)]
+			Self::Unix(ref socket, _) => socket.set_read_timeout(Some(timeout)),
+		}
+	}
+
+	pub(crate) fn send(&self, data: &[u8]) -> io::Result<usize> {
+		match *self {
+			Self::Udp(ref socket) => socket.send(data),
+			#[cfg(unix)]
+			Self::Unix(ref socket, _) => socket.send(data),
+		}
+	}
+
+	// the next datagram from the server, none when nothing arrives within the timeout
+	pub(crate) fn receive(&self, buffer: &mut [u8]) -> io::Result<Option<usize>> {
+		let result = match *self {
+			Self::Udp(ref socket) => socket.recv(buffer),
+			#[cfg(unix)]
+			Self::Unix(ref socket, _) => socket.recv(buffer),
+		};
+		match result {
+			Ok(size) => Ok(Some(size)),
+			Err(ref err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+			Err(err) => Err(err),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn report(latencies: &[u64]) -> LoadReport {
+		LoadReport {
+			connections: 1,
+			elapsed: Duration::from_secs(2),
+			latencies: latencies.iter().copied().map(Duration::from_millis).collect(),
+			errors: 0,
+		}
+	}
+
+	#[test]
+	fn percentiles() {
+		let report = report(&(1..=100).collect::<Vec<_>>());
+		assert_eq!(report.percentile(50), Duration::from_millis(50));
+		assert_eq!(report.percentile(99), Duration::from_millis(99));
+		assert_eq!(report.percentile(100), Duration::from_millis(100));
+	}
+
+	#[test]
+	fn report_without_responses() {
+		assert_eq!(
+			report(&[]).to_string(),
+			concat!(
+				"0 responses over 1 connections in 2.00s, 0.0 responses a second, 0 errors\n",
+				"Latency: p50 0.000ms, p90 0.000ms, p99 0.000ms, max 0.000ms\n"
+			)
+		);
+	}
+}
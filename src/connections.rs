@@ -0,0 +1,250 @@
+use std::{
+	collections::HashMap,
+	net::{IpAddr, Shutdown},
+	sync::Arc,
+	thread,
+	time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use crossbeam::channel::RecvTimeoutError;
+use parking_lot::{Condvar, Mutex};
+use socket2::{SockRef, Socket};
+
+use crate::{
+	context::ConnectionContext,
+	limits,
+	logger::info,
+	shutdown::ShutdownSignal,
+	timeouts::{self, Timeouts},
+};
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) struct DrainReport {
+	pub(crate) drained: usize,
+	pub(crate) killed: usize,
+}
+
+#[derive(Debug)]
+struct Connection {
+	socket: Socket,
+	context: ConnectionContext,
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+	open: HashMap<u64, Connection>,
+	// open connections by the source address of the client
+	sources: HashMap<IpAddr, usize>,
+	// connections closed after shutdown was requested, handlers may stop on their own before the drain starts
+	drained: usize,
+}
+
+#[derive(Debug)]
+pub(crate) struct ConnectionTracker {
+	connections: Mutex<Connections>,
+	closed: Condvar,
+	shutdown: Arc<ShutdownSignal>,
+	timeouts: Timeouts,
+}
+
+impl ConnectionTracker {
+	pub(crate) fn new(shutdown: &Arc<ShutdownSignal>, timeouts: Timeouts) -> Arc<Self> {
+		Arc::new(Self {
+			connections: Mutex::new(Connections::default()),
+			closed: Condvar::new(),
+			shutdown: Arc::clone(shutdown),
+			timeouts,
+		})
+	}
+
+	// keeps a duplicate of the connection socket, so the connection can be closed while a handler owns the stream
+	pub(crate) fn track<S>(
+		self: &Arc<Self>,
+		context: &ConnectionContext,
+		stream: &S,
+	) -> Result<ConnectionGuard, Error>
+	where
+		for<'s> SockRef<'s>: From<&'s S>,
+	{
+		let socket = SockRef::from(stream).try_clone()?;
+		let source = limits::source_ip(context.peer_addr());
+		let mut connections = self.connections.lock();
+		let _previous = connections.open.insert(context.id(), Connection {
+			socket,
+			context: context.clone(),
+		});
+		if let Some(source) = source {
+			*connections.sources.entry(source).or_default() += 1;
+		}
+		Ok(ConnectionGuard {
+			id: context.id(),
+			source,
+			tracker: Arc::clone(self),
+		})
+	}
+
+	// closes the connections past one of their timeouts in both directions, so a handler waiting on the connection
+	// sees end of stream, or an error from a blocked write, and finishes, a connection is only closed once
+	pub(crate) fn expire(&self) {
+		let connections = self.connections.lock();
+		for connection in connections.open.values() {
+			if connection.context.is_timed_out() {
+				continue;
+			}
+			if let Some(timeout) = connection.context.timed_out(self.timeouts) {
+				info!(connection: connection.context.id(), "Connection timed out: {timeout}");
+				connection.context.metrics().timed_out(timeout);
+				connection.context.set_timed_out();
+				let _result = connection.socket.shutdown(Shutdown::Both);
+			}
+		}
+	}
+
+	// expires connections on a thread of its own, so they time out while the accept loop is blocked, e.g. waiting for
+	// room in a full job queue, the thread stops on shutdown
+	pub(crate) fn expire_on_interval(self: &Arc<Self>) -> Result<(), Error> {
+		let tracker = Arc::clone(self);
+		let stop = self.shutdown.receiver();
+		let _handle = thread::Builder::new().name(String::from("timeouts")).spawn(move || {
+			while stop.recv_timeout(timeouts::CHECK_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+				tracker.expire();
+			}
+		})?;
+		Ok(())
+	}
+
+	pub(crate) fn open_from(&self, source: IpAddr) -> usize {
+		self.connections.lock().sources.get(&source).copied().unwrap_or(0)
+	}
+
+	// closes the read side of every connection, so handlers see end of stream and finish, any connection still open
+	// after the timeout is closed in both directions
+	pub(crate) fn drain(&self, timeout: Duration) -> DrainReport {
+		let deadline = Instant::now() + timeout;
+		let mut connections = self.connections.lock();
+
+		for connection in connections.open.values() {
+			let _result = connection.socket.shutdown(Shutdown::Read);
+		}
+
+		while !connections.open.is_empty() {
+			if self.closed.wait_until(&mut connections, deadline).timed_out() {
+				break;
+			}
+		}
+
+		for connection in connections.open.values() {
+			let _result = connection.socket.shutdown(Shutdown::Both);
+		}
+
+		DrainReport {
+			drained: connections.drained,
+			killed: connections.open.len(),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard {
+	id: u64,
+	source: Option<IpAddr>,
+	tracker: Arc<ConnectionTracker>,
+}
+
+impl Drop for ConnectionGuard {
+	fn drop(&mut self) {
+		let mut connections = self.tracker.connections.lock();
+		if connections.open.remove(&self.id).is_some() && self.tracker.shutdown.is_requested() {
+			connections.drained += 1;
+		}
+		if let Some(source) = self.source {
+			if let Some(open) = connections.sources.get_mut(&source) {
+				*open -= 1;
+				if *open == 0 {
+					let _open = connections.sources.remove(&source);
+				}
+			}
+		}
+		let _notified = self.tracker.closed.notify_all();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		io::Read,
+		iter,
+		net::{Ipv4Addr, TcpListener, TcpStream},
+		num::NonZeroUsize,
+	};
+
+	use crossbeam::channel::never;
+
+	use super::*;
+	use crate::{
+		stream::testing::context,
+		thread_pool::{PoolSize, ThreadPool},
+	};
+
+	#[test]
+	fn closed_connections_are_no_longer_open_from_their_source() {
+		let tracker = ConnectionTracker::new(&ShutdownSignal::new(), Timeouts::default());
+		let socket = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+		let source = IpAddr::from(Ipv4Addr::LOCALHOST);
+
+		let first = tracker.track(&context(), &socket).unwrap();
+		let second = tracker.track(&context(), &socket).unwrap();
+		assert_eq!(tracker.open_from(source), 2);
+		drop(first);
+		assert_eq!(tracker.open_from(source), 1);
+		drop(second);
+		assert_eq!(tracker.open_from(source), 0);
+		assert!(tracker.connections.lock().sources.is_empty());
+	}
+
+	#[test]
+	fn idle_connections_of_a_full_pool_expire() {
+		let shutdown = ShutdownSignal::new();
+		let tracker = ConnectionTracker::new(&shutdown, Timeouts {
+			idle: Some(Duration::from_millis(100)),
+			..Timeouts::default()
+		});
+		tracker.expire_on_interval().unwrap();
+		let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+		let clients = iter::repeat_with(|| TcpStream::connect(listener.local_addr().unwrap()).unwrap())
+			.take(3)
+			.collect::<Vec<_>>();
+
+		// like the accept loop, queueing the third connection blocks while the first holds the only worker and the
+		// second the only queue slot, so only expiring the first lets it through
+		let accept_tracker = Arc::clone(&tracker);
+		let accepted = thread::spawn(move || {
+			let size = PoolSize {
+				min_workers: 1,
+				max_workers: NonZeroUsize::MIN,
+				idle_timeout: Duration::from_secs(1),
+				client_workers: None,
+			};
+			let pool = ThreadPool::new("test", size, Some(NonZeroUsize::MIN), never());
+			for _ in &clients {
+				let (mut stream, _) = listener.accept().unwrap();
+				let guard = accept_tracker.track(&context(), &stream).unwrap();
+				let _handle = pool
+					.execute(move || {
+						let _guard = guard;
+						stream.read(&mut [0; 1])
+					})
+					.unwrap();
+			}
+			clients
+		});
+
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while !accepted.is_finished() || !tracker.connections.lock().open.is_empty() {
+			assert!(Instant::now() < deadline, "idle connections did not expire");
+			thread::sleep(Duration::from_millis(10));
+		}
+		shutdown.request();
+	}
+}
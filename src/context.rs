@@ -0,0 +1,242 @@
+use std::{
+	io::{self, Read, Write},
+	pin::Pin,
+	sync::{
+		atomic::{AtomicBool, AtomicU64, Ordering},
+		Arc,
+	},
+	task::{Context, Poll},
+	time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+	address::Address,
+	metrics::ServerMetrics,
+	shutdown::ShutdownSignal,
+	timeouts::{Timeout, Timeouts},
+};
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// bytes are counted for the connection and added to the totals of its server, along with the activity the
+// connection timeouts are checked against
+#[derive(Debug)]
+struct ByteCounters {
+	read: AtomicU64,
+	written: AtomicU64,
+	server: Arc<ServerMetrics>,
+	connected_at: Instant,
+	// milliseconds after connecting, of the last read, and of the start of a blocked write plus one, zero for none
+	last_read: AtomicU64,
+	write_started: AtomicU64,
+	timed_out: AtomicBool,
+}
+
+impl ByteCounters {
+	fn now(&self) -> u64 {
+		u64::try_from(self.connected_at.elapsed().as_millis()).unwrap_or(u64::MAX)
+	}
+
+	fn add_read(&self, size: usize) {
+		let _previous = self.read.fetch_add(size as u64, Ordering::Relaxed);
+		self.last_read.store(self.now(), Ordering::Relaxed);
+		self.server.add_read(size);
+	}
+
+	// marks a write as blocked from now, unless it already is
+	fn write_blocked(&self) {
+		let _previous = self
+			.write_started
+			.compare_exchange(0, self.now() + 1, Ordering::Relaxed, Ordering::Relaxed);
+	}
+
+	fn write_done(&self) {
+		self.write_started.store(0, Ordering::Relaxed);
+	}
+
+	fn add_written(&self, size: usize) {
+		let _previous = self.written.fetch_add(size as u64, Ordering::Relaxed);
+		self.server.add_written(size);
+	}
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionContext {
+	id: u64,
+	peer_addr: Address,
+	local_addr: Address,
+	shutdown: Arc<ShutdownSignal>,
+	counters: Arc<ByteCounters>,
+}
+
+impl ConnectionContext {
+	pub(crate) fn new(
+		peer_addr: Address,
+		local_addr: Address,
+		shutdown: &Arc<ShutdownSignal>,
+		metrics: &Arc<ServerMetrics>,
+	) -> Self {
+		Self {
+			id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+			peer_addr,
+			local_addr,
+			shutdown: Arc::clone(shutdown),
+			counters: Arc::new(ByteCounters {
+				read: AtomicU64::new(0),
+				written: AtomicU64::new(0),
+				server: Arc::clone(metrics),
+				connected_at: Instant::now(),
+				last_read: AtomicU64::new(0),
+				write_started: AtomicU64::new(0),
+				timed_out: AtomicBool::new(false),
+			}),
+		}
+	}
+
+	pub(crate) const fn id(&self) -> u64 {
+		self.id
+	}
+
+	pub(crate) const fn peer_addr(&self) -> &Address {
+		&self.peer_addr
+	}
+
+	pub(crate) const fn local_addr(&self) -> &Address {
+		&self.local_addr
+	}
+
+	pub(crate) fn elapsed(&self) -> Duration {
+		self.counters.connected_at.elapsed()
+	}
+
+	pub(crate) fn is_cancelled(&self) -> bool {
+		self.shutdown.is_requested()
+	}
+
+	pub(crate) async fn cancelled(&self) {
+		self.shutdown.requested().await;
+	}
+
+	pub(crate) fn bytes_read(&self) -> u64 {
+		self.counters.read.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn bytes_written(&self) -> u64 {
+		self.counters.written.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn metrics(&self) -> &ServerMetrics {
+		&self.counters.server
+	}
+
+	// the first timeout the connection is past, the idle time counts from the last read, or from connecting
+	pub(crate) fn timed_out(&self, timeouts: Timeouts) -> Option<Timeout> {
+		let elapsed = self.elapsed();
+		let since = |millis: u64| elapsed.saturating_sub(Duration::from_millis(millis));
+		if let Some(session) = timeouts.session.filter(|&session| elapsed >= session) {
+			return Some(Timeout::Session(session));
+		}
+		let write_started = self.counters.write_started.load(Ordering::Relaxed);
+		if let Some(write) = timeouts
+			.write
+			.filter(|&write| write_started != 0 && since(write_started - 1) >= write)
+		{
+			return Some(Timeout::Write(write));
+		}
+		timeouts
+			.idle
+			.filter(|&idle| since(self.counters.last_read.load(Ordering::Relaxed)) >= idle)
+			.map(Timeout::Idle)
+	}
+
+	// the connection is closed once it times out, so errors the handler sees after are expected
+	pub(crate) fn set_timed_out(&self) {
+		self.counters.timed_out.store(true, Ordering::Relaxed);
+	}
+
+	pub(crate) fn is_timed_out(&self) -> bool {
+		self.counters.timed_out.load(Ordering::Relaxed)
+	}
+
+	// wraps a stream, or a half or clone of one, so reads and writes are added to the connection byte counters
+	pub(crate) fn counted<S>(&self, stream: S) -> CountedStream<S> {
+		CountedStream {
+			inner: stream,
+			counters: Arc::clone(&self.counters),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub(crate) struct CountedStream<S> {
+	inner: S,
+	counters: Arc<ByteCounters>,
+}
+
+impl<S> CountedStream<S> {
+	pub(crate) const fn get_ref(&self) -> &S {
+		&self.inner
+	}
+}
+
+impl<S: Read> Read for CountedStream<S> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let size = self.inner.read(buf)?;
+		self.counters.add_read(size);
+		Ok(size)
+	}
+}
+
+impl<S: Write> Write for CountedStream<S> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		// a blocking write is marked blocked until it returns, as there is no way to tell it would block
+		self.counters.write_blocked();
+		let result = self.inner.write(buf);
+		self.counters.write_done();
+		let size = result?;
+		self.counters.add_written(size);
+		Ok(size)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountedStream<S> {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		let filled = buf.filled().len();
+		let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+		if let Poll::Ready(Ok(())) = result {
+			this.counters.add_read(buf.filled().len() - filled);
+		}
+		result
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountedStream<S> {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+		match result {
+			Poll::Ready(Ok(size)) => {
+				this.counters.write_done();
+				this.counters.add_written(size);
+			},
+			Poll::Ready(Err(_)) => this.counters.write_done(),
+			Poll::Pending => this.counters.write_blocked(),
+		}
+		result
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+	}
+}
@@ -0,0 +1,208 @@
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::{
+	io::{self, ErrorKind},
+	net::UdpSocket,
+	sync::Arc,
+	task::{Context, Poll},
+};
+
+#[cfg(not(unix))]
+use anyhow::anyhow;
+use anyhow::Error;
+use mio::{event::Source, net::UdpSocket as PollUdpSocket};
+#[cfg(unix)]
+use tokio::net::UnixDatagram as AsyncUnixDatagram;
+use tokio::{io::ReadBuf, net::UdpSocket as AsyncUdpSocket};
+
+#[cfg(unix)]
+use crate::unix::{self, SocketFile};
+use crate::{
+	address::Address,
+	capture::Capture,
+	socket::{self, Bind},
+};
+
+fn unreachable(peer: &Address) -> io::Error {
+	let message = if *peer == Address::Unix(None) {
+		String::from("Unable to reply to an unnamed unix socket client, the client must bind a path to get replies")
+	}
+	else {
+		format!("Unable to send a datagram to {peer}")
+	};
+	io::Error::new(ErrorKind::InvalidInput, message)
+}
+
+// a unix socket keeps its file until the socket is dropped, replies to a unix socket client need the client to have
+// bound a path
+#[derive(Debug)]
+enum Socket {
+	Udp(UdpSocket),
+	#[cfg(unix)]
+	Unix(UnixDatagram, SocketFile),
+}
+
+// the socket of a datagram problem, the datagrams sent from it are captured along with those the server receives
+#[derive(Debug)]
+pub(crate) struct Datagram {
+	socket: Socket,
+	capture: Option<Arc<Capture>>,
+}
+
+impl Datagram {
+	pub(crate) fn bind(bind: &Bind, capture: Option<&Arc<Capture>>) -> Result<Vec<Self>, Error> {
+		let sockets = match *bind {
+			Bind::Ip(ref addresses) => socket::bind_udp(addresses)?.into_iter().map(Socket::Udp).collect(),
+			#[cfg(unix)]
+			Bind::Unix(ref path) => {
+				let (socket, file) = unix::bind_datagram(path)?;
+				vec![Socket::Unix(socket, file)]
+			},
+			#[cfg(not(unix))]
+			Bind::Unix(_) => return Err(anyhow!("Unix sockets are not supported on this platform")),
+		};
+		Ok(sockets
+			.into_iter()
+			.map(|socket| {
+				Self {
+					socket,
+					capture: capture.cloned(),
+				}
+			})
+			.collect())
+	}
+
+	pub(crate) const fn transport(&self) -> &'static str {
+		match self.socket {
+			Socket::Udp(_) => "UDP",
+			#[cfg(unix)]
+			Socket::Unix(..) => "unix socket",
+		}
+	}
+
+	pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+		match self.socket {
+			Socket::Udp(ref socket) => socket.set_nonblocking(true),
+			#[cfg(unix)]
+			Socket::Unix(ref socket, _) => socket.set_nonblocking(true),
+		}
+	}
+
+	pub(crate) fn local_addr(&self) -> io::Result<Address> {
+		match self.socket {
+			Socket::Udp(ref socket) => socket.local_addr().map(Address::from),
+			#[cfg(unix)]
+			Socket::Unix(ref socket, _) => socket.local_addr().map(Address::from),
+		}
+	}
+
+	// a duplicate of the socket to register with an event poll
+	pub(crate) fn poll_source(&self) -> io::Result<Box<dyn Source>> {
+		Ok(match self.socket {
+			Socket::Udp(ref socket) => Box::new(PollUdpSocket::from_std(socket.try_clone()?)),
+			#[cfg(unix)]
+			Socket::Unix(ref socket, _) => Box::new(mio::net::UnixDatagram::from_std(socket.try_clone()?)),
+		})
+	}
+
+	pub(crate) fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, Address)> {
+		match self.socket {
+			Socket::Udp(ref socket) => socket.recv_from(buffer).map(|(size, addr)| (size, Address::from(addr))),
+			#[cfg(unix)]
+			Socket::Unix(ref socket, _) => socket.recv_from(buffer).map(|(size, addr)| (size, Address::from(addr))),
+		}
+	}
+
+	pub(crate) fn send_to(&self, data: &[u8], peer: &Address) -> io::Result<usize> {
+		let size = match (&self.socket, peer) {
+			(Socket::Udp(socket), &Address::Ip(addr)) => socket.send_to(data, addr)?,
+			#[cfg(unix)]
+			(Socket::Unix(socket, _), Address::Unix(Some(path))) => socket.send_to(data, path)?,
+			_ => return Err(unreachable(peer)),
+		};
+		if let Some(ref capture) = self.capture {
+			capture.sent(peer, &data[..size]);
+		}
+		Ok(size)
+	}
+
+	pub(crate) fn into_async(self) -> io::Result<AsyncDatagram> {
+		self.set_nonblocking()?;
+		let socket = match self.socket {
+			Socket::Udp(socket) => AsyncSocket::Udp(AsyncUdpSocket::from_std(socket)?),
+			#[cfg(unix)]
+			Socket::Unix(socket, file) => AsyncSocket::Unix(AsyncUnixDatagram::from_std(socket)?, file),
+		};
+		Ok(AsyncDatagram {
+			socket,
+			capture: self.capture,
+		})
+	}
+}
+
+#[derive(Debug)]
+enum AsyncSocket {
+	Udp(AsyncUdpSocket),
+	#[cfg(unix)]
+	Unix(AsyncUnixDatagram, #[allow(dead_code)] SocketFile),
+}
+
+#[derive(Debug)]
+pub(crate) struct AsyncDatagram {
+	socket: AsyncSocket,
+	capture: Option<Arc<Capture>>,
+}
+
+impl AsyncDatagram {
+	pub(crate) const fn transport(&self) -> &'static str {
+		match self.socket {
+			AsyncSocket::Udp(_) => "UDP",
+			#[cfg(unix)]
+			AsyncSocket::Unix(..) => "unix socket",
+		}
+	}
+
+	pub(crate) fn local_addr(&self) -> io::Result<Address> {
+		match self.socket {
+			AsyncSocket::Udp(ref socket) => socket.local_addr().map(Address::from),
+			#[cfg(unix)]
+			AsyncSocket::Unix(ref socket, _) => socket.local_addr().map(Address::from),
+		}
+	}
+
+	fn poll_recv_from(&self, cx: &mut Context<'_>, buffer: &mut ReadBuf<'_>) -> Poll<io::Result<Address>> {
+		match self.socket {
+			AsyncSocket::Udp(ref socket) => socket.poll_recv_from(cx, buffer).map_ok(Address::from),
+			#[cfg(unix)]
+			AsyncSocket::Unix(ref socket, _) => socket.poll_recv_from(cx, buffer).map_ok(Address::from),
+		}
+	}
+
+	pub(crate) async fn send_to(&self, data: &[u8], peer: &Address) -> io::Result<usize> {
+		let size = match (&self.socket, peer) {
+			(AsyncSocket::Udp(socket), &Address::Ip(addr)) => socket.send_to(data, addr).await?,
+			#[cfg(unix)]
+			(AsyncSocket::Unix(socket, _), Address::Unix(Some(path))) => socket.send_to(data, path).await?,
+			_ => return Err(unreachable(peer)),
+		};
+		if let Some(ref capture) = self.capture {
+			capture.sent(peer, &data[..size]);
+		}
+		Ok(size)
+	}
+}
+
+pub(crate) fn poll_recv_from_any(
+	sockets: &[AsyncDatagram],
+	cx: &mut Context<'_>,
+	buffer: &mut [u8],
+) -> Poll<io::Result<(usize, usize, Address)>> {
+	for (index, socket) in sockets.iter().enumerate() {
+		let mut read_buffer = ReadBuf::new(buffer);
+		if let Poll::Ready(result) = socket.poll_recv_from(cx, &mut read_buffer) {
+			let size = read_buffer.filled().len();
+			return Poll::Ready(result.map(|addr| (index, size, addr)));
+		}
+	}
+	Poll::Pending
+}
@@ -1,15 +1,94 @@
-use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::{
+	any::Any,
+	error,
+	fmt::{self, Display, Formatter},
+	future::Future,
+	panic::{catch_unwind, AssertUnwindSafe},
+	pin::Pin,
+	task::{Context, Poll},
+};
 
 use anyhow::Error;
 
+use crate::{
+	address::Address,
+	context::ConnectionContext,
+	datagram::{AsyncDatagram, Datagram},
+	stream::{AsyncStream, Stream},
+	utils::panic_message,
+};
+
+// the error for a handler that panicked, so the panic can be reported like any other handler error
+#[derive(Debug)]
+pub(crate) struct HandlerPanic(pub(crate) String);
+
+impl Display for HandlerPanic {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "Handler panicked: {}", self.0)
+	}
+}
+
+impl error::Error for HandlerPanic {}
+
+impl HandlerPanic {
+	pub(crate) fn error(payload: &(dyn Any + Send)) -> Error {
+		Error::new(Self(String::from(panic_message(payload))))
+	}
+}
+
+pub(crate) type HandlerFuture<'handler> = Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'handler>>;
+
+// a handler future that resolves to a HandlerPanic when polling it panics, as catch_unwind does for the sync handlers
+pub(crate) struct CatchUnwind<'handler>(pub(crate) HandlerFuture<'handler>);
+
+impl Future for CatchUnwind<'_> {
+	type Output = Result<(), Error>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let future = &mut self.0;
+		catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx)))
+			.unwrap_or_else(|payload| Poll::Ready(Err(HandlerPanic::error(payload.as_ref()))))
+	}
+}
+
 pub(crate) trait TcpHandler: Send + Sync {
-	fn handler(&self, stream: TcpStream, _id: u32) -> Result<(), Error>;
+	fn handler(&self, stream: Box<dyn Stream>, context: ConnectionContext) -> Result<(), Error>;
+
+	// sent to connections rejected while the server is busy, before they are closed
+	fn busy_response(&self) -> Option<&'static [u8]> {
+		None
+	}
 
 	fn shutdown(&self) {}
 }
 
 pub(crate) trait UdpHandler: Send + Sync {
-	fn handler(&self, data: &[u8], socket: &mut UdpSocket, addr: SocketAddr) -> Result<(), Error>;
+	fn max_datagram_size(&self) -> usize {
+		1024
+	}
+
+	fn handler(&self, data: &[u8], socket: &Datagram, addr: &Address) -> Result<(), Error>;
+
+	fn shutdown(&self) {}
+}
+
+pub(crate) trait AsyncTcpHandler: Send + Sync {
+	fn handler(&self, stream: Box<dyn AsyncStream>, context: ConnectionContext) -> HandlerFuture<'_>;
+
+	fn shutdown(&self) {}
+}
+
+pub(crate) trait AsyncUdpHandler: Send + Sync {
+	fn max_datagram_size(&self) -> usize {
+		1024
+	}
+
+	fn handler<'handler>(
+		&'handler self,
+		data: &'handler [u8],
+		socket: &'handler AsyncDatagram,
+		addr: &'handler Address,
+	) -> HandlerFuture<'handler>;
 
 	fn shutdown(&self) {}
 }
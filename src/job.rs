@@ -1 +1,214 @@
-pub(crate) type Job = Box<dyn FnOnce() + Send + 'static>;
+use std::{
+	fmt::{self, Debug, Formatter},
+	future::Future,
+	mem,
+	net::IpAddr,
+	panic::{catch_unwind, AssertUnwindSafe},
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll, Waker},
+};
+
+use anyhow::{anyhow, Error};
+use parking_lot::{Condvar, Mutex};
+
+use crate::{address::Address, logger::error, utils::panic_message};
+
+// what a job is working on, shown in the worker status table
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct JobLabel {
+	pub(crate) connection: Option<u64>,
+	pub(crate) problem: Option<String>,
+	pub(crate) peer: Option<Address>,
+}
+
+impl JobLabel {
+	pub(crate) fn client(&self) -> Option<IpAddr> {
+		self.peer.as_ref().and_then(Address::ip)
+	}
+}
+
+pub(crate) struct Job {
+	label: JobLabel,
+	run: Box<dyn FnOnce() + Send + 'static>,
+}
+
+impl Debug for Job {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Job")
+			.field("label", &self.label)
+			.finish_non_exhaustive()
+	}
+}
+
+impl Job {
+	pub(crate) const fn label(&self) -> &JobLabel {
+		&self.label
+	}
+
+	pub(crate) fn run(self) {
+		(self.run)();
+	}
+}
+
+#[derive(Debug)]
+enum JobState<T> {
+	Queued,
+	Running,
+	Finished(Result<T, String>),
+	Cancelled,
+	// the result was already returned by a poll of the handle
+	Taken,
+}
+
+impl<T> JobState<T> {
+	const fn is_done(&self) -> bool {
+		!matches!(*self, Self::Queued | Self::Running)
+	}
+
+	fn take_result(&mut self) -> Result<T, Error> {
+		match mem::replace(self, Self::Taken) {
+			Self::Finished(result) => result.map_err(|message| anyhow!("Job panicked: {message}")),
+			Self::Cancelled => Err(anyhow!("Job was cancelled before it started")),
+			Self::Queued | Self::Running | Self::Taken => Err(anyhow!("Job result was already taken")),
+		}
+	}
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+	state: Mutex<(JobState<T>, Option<Waker>)>,
+	done: Condvar,
+}
+
+impl<T> Shared<T> {
+	fn start(&self) -> bool {
+		let mut state = self.state.lock();
+		if state.0.is_done() {
+			return false;
+		}
+		state.0 = JobState::Running;
+		true
+	}
+
+	fn complete(&self, result: JobState<T>) {
+		let mut state = self.state.lock();
+		state.0 = result;
+		self.wake(&mut state);
+	}
+
+	// checked and cancelled under one lock, so a worker cannot start the job in between
+	fn cancel(&self) -> bool {
+		let mut state = self.state.lock();
+		if !matches!(state.0, JobState::Queued) {
+			return false;
+		}
+		state.0 = JobState::Cancelled;
+		self.wake(&mut state);
+		true
+	}
+
+	fn wake(&self, state: &mut (JobState<T>, Option<Waker>)) {
+		if let Some(waker) = state.1.take() {
+			waker.wake();
+		}
+		let _woken = self.done.notify_all();
+	}
+}
+
+// a handle to a queued job, that can be joined from a thread or awaited from a task
+#[derive(Debug)]
+pub(crate) struct JobHandle<T> {
+	shared: Arc<Shared<T>>,
+}
+
+impl<T> JobHandle<T> {
+	// stops the job from running if a worker has not started it yet, returning whether it was cancelled
+	pub(crate) fn cancel(&self) -> bool {
+		self.shared.cancel()
+	}
+
+	// waits for the job to finish, returning its result, or an error when it panicked or was cancelled
+	pub(crate) fn join(self) -> Result<T, Error> {
+		let mut state = self.shared.state.lock();
+		while !state.0.is_done() {
+			self.shared.done.wait(&mut state);
+		}
+		state.0.take_result()
+	}
+}
+
+impl<T> Future for JobHandle<T> {
+	type Output = Result<T, Error>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let mut state = self.shared.state.lock();
+		if state.0.is_done() {
+			return Poll::Ready(state.0.take_result());
+		}
+		state.1 = Some(cx.waker().clone());
+		Poll::Pending
+	}
+}
+
+// wraps a function into a job for the pool queue, with a handle to its result
+pub(crate) fn with_handle<T, F>(label: JobLabel, f: F) -> (Job, JobHandle<T>)
+where
+	T: Send + 'static,
+	F: FnOnce() -> T + Send + 'static,
+{
+	let shared = Arc::new(Shared {
+		state: Mutex::new((JobState::Queued, None)),
+		done: Condvar::new(),
+	});
+	let job_shared = Arc::clone(&shared);
+	let run = Box::new(move || {
+		if !job_shared.start() {
+			return;
+		}
+		let result = catch_unwind(AssertUnwindSafe(f)).map_err(|payload| String::from(panic_message(payload.as_ref())));
+		// nobody can join a job once its handle is dropped, so the panic is reported here instead
+		if let Err(ref message) = result {
+			if Arc::strong_count(&job_shared) == 1 {
+				error!("Job panicked: {message}");
+			}
+		}
+		job_shared.complete(JobState::Finished(result));
+	});
+	(Job { label, run }, JobHandle { shared })
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{sync::mpsc::channel, thread};
+
+	use super::*;
+
+	#[test]
+	fn cancel_queued_job() {
+		let (job, handle) = with_handle(JobLabel::default(), || 1);
+		assert!(handle.cancel());
+		job.run();
+		assert_eq!(
+			handle.join().unwrap_err().to_string(),
+			"Job was cancelled before it started"
+		);
+	}
+
+	#[test]
+	fn cancel_started_job() {
+		let (started_sender, started) = channel();
+		let (release, release_receiver) = channel::<()>();
+		let (job, handle) = with_handle(JobLabel::default(), move || {
+			started_sender.send(()).unwrap();
+			release_receiver.recv().unwrap();
+			1
+		});
+		let worker = thread::spawn(move || job.run());
+		started.recv().unwrap();
+		assert!(!handle.cancel());
+		release.send(()).unwrap();
+		assert_eq!(handle.join().unwrap(), 1);
+		worker.join().unwrap();
+	}
+}
@@ -0,0 +1,252 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	net::IpAddr,
+	num::NonZeroUsize,
+	time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+use crossbeam::{
+	channel::{bounded, Receiver, Sender},
+	select,
+};
+use parking_lot::{Condvar, Mutex};
+
+use crate::job::Job;
+
+// what a worker gets from the queue
+pub(crate) enum Next {
+	Job(Job),
+	Timeout,
+	Closed,
+}
+
+#[derive(Debug, Default)]
+struct Client {
+	jobs: VecDeque<Job>,
+	running: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+	// jobs are grouped by the IP address of the connection they serve, jobs without a connection share a group
+	clients: HashMap<Option<IpAddr>, Client>,
+	// the clients with queued jobs, in the order they get their next turn
+	turns: VecDeque<Option<IpAddr>>,
+	closed: bool,
+}
+
+impl State {
+	fn running(&self, key: Option<IpAddr>) -> usize {
+		self.clients.get(&key).map_or(0, |client| client.running)
+	}
+
+	// takes the first job of the client with the fewest running jobs that is below the worker limit, clients with
+	// the same number of running jobs take turns
+	fn take(&mut self, client_workers: Option<NonZeroUsize>) -> Option<Job> {
+		let (turn, _) = self
+			.turns
+			.iter()
+			.map(|&key| self.running(key))
+			.enumerate()
+			.filter(|&(_, running)| client_workers.map_or(true, |limit| running < limit.get()))
+			.min_by_key(|&(_, running)| running)?;
+		let key = self.turns.remove(turn)?;
+		let client = self.clients.get_mut(&key)?;
+		let job = client.jobs.pop_front()?;
+		client.running += 1;
+		if !client.jobs.is_empty() {
+			self.turns.push_back(key);
+		}
+		Some(job)
+	}
+
+	fn is_empty(&self) -> bool {
+		self.turns.is_empty()
+	}
+}
+
+// jobs waiting for a worker, taken in turn from each client, so a client with many connections cannot delay the
+// connections of the others, and optionally cannot take more than a number of workers
+#[derive(Debug)]
+pub(crate) struct JobQueue {
+	state: Mutex<State>,
+	available: Condvar,
+	client_workers: Option<NonZeroUsize>,
+	// a slot is held for every queued job, so a full channel means a full queue
+	slots: Option<(Sender<()>, Receiver<()>)>,
+}
+
+impl JobQueue {
+	// a capacity of None leaves the queue unbounded
+	pub(crate) fn new(capacity: Option<NonZeroUsize>, client_workers: Option<NonZeroUsize>) -> Self {
+		Self {
+			state: Mutex::new(State::default()),
+			available: Condvar::new(),
+			client_workers,
+			slots: capacity.map(|capacity| bounded(capacity.get())),
+		}
+	}
+
+	// queues the job, waiting for room in the queue while it is full, unless the stop receiver disconnects
+	pub(crate) fn push(&self, job: Job, stop: &Receiver<()>) -> Result<(), Error> {
+		if let Some((ref sender, _)) = self.slots {
+			select! {
+				send(sender, ()) -> _ => {},
+				recv(stop) -> _ => return Err(anyhow!("Shutdown requested while waiting for a worker")),
+			}
+		}
+
+		let mut state = self.state.lock();
+		let key = job.label().client();
+		let client = state.clients.entry(key).or_default();
+		client.jobs.push_back(job);
+		if client.jobs.len() == 1 {
+			state.turns.push_back(key);
+		}
+		let _woken = self.available.notify_one();
+		Ok(())
+	}
+
+	// waits up to the timeout for a job that can run, jobs still queued when the queue is closed are run first
+	pub(crate) fn pop(&self, timeout: Duration) -> Next {
+		let deadline = Instant::now() + timeout;
+		let mut state = self.state.lock();
+		loop {
+			if let Some(job) = state.take(self.client_workers) {
+				if let Some((_, ref receiver)) = self.slots {
+					let _slot = receiver.try_recv();
+				}
+				return Next::Job(job);
+			}
+			if state.closed && state.is_empty() {
+				return Next::Closed;
+			}
+			if self.available.wait_until(&mut state, deadline).timed_out() {
+				return Next::Timeout;
+			}
+		}
+	}
+
+	// ends a job taken from the queue, which may let the next job of the client run
+	pub(crate) fn finished(&self, key: Option<IpAddr>) {
+		let mut state = self.state.lock();
+		if let Some(client) = state.clients.get_mut(&key) {
+			client.running -= 1;
+			if client.running == 0 && client.jobs.is_empty() {
+				let _client = state.clients.remove(&key);
+			}
+		}
+		let _woken = self.available.notify_one();
+	}
+
+	// whether more jobs could run now than the workers are running, jobs of a client at the worker limit wait for
+	// its running jobs to finish, so another worker would not help them
+	pub(crate) fn needs_worker(&self, workers: usize) -> bool {
+		let state = self.state.lock();
+		let (running, runnable) = state.clients.values().fold((0, 0), |(running, runnable), client| {
+			let room = self
+				.client_workers
+				.map_or(usize::MAX, |limit| limit.get().saturating_sub(client.running));
+			(running + client.running, runnable + client.jobs.len().min(room))
+		});
+		running + runnable > workers
+	}
+
+	pub(crate) fn is_full(&self) -> bool {
+		self.slots.as_ref().map_or(false, |(sender, _)| sender.is_full())
+	}
+
+	pub(crate) fn close(&self) {
+		self.state.lock().closed = true;
+		let _woken = self.available.notify_all();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+
+	use crossbeam::channel::never;
+
+	use super::*;
+	use crate::{
+		address::Address,
+		job::{self, JobLabel},
+	};
+
+	fn push(queue: &JobQueue, client: u8, connection: u64) {
+		let label = JobLabel {
+			connection: Some(connection),
+			problem: None,
+			peer: Some(Address::from(SocketAddr::new(client_ip(client), 1000))),
+		};
+		let (job, _handle) = job::with_handle(label, || ());
+		queue.push(job, &never()).unwrap();
+	}
+
+	// the connection of the next job, or None when no job can run
+	fn pop(queue: &JobQueue) -> Option<u64> {
+		match queue.pop(Duration::from_millis(10)) {
+			Next::Job(job) => job.label().connection,
+			Next::Timeout | Next::Closed => None,
+		}
+	}
+
+	fn client_ip(client: u8) -> IpAddr {
+		IpAddr::from([10, 0, 0, client])
+	}
+
+	#[test]
+	fn clients_take_turns() {
+		let queue = JobQueue::new(None, None);
+		push(&queue, 1, 1);
+		push(&queue, 1, 2);
+		push(&queue, 1, 3);
+		push(&queue, 2, 4);
+		assert_eq!(pop(&queue), Some(1));
+		assert_eq!(pop(&queue), Some(4));
+		assert_eq!(pop(&queue), Some(2));
+		queue.finished(Some(client_ip(1)));
+		assert_eq!(pop(&queue), Some(3));
+	}
+
+	#[test]
+	fn client_workers_limit() {
+		let queue = JobQueue::new(None, NonZeroUsize::new(1));
+		push(&queue, 1, 1);
+		push(&queue, 1, 2);
+		push(&queue, 2, 3);
+		assert_eq!(pop(&queue), Some(1));
+		assert_eq!(pop(&queue), Some(3));
+		assert!(!queue.needs_worker(2));
+		assert_eq!(pop(&queue), None);
+		queue.finished(Some(client_ip(1)));
+		assert!(queue.needs_worker(1));
+		assert_eq!(pop(&queue), Some(2));
+	}
+
+	#[test]
+	fn pop_times_out_without_jobs() {
+		let queue = JobQueue::new(None, None);
+		assert!(matches!(queue.pop(Duration::from_millis(10)), Next::Timeout));
+	}
+
+	#[test]
+	fn closed_queue_runs_queued_jobs_first() {
+		let queue = JobQueue::new(None, None);
+		push(&queue, 1, 1);
+		queue.close();
+		assert_eq!(pop(&queue), Some(1));
+		assert!(matches!(queue.pop(Duration::from_secs(5)), Next::Closed));
+	}
+
+	#[test]
+	fn full_queue() {
+		let queue = JobQueue::new(NonZeroUsize::new(1), None);
+		push(&queue, 1, 1);
+		assert!(queue.is_full());
+		assert_eq!(pop(&queue), Some(1));
+		assert!(!queue.is_full());
+	}
+}
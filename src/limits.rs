@@ -0,0 +1,351 @@
+use std::{
+	collections::HashMap,
+	fmt::{self, Display, Formatter},
+	net::IpAddr,
+	num::{NonZeroU32, NonZeroUsize},
+	time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+use parking_lot::Mutex;
+
+use crate::{
+	address::Address,
+	logger::{debug, warning},
+};
+
+const WINDOW: Duration = Duration::from_secs(1);
+// sources quiet for a window are forgotten, once this many are remembered
+const PRUNE_SOURCES: usize = 1024;
+
+// an address, or a network in CIDR notation, e.g. 10.0.0.0/8
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct IpNetwork {
+	address: IpAddr,
+	prefix: u32,
+}
+
+impl IpNetwork {
+	pub(crate) fn parse(value: &str) -> Result<Self, Error> {
+		let (address, prefix) = value
+			.split_once('/')
+			.map_or((value, None), |(address, prefix)| (address, Some(prefix)));
+		let address = address
+			.parse::<IpAddr>()
+			.map_err(|_e| anyhow!("'{value}' is not an IP address or network"))?
+			.to_canonical();
+		let bits = if address.is_ipv4() { 32 } else { 128 };
+		let prefix = prefix.map_or(Ok(bits), |prefix| {
+			prefix
+				.parse::<u32>()
+				.ok()
+				.filter(|&prefix| prefix <= bits)
+				.ok_or_else(|| anyhow!("'{value}' must have a prefix length between 0 and {bits}"))
+		})?;
+		Ok(Self { address, prefix })
+	}
+
+	fn contains(self, ip: IpAddr) -> bool {
+		// IPv4 addresses are compared in the top bits, so the prefix length applies the same way to both families
+		let (network, ip) = match (self.address, ip) {
+			(IpAddr::V4(network), IpAddr::V4(ip)) => {
+				(u128::from(u32::from(network)) << 96, u128::from(u32::from(ip)) << 96)
+			},
+			(IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip)),
+			_ => return false,
+		};
+		// shifting by the full width overflows, and a prefix of zero matches every address
+		let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+		(network ^ ip) & mask == 0
+	}
+}
+
+// limits on a single source address, None for no limit, addresses in the allow list are never limited
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct SourceLimits {
+	pub(crate) connections: Option<NonZeroUsize>,
+	pub(crate) connection_rate: Option<NonZeroU32>,
+	pub(crate) datagram_rate: Option<NonZeroU32>,
+	pub(crate) allow: Vec<IpNetwork>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Refusal {
+	Connections(usize),
+	ConnectionRate(u32),
+	DatagramRate(u32),
+}
+
+impl Refusal {
+	pub(crate) const fn name(self) -> &'static str {
+		match self {
+			Self::Connections(_) => "connections",
+			Self::ConnectionRate(_) => "connection_rate",
+			Self::DatagramRate(_) => "datagram_rate",
+		}
+	}
+}
+
+impl Display for Refusal {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		match *self {
+			Self::Connections(limit) => write!(f, "{limit} connections are already open"),
+			Self::ConnectionRate(limit) => write!(f, "more than {limit} connections a second"),
+			Self::DatagramRate(limit) => write!(f, "more than {limit} datagrams a second"),
+		}
+	}
+}
+
+#[derive(Debug)]
+struct Source {
+	window_start: Instant,
+	window_count: u32,
+	// only the first refusal in a row is logged as a warning, so a flood does not flood the log
+	refused: bool,
+}
+
+impl Source {
+	// counts an event in the current window, unless the window is already at the limit
+	fn admit(&mut self, limit: NonZeroU32, now: Instant) -> bool {
+		if now.duration_since(self.window_start) >= WINDOW {
+			self.window_start = now;
+			self.window_count = 0;
+		}
+		if self.window_count >= limit.get() {
+			return false;
+		}
+		self.window_count += 1;
+		true
+	}
+}
+
+#[derive(Debug)]
+pub(crate) struct SourceLimiter {
+	limits: SourceLimits,
+	sources: Mutex<HashMap<IpAddr, Source>>,
+}
+
+impl SourceLimiter {
+	pub(crate) fn new(limits: SourceLimits) -> Self {
+		Self {
+			limits,
+			sources: Mutex::new(HashMap::new()),
+		}
+	}
+
+	// checks a new connection, given the number of connections already open from its source
+	pub(crate) fn check_connection(&self, ip: IpAddr, open: usize) -> Result<(), Refusal> {
+		let refusal = self
+			.limits
+			.connections
+			.filter(|limit| open >= limit.get())
+			.map(|limit| Refusal::Connections(limit.get()));
+		let rate = self
+			.limits
+			.connection_rate
+			.map(|rate| (rate, Refusal::ConnectionRate(rate.get())));
+		self.check(ip, refusal, rate)
+	}
+
+	pub(crate) fn check_datagram(&self, ip: IpAddr) -> Result<(), Refusal> {
+		let rate = self
+			.limits
+			.datagram_rate
+			.map(|rate| (rate, Refusal::DatagramRate(rate.get())));
+		self.check(ip, None, rate)
+	}
+
+	fn check(&self, ip: IpAddr, refusal: Option<Refusal>, rate: Option<(NonZeroU32, Refusal)>) -> Result<(), Refusal> {
+		if (refusal.is_none() && rate.is_none()) || self.limits.allow.iter().any(|network| network.contains(ip)) {
+			return Ok(());
+		}
+
+		let now = Instant::now();
+		let mut sources = self.sources.lock();
+		if sources.len() >= PRUNE_SOURCES {
+			sources.retain(|_, source| now.duration_since(source.window_start) < WINDOW);
+		}
+		let source = sources.entry(ip).or_insert_with(|| {
+			Source {
+				window_start: now,
+				window_count: 0,
+				refused: false,
+			}
+		});
+
+		let Some(refusal) = refusal.or_else(|| {
+			let (limit, refusal) = rate?;
+			(!source.admit(limit, now)).then_some(refusal)
+		})
+		else {
+			source.refused = false;
+			return Ok(());
+		};
+		if source.refused {
+			debug!("({ip}) Refused, {refusal}");
+		}
+		else {
+			warning!(
+				"({ip}) Refused, {refusal}, further refusals are logged at debug level until the address is admitted"
+			);
+			source.refused = true;
+		}
+		Err(refusal)
+	}
+}
+
+// IPv4 clients of a dual-stack listener are seen as IPv4 mapped IPv6 addresses, and are limited as IPv4 addresses,
+// unix socket clients have no source address and are never limited
+pub(crate) fn source_ip(addr: &Address) -> Option<IpAddr> {
+	addr.ip().map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::thread;
+
+	use super::*;
+
+	fn ip(value: &str) -> IpAddr {
+		value.parse::<IpAddr>().unwrap().to_canonical()
+	}
+
+	fn network(value: &str) -> IpNetwork {
+		IpNetwork::parse(value).unwrap()
+	}
+
+	fn limiter(limits: SourceLimits) -> SourceLimiter {
+		SourceLimiter::new(limits)
+	}
+
+	#[test]
+	fn parse_network() {
+		assert_eq!(network("10.1.2.3"), IpNetwork {
+			address: ip("10.1.2.3"),
+			prefix: 32,
+		});
+		assert_eq!(network("::1"), IpNetwork {
+			address: ip("::1"),
+			prefix: 128,
+		});
+		assert_eq!(network("10.0.0.0/8").prefix, 8);
+		assert_eq!(network("::ffff:10.0.0.0/8"), network("10.0.0.0/8"));
+		assert_eq!(network("0.0.0.0/0").prefix, 0);
+		assert_eq!(network("::/128").prefix, 128);
+	}
+
+	#[test]
+	fn parse_network_errors() {
+		for value in [
+			"",
+			"nope",
+			"10.0.0.0/",
+			"10.0.0.0/x",
+			"10.0.0.0/-1",
+			"10.0.0.0/33",
+			"::/129",
+			"10.0.0/8",
+		] {
+			assert!(IpNetwork::parse(value).is_err(), "{value}");
+		}
+	}
+
+	#[test]
+	fn contains_at_prefix_edges() {
+		assert!(network("0.0.0.0/0").contains(ip("255.255.255.255")));
+		assert!(network("0.0.0.0/0").contains(ip("0.0.0.0")));
+		assert!(!network("0.0.0.0/0").contains(ip("::1")));
+		assert!(network("::/0").contains(ip("ffff::1")));
+		assert!(!network("::/0").contains(ip("10.0.0.1")));
+
+		assert!(network("10.1.2.3/32").contains(ip("10.1.2.3")));
+		assert!(!network("10.1.2.3/32").contains(ip("10.1.2.2")));
+		assert!(!network("10.1.2.3/32").contains(ip("10.1.2.4")));
+		assert!(network("fe80::1/128").contains(ip("fe80::1")));
+		assert!(!network("fe80::1/128").contains(ip("fe80::2")));
+
+		assert!(network("10.0.0.0/8").contains(ip("10.255.255.255")));
+		assert!(!network("10.0.0.0/8").contains(ip("11.0.0.0")));
+		assert!(network("10.0.0.0/31").contains(ip("10.0.0.1")));
+		assert!(!network("10.0.0.0/31").contains(ip("10.0.0.2")));
+	}
+
+	#[test]
+	fn contains_ipv4_mapped_ipv6() {
+		assert!(network("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+		assert!(network("::ffff:10.0.0.0/8").contains(ip("10.1.2.3")));
+		assert!(!network("::ffff:10.0.0.0/8").contains(ip("11.1.2.3")));
+		assert!(!network("::/96").contains(ip("::ffff:10.1.2.3")));
+	}
+
+	#[test]
+	fn connection_cap() {
+		let limiter = limiter(SourceLimits {
+			connections: NonZeroUsize::new(2),
+			..SourceLimits::default()
+		});
+		let source = ip("10.0.0.1");
+		assert_eq!(limiter.check_connection(source, 0), Ok(()));
+		assert_eq!(limiter.check_connection(source, 1), Ok(()));
+		assert_eq!(limiter.check_connection(source, 2), Err(Refusal::Connections(2)));
+		assert_eq!(limiter.check_connection(ip("10.0.0.2"), 0), Ok(()));
+		// a closed connection is no longer counted as open, so the source is admitted again
+		assert_eq!(limiter.check_connection(source, 1), Ok(()));
+	}
+
+	#[test]
+	fn allowed_networks_are_not_limited() {
+		let limiter = limiter(SourceLimits {
+			connections: NonZeroUsize::new(1),
+			datagram_rate: NonZeroU32::new(1),
+			allow: vec![network("127.0.0.0/8")],
+			..SourceLimits::default()
+		});
+		assert_eq!(limiter.check_connection(ip("127.0.0.1"), 10), Ok(()));
+		assert_eq!(limiter.check_datagram(ip("::ffff:127.0.0.1")), Ok(()));
+		assert_eq!(limiter.check_datagram(ip("::ffff:127.0.0.1")), Ok(()));
+		assert_eq!(
+			limiter.check_connection(ip("10.0.0.1"), 1),
+			Err(Refusal::Connections(1))
+		);
+	}
+
+	#[test]
+	fn rate_window_resets() {
+		let limiter = limiter(SourceLimits {
+			connection_rate: NonZeroU32::new(2),
+			datagram_rate: NonZeroU32::new(1),
+			..SourceLimits::default()
+		});
+		let source = ip("10.0.0.1");
+		assert_eq!(limiter.check_connection(source, 0), Ok(()));
+		assert_eq!(limiter.check_connection(source, 0), Ok(()));
+		assert_eq!(limiter.check_connection(source, 0), Err(Refusal::ConnectionRate(2)));
+		assert_eq!(limiter.check_connection(ip("10.0.0.2"), 0), Ok(()));
+		thread::sleep(WINDOW);
+		assert_eq!(limiter.check_connection(source, 0), Ok(()));
+		assert_eq!(limiter.check_connection(source, 0), Ok(()));
+		assert_eq!(limiter.check_connection(source, 0), Err(Refusal::ConnectionRate(2)));
+	}
+
+	#[test]
+	fn quiet_sources_are_pruned() {
+		let limiter = limiter(SourceLimits {
+			datagram_rate: NonZeroU32::new(1),
+			..SourceLimits::default()
+		});
+		for index in 0..PRUNE_SOURCES {
+			let source = IpAddr::from(u32::try_from(index).unwrap().to_be_bytes());
+			assert_eq!(limiter.check_datagram(source), Ok(()));
+		}
+		assert_eq!(limiter.sources.lock().len(), PRUNE_SOURCES);
+		assert_eq!(limiter.check_datagram(ip("10.0.0.1")), Ok(()));
+		assert_eq!(limiter.sources.lock().len(), PRUNE_SOURCES + 1);
+
+		thread::sleep(WINDOW);
+		assert_eq!(limiter.check_datagram(ip("10.0.0.2")), Ok(()));
+		assert_eq!(limiter.sources.lock().len(), 1);
+		// a pruned source starts a new window
+		assert_eq!(limiter.check_datagram(ip("10.0.0.1")), Ok(()));
+	}
+}
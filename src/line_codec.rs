@@ -0,0 +1,277 @@
+use std::{
+	error,
+	fmt::{self, Display, Formatter},
+	io::Read,
+	mem,
+};
+
+use anyhow::{anyhow, Error};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::options::{OptionKind, Options, ProblemOption};
+
+const READ_SIZE: usize = 4096;
+
+// the line options of a problem, with the default maximum length for the problem
+pub(crate) const fn max_length_option(default: &'static str) -> ProblemOption {
+	ProblemOption {
+		name: "max-line-length",
+		kind: OptionKind::Count,
+		default,
+		description: "Most bytes in a line, not counting the line ending, 0 for no limit",
+	}
+}
+
+pub(crate) const UTF8_OPTION: ProblemOption = ProblemOption {
+	name: "utf8",
+	kind: OptionKind::Choice(&["lossy", "strict"]),
+	default: "lossy",
+	description: "Lines that are not valid UTF-8, lossy replaces the invalid bytes and strict rejects the line",
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Utf8 {
+	Lossy,
+	Strict,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct LineFormat {
+	// 0 for no limit
+	pub(crate) max_length: usize,
+	pub(crate) utf8: Utf8,
+}
+
+impl LineFormat {
+	pub(crate) fn from_options(options: &Options) -> Result<Self, Error> {
+		Ok(Self {
+			max_length: options.count("max-line-length")?,
+			utf8: match options.choice("utf8")? {
+				"strict" => Utf8::Strict,
+				"lossy" => Utf8::Lossy,
+				utf8 => return Err(anyhow!("Unknown utf8 policy: {utf8}")),
+			},
+		})
+	}
+}
+
+// a line the codec cannot return, the stream cannot be read past it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum LineError {
+	TooLong(usize),
+	InvalidUtf8,
+}
+
+impl Display for LineError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		match *self {
+			Self::TooLong(max_length) => write!(f, "line longer than {max_length} bytes"),
+			Self::InvalidUtf8 => write!(f, "line is not valid UTF-8"),
+		}
+	}
+}
+
+impl error::Error for LineError {}
+
+// splits the data read from a connection into lines ended by \n or \r\n, the start of a line that is not yet complete
+// is kept until the rest of the line is read, and dropped at the end of the stream
+#[derive(Debug)]
+pub(crate) struct LineCodec {
+	format: LineFormat,
+	buffer: Vec<u8>,
+	// the bytes of the buffer already searched for a line ending
+	searched: usize,
+}
+
+impl LineCodec {
+	pub(crate) const fn new(format: LineFormat) -> Self {
+		Self {
+			format,
+			buffer: vec![],
+			searched: 0,
+		}
+	}
+
+	pub(crate) fn push(&mut self, data: &[u8]) {
+		self.buffer.extend_from_slice(data);
+	}
+
+	// the next complete line, without its line ending
+	pub(crate) fn next_line(&mut self) -> Result<Option<String>, LineError> {
+		let max_length = self.format.max_length;
+		let Some(end) = self.buffer[self.searched..]
+			.iter()
+			.position(|&byte| byte == b'\n')
+			.map(|position| self.searched + position)
+		else {
+			self.searched = self.buffer.len();
+			// the last byte may be the \r of a \r\n
+			if max_length != 0 && self.buffer.len() > max_length + 1 {
+				return Err(LineError::TooLong(max_length));
+			}
+			return Ok(None);
+		};
+
+		let rest = self.buffer.split_off(end + 1);
+		let mut line = mem::replace(&mut self.buffer, rest);
+		self.searched = 0;
+		let _newline = line.pop();
+		if line.last() == Some(&b'\r') {
+			let _carriage_return = line.pop();
+		}
+		if max_length != 0 && line.len() > max_length {
+			return Err(LineError::TooLong(max_length));
+		}
+		match self.format.utf8 {
+			Utf8::Lossy => Ok(Some(String::from_utf8_lossy(&line).into_owned())),
+			Utf8::Strict => String::from_utf8(line).map(Some).map_err(|_e| LineError::InvalidUtf8),
+		}
+	}
+
+	// reads until a line is complete, None at the end of the stream, errors are a LineError or an error reading
+	pub(crate) fn read_line<R: Read + ?Sized>(&mut self, reader: &mut R) -> Result<Option<String>, Error> {
+		let mut buffer = [0; READ_SIZE];
+		loop {
+			if let Some(line) = self.next_line()? {
+				return Ok(Some(line));
+			}
+			let size = reader.read(&mut buffer)?;
+			if size == 0 {
+				return Ok(None);
+			}
+			self.push(&buffer[..size]);
+		}
+	}
+
+	pub(crate) async fn read_line_async<R: AsyncRead + Unpin + ?Sized>(
+		&mut self,
+		reader: &mut R,
+	) -> Result<Option<String>, Error> {
+		let mut buffer = [0; READ_SIZE];
+		loop {
+			if let Some(line) = self.next_line()? {
+				return Ok(Some(line));
+			}
+			let size = reader.read(&mut buffer).await?;
+			if size == 0 {
+				return Ok(None);
+			}
+			self.push(&buffer[..size]);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const LOSSY: LineFormat = LineFormat {
+		max_length: 0,
+		utf8: Utf8::Lossy,
+	};
+
+	fn lines(codec: &mut LineCodec, chunks: &[&[u8]]) -> Vec<Result<String, LineError>> {
+		let mut lines = vec![];
+		for chunk in chunks {
+			codec.push(chunk);
+			while let Some(line) = codec.next_line().transpose() {
+				let failed = line.is_err();
+				lines.push(line);
+				if failed {
+					return lines;
+				}
+			}
+		}
+		lines
+	}
+
+	#[test]
+	fn fragmented_lines() {
+		let mut codec = LineCodec::new(LOSSY);
+		assert_eq!(lines(&mut codec, &[b"fir", b"st\nsec", b"ond", b"\nthird\nfo"]), vec![
+			Ok(String::from("first")),
+			Ok(String::from("second")),
+			Ok(String::from("third"))
+		]);
+		assert_eq!(lines(&mut codec, &[b"urth\n"]), vec![Ok(String::from("fourth"))]);
+	}
+
+	#[test]
+	fn byte_at_a_time() {
+		let mut codec = LineCodec::new(LOSSY);
+		let data = b"one\r\ntwo\n\nthree\r\n";
+		let chunks = data.chunks(1).collect::<Vec<&[u8]>>();
+		assert_eq!(lines(&mut codec, &chunks), vec![
+			Ok(String::from("one")),
+			Ok(String::from("two")),
+			Ok(String::new()),
+			Ok(String::from("three"))
+		]);
+	}
+
+	#[test]
+	fn crlf_split_across_reads() {
+		let mut codec = LineCodec::new(LOSSY);
+		assert_eq!(lines(&mut codec, &[b"line\r", b"\nnext\r\n"]), vec![
+			Ok(String::from("line")),
+			Ok(String::from("next"))
+		]);
+		// only the \r of a line ending is removed
+		assert_eq!(lines(&mut codec, &[b"a\rb\r\r\n"]), vec![Ok(String::from("a\rb\r"))]);
+	}
+
+	#[test]
+	fn long_line_across_reads() {
+		let mut codec = LineCodec::new(LOSSY);
+		let line = "x".repeat(10_000);
+		let data = format!("{line}\nshort\n");
+		let chunks = data.as_bytes().chunks(4068).collect::<Vec<&[u8]>>();
+		assert_eq!(lines(&mut codec, &chunks), vec![Ok(line), Ok(String::from("short"))]);
+	}
+
+	#[test]
+	fn max_length() {
+		let format = LineFormat {
+			max_length: 5,
+			utf8: Utf8::Lossy,
+		};
+		let mut codec = LineCodec::new(format);
+		assert_eq!(lines(&mut codec, &[b"12345\r\n", b"123", b"45\n"]), vec![
+			Ok(String::from("12345")),
+			Ok(String::from("12345"))
+		]);
+		assert_eq!(lines(&mut codec, &[b"123456\n"]), vec![Err(LineError::TooLong(5))]);
+
+		// a partial line is rejected once it cannot fit, before its end is read
+		let mut codec = LineCodec::new(format);
+		assert_eq!(lines(&mut codec, &[b"1234", b"5\r"]), vec![]);
+		assert_eq!(lines(&mut codec, &[b"x"]), vec![Err(LineError::TooLong(5))]);
+	}
+
+	#[test]
+	fn utf8() {
+		let data: &[&[u8]] = &[b"caf\xC3", b"\xA9\n", b"bad \xFF\n"];
+		let mut codec = LineCodec::new(LOSSY);
+		assert_eq!(lines(&mut codec, data), vec![
+			Ok(String::from("caf\u{e9}")),
+			Ok(String::from("bad \u{FFFD}"))
+		]);
+
+		let mut codec = LineCodec::new(LineFormat {
+			max_length: 0,
+			utf8: Utf8::Strict,
+		});
+		assert_eq!(lines(&mut codec, data), vec![
+			Ok(String::from("caf\u{e9}")),
+			Err(LineError::InvalidUtf8)
+		]);
+	}
+
+	#[test]
+	fn read_line_drops_partial_line_at_end() {
+		let mut codec = LineCodec::new(LOSSY);
+		let mut reader: &[u8] = b"complete\npartial";
+		assert_eq!(codec.read_line(&mut reader).unwrap(), Some(String::from("complete")));
+		assert_eq!(codec.read_line(&mut reader).unwrap(), None);
+	}
+}
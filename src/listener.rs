@@ -0,0 +1,233 @@
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::{
+	io,
+	net::{TcpListener, TcpStream},
+	sync::Arc,
+	task::{Context, Poll},
+};
+
+#[cfg(not(unix))]
+use anyhow::anyhow;
+use anyhow::Error;
+use mio::{event::Source, net::TcpListener as PollTcpListener};
+use rustls::ServerConfig;
+use socket2::SockRef;
+use tokio::net::{TcpListener as AsyncTcpListener, TcpStream as AsyncTcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener as AsyncUnixListener, UnixStream as AsyncUnixStream};
+
+#[cfg(unix)]
+use crate::unix::{self, SocketFile};
+use crate::{
+	address::Address,
+	socket::{self, Bind},
+	stream::{AsyncStream, Stream},
+	tls,
+};
+
+// a listener of a stream problem, a unix socket keeps its file until the listener is dropped
+#[derive(Debug)]
+pub(crate) enum Listener {
+	Tcp(TcpListener),
+	#[cfg(unix)]
+	Unix(UnixListener, SocketFile),
+}
+
+impl Listener {
+	pub(crate) fn bind(bind: &Bind) -> Result<Vec<Self>, Error> {
+		match *bind {
+			Bind::Ip(ref addresses) => Ok(socket::bind_tcp(addresses)?.into_iter().map(Self::Tcp).collect()),
+			#[cfg(unix)]
+			Bind::Unix(ref path) => {
+				let (listener, file) = unix::bind_listener(path)?;
+				Ok(vec![Self::Unix(listener, file)])
+			},
+			#[cfg(not(unix))]
+			Bind::Unix(_) => Err(anyhow!("Unix sockets are not supported on this platform")),
+		}
+	}
+
+	pub(crate) const fn transport(&self) -> &'static str {
+		match *self {
+			Self::Tcp(_) => "TCP",
+			#[cfg(unix)]
+			Self::Unix(..) => "unix socket",
+		}
+	}
+
+	pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+		match *self {
+			Self::Tcp(ref listener) => listener.set_nonblocking(true),
+			#[cfg(unix)]
+			Self::Unix(ref listener, _) => listener.set_nonblocking(true),
+		}
+	}
+
+	pub(crate) fn local_addr(&self) -> io::Result<Address> {
+		match *self {
+			Self::Tcp(ref listener) => listener.local_addr().map(Address::from),
+			#[cfg(unix)]
+			Self::Unix(ref listener, _) => listener.local_addr().map(Address::from),
+		}
+	}
+
+	// a duplicate of the listener to register with an event poll
+	pub(crate) fn poll_source(&self) -> io::Result<Box<dyn Source>> {
+		Ok(match *self {
+			Self::Tcp(ref listener) => Box::new(PollTcpListener::from_std(listener.try_clone()?)),
+			#[cfg(unix)]
+			Self::Unix(ref listener, _) => Box::new(mio::net::UnixListener::from_std(listener.try_clone()?)),
+		})
+	}
+
+	pub(crate) fn accept(&self) -> io::Result<(Accepted, Address)> {
+		match *self {
+			Self::Tcp(ref listener) => {
+				listener
+					.accept()
+					.map(|(stream, addr)| (Accepted::Tcp(stream), Address::from(addr)))
+			},
+			#[cfg(unix)]
+			Self::Unix(ref listener, _) => {
+				listener
+					.accept()
+					.map(|(stream, addr)| (Accepted::Unix(stream), Address::from(addr)))
+			},
+		}
+	}
+
+	pub(crate) fn into_async(self) -> io::Result<AsyncListener> {
+		self.set_nonblocking()?;
+		Ok(match self {
+			Self::Tcp(listener) => AsyncListener::Tcp(AsyncTcpListener::from_std(listener)?),
+			#[cfg(unix)]
+			Self::Unix(listener, file) => AsyncListener::Unix(AsyncUnixListener::from_std(listener)?, file),
+		})
+	}
+}
+
+#[derive(Debug)]
+pub(crate) enum Accepted {
+	Tcp(TcpStream),
+	#[cfg(unix)]
+	Unix(UnixStream),
+}
+
+impl Accepted {
+	pub(crate) fn local_addr(&self) -> io::Result<Address> {
+		match *self {
+			Self::Tcp(ref stream) => stream.local_addr().map(Address::from),
+			#[cfg(unix)]
+			Self::Unix(ref stream) => stream.local_addr().map(Address::from),
+		}
+	}
+
+	// completes the TLS handshake of a TCP connection when TLS is configured, TLS is refused for problems on a unix
+	// socket
+	pub(crate) fn into_stream(self, tls: Option<&Arc<ServerConfig>>) -> Result<Box<dyn Stream>, Error> {
+		match self {
+			Self::Tcp(stream) => tls::wrap(stream, tls),
+			#[cfg(unix)]
+			Self::Unix(stream) => Ok(Box::new(stream)),
+		}
+	}
+}
+
+impl<'s> From<&'s Accepted> for SockRef<'s> {
+	fn from(accepted: &'s Accepted) -> Self {
+		match *accepted {
+			Accepted::Tcp(ref stream) => SockRef::from(stream),
+			#[cfg(unix)]
+			Accepted::Unix(ref stream) => SockRef::from(stream),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub(crate) enum AsyncListener {
+	Tcp(AsyncTcpListener),
+	#[cfg(unix)]
+	Unix(AsyncUnixListener, #[allow(dead_code)] SocketFile),
+}
+
+impl AsyncListener {
+	pub(crate) const fn transport(&self) -> &'static str {
+		match *self {
+			Self::Tcp(_) => "TCP",
+			#[cfg(unix)]
+			Self::Unix(..) => "unix socket",
+		}
+	}
+
+	pub(crate) fn local_addr(&self) -> io::Result<Address> {
+		match *self {
+			Self::Tcp(ref listener) => listener.local_addr().map(Address::from),
+			#[cfg(unix)]
+			Self::Unix(ref listener, _) => listener.local_addr().map(Address::from),
+		}
+	}
+
+	fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(AsyncAccepted, Address)>> {
+		match *self {
+			Self::Tcp(ref listener) => {
+				listener
+					.poll_accept(cx)
+					.map_ok(|(stream, addr)| (AsyncAccepted::Tcp(stream), Address::from(addr)))
+			},
+			#[cfg(unix)]
+			Self::Unix(ref listener, _) => {
+				listener
+					.poll_accept(cx)
+					.map_ok(|(stream, addr)| (AsyncAccepted::Unix(stream), Address::from(addr)))
+			},
+		}
+	}
+}
+
+#[derive(Debug)]
+pub(crate) enum AsyncAccepted {
+	Tcp(AsyncTcpStream),
+	#[cfg(unix)]
+	Unix(AsyncUnixStream),
+}
+
+impl AsyncAccepted {
+	pub(crate) fn local_addr(&self) -> io::Result<Address> {
+		match *self {
+			Self::Tcp(ref stream) => stream.local_addr().map(Address::from),
+			#[cfg(unix)]
+			Self::Unix(ref stream) => stream.local_addr().map(Address::from),
+		}
+	}
+
+	pub(crate) async fn into_stream(self, tls: Option<&Arc<ServerConfig>>) -> Result<Box<dyn AsyncStream>, Error> {
+		match self {
+			Self::Tcp(stream) => tls::wrap_async(stream, tls).await,
+			#[cfg(unix)]
+			Self::Unix(stream) => Ok(Box::new(stream)),
+		}
+	}
+}
+
+impl<'s> From<&'s AsyncAccepted> for SockRef<'s> {
+	fn from(accepted: &'s AsyncAccepted) -> Self {
+		match *accepted {
+			AsyncAccepted::Tcp(ref stream) => SockRef::from(stream),
+			#[cfg(unix)]
+			AsyncAccepted::Unix(ref stream) => SockRef::from(stream),
+		}
+	}
+}
+
+pub(crate) fn poll_accept_any(
+	listeners: &[AsyncListener],
+	cx: &mut Context<'_>,
+) -> Poll<io::Result<(AsyncAccepted, Address)>> {
+	for listener in listeners {
+		if let Poll::Ready(result) = listener.poll_accept(cx) {
+			return Poll::Ready(result);
+		}
+	}
+	Poll::Pending
+}
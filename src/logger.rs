@@ -0,0 +1,241 @@
+use std::{
+	fmt::{self, Write as _},
+	io::{stderr, Write},
+	sync::atomic::{AtomicBool, Ordering},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use parking_lot::{const_rwlock, RwLock};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Level {
+	Off,
+	Error,
+	Warn,
+	Info,
+	Debug,
+	Trace,
+}
+
+impl Level {
+	fn parse(value: &str) -> Result<Self, Error> {
+		match value.to_lowercase().as_str() {
+			"off" => Ok(Self::Off),
+			"error" => Ok(Self::Error),
+			"warn" => Ok(Self::Warn),
+			"info" => Ok(Self::Info),
+			"debug" => Ok(Self::Debug),
+			"trace" => Ok(Self::Trace),
+			_ => {
+				Err(anyhow!(
+					"'{value}' is not a level, expected off, error, warn, info, debug or trace"
+				))
+			},
+		}
+	}
+
+	const fn name(self) -> &'static str {
+		match self {
+			Self::Off => "off",
+			Self::Error => "error",
+			Self::Warn => "warn",
+			Self::Info => "info",
+			Self::Debug => "debug",
+			Self::Trace => "trace",
+		}
+	}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Format {
+	Text,
+	Json,
+}
+
+impl Format {
+	pub(crate) fn parse(value: &str) -> Result<Self, Error> {
+		match value.to_lowercase().as_str() {
+			"text" => Ok(Self::Text),
+			"json" => Ok(Self::Json),
+			_ => Err(anyhow!("'{value}' is not a log format, expected text or json")),
+		}
+	}
+}
+
+// a default level followed by module overrides, e.g. "info,budget_chat=debug,worker=off"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Filter {
+	default: Level,
+	modules: Vec<(String, Level)>,
+}
+
+impl Filter {
+	pub(crate) fn parse(spec: &str) -> Result<Self, Error> {
+		let mut filter = Self {
+			default: Level::Info,
+			modules: vec![],
+		};
+		for directive in spec.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+			if let Some((module, level)) = directive.split_once('=') {
+				filter
+					.modules
+					.push((String::from(module.trim()), Level::parse(level.trim())?));
+			}
+			else {
+				filter.default = Level::parse(directive)?;
+			}
+		}
+		Ok(filter)
+	}
+
+	fn level(&self, module: &str) -> Level {
+		self.modules
+			.iter()
+			.rev()
+			.find(|&(name, _)| name == module)
+			.map_or(self.default, |&(_, level)| level)
+	}
+}
+
+#[derive(Debug)]
+struct Config {
+	filter: Filter,
+	format: Format,
+}
+
+static CONFIG: RwLock<Config> = const_rwlock(Config {
+	filter: Filter {
+		default: Level::Info,
+		modules: Vec::new(),
+	},
+	format: Format::Text,
+});
+
+static PAYLOADS: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn init(filter: Filter, format: Format, payloads: bool) {
+	*CONFIG.write() = Config { filter, format };
+	PAYLOADS.store(payloads, Ordering::Release);
+}
+
+// flips payload dumps on or off, returning the new state
+pub(crate) fn toggle_payloads() -> bool {
+	!PAYLOADS.fetch_xor(true, Ordering::AcqRel)
+}
+
+fn module_name(module_path: &str) -> &str {
+	module_path.split_once("::").map_or("main", |(_, module)| module)
+}
+
+pub(crate) fn enabled(level: Level, module_path: &str) -> bool {
+	level != Level::Off && level <= CONFIG.read().filter.level(module_name(module_path))
+}
+
+pub(crate) fn payloads_enabled(module_path: &str) -> bool {
+	PAYLOADS.load(Ordering::Acquire) && CONFIG.read().filter.level(module_name(module_path)) != Level::Off
+}
+
+fn escape_json(value: &str, out: &mut String) {
+	for c in value.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if c.is_control() => {
+				let _result = write!(out, "\\u{:04x}", c as u32);
+			},
+			c => out.push(c),
+		}
+	}
+}
+
+pub(crate) fn write(level: Level, module_path: &str, connection: Option<u64>, message: fmt::Arguments<'_>) {
+	let module = module_name(module_path);
+	let mut line = String::new();
+
+	match CONFIG.read().format {
+		Format::Text => {
+			let _result = write!(line, "{:<5} {module}", level.name().to_uppercase());
+			if let Some(connection) = connection {
+				let _result = write!(line, " ({connection})");
+			}
+			let _result = write!(line, " {message}");
+		},
+		Format::Json => {
+			let time = SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.map_or(0, |duration| duration.as_millis());
+			let _result = write!(line, "{{\"time\":{time},\"level\":\"{}\",\"module\":\"", level.name());
+			escape_json(module, &mut line);
+			line.push('"');
+			if let Some(connection) = connection {
+				let _result = write!(line, ",\"connection\":{connection}");
+			}
+			line.push_str(",\"message\":\"");
+			escape_json(message.to_string().as_str(), &mut line);
+			line.push_str("\"}");
+		},
+	}
+	line.push('\n');
+
+	// a failed write to stderr has nowhere to be reported
+	let _result = stderr().lock().write_all(line.as_bytes());
+}
+
+macro_rules! log {
+	($level:expr, connection: $connection:expr, $($arg:tt)+) => {
+		if $crate::logger::enabled($level, module_path!()) {
+			$crate::logger::write($level, module_path!(), Some($connection), format_args!($($arg)+));
+		}
+	};
+	($level:expr, $($arg:tt)+) => {
+		if $crate::logger::enabled($level, module_path!()) {
+			$crate::logger::write($level, module_path!(), None, format_args!($($arg)+));
+		}
+	};
+}
+
+macro_rules! error {
+	($($arg:tt)+) => { $crate::logger::log!($crate::logger::Level::Error, $($arg)+) };
+}
+
+macro_rules! warning {
+	($($arg:tt)+) => { $crate::logger::log!($crate::logger::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+	($($arg:tt)+) => { $crate::logger::log!($crate::logger::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+	($($arg:tt)+) => { $crate::logger::log!($crate::logger::Level::Debug, $($arg)+) };
+}
+
+macro_rules! trace {
+	($($arg:tt)+) => { $crate::logger::log!($crate::logger::Level::Trace, $($arg)+) };
+}
+
+// payload dumps are controlled by their own switch, which can be toggled at runtime, instead of the level
+macro_rules! payload {
+	(connection: $connection:expr, $($arg:tt)+) => {
+		if $crate::logger::payloads_enabled(module_path!()) {
+			$crate::logger::write($crate::logger::Level::Trace, module_path!(), Some($connection), format_args!($($arg)+));
+		}
+	};
+	($($arg:tt)+) => {
+		if $crate::logger::payloads_enabled(module_path!()) {
+			$crate::logger::write($crate::logger::Level::Trace, module_path!(), None, format_args!($($arg)+));
+		}
+	};
+}
+
+pub(crate) use debug;
+pub(crate) use error;
+pub(crate) use info;
+pub(crate) use log;
+pub(crate) use payload;
+pub(crate) use trace;
+pub(crate) use warning;
@@ -71,79 +71,90 @@
 	clippy::unwrap_used
 )]
 
+mod address;
+mod binary_codec;
 mod budget_chat;
+mod capture;
+mod cli;
+mod client;
+mod connections;
+mod context;
+mod datagram;
 mod handler;
 mod job;
+mod job_queue;
+mod limits;
+mod line_codec;
+mod listener;
+mod logger;
 mod means_to_an_end;
+mod metrics;
+mod options;
 mod prime_time;
+mod registry;
+mod replay;
+mod shutdown;
+mod signals;
 mod smoke_test;
+mod socket;
+mod stream;
 mod thread_pool;
+mod timeouts;
+mod tls;
+#[cfg(unix)]
+mod unix;
 mod unusual_database_program;
 mod utils;
 mod worker;
 
 use std::{
-	collections::HashMap,
 	env,
-	io::ErrorKind,
-	net::{TcpListener, UdpSocket},
+	future::poll_fn,
+	io::{self, stdout, ErrorKind, Write},
+	net::Shutdown,
 	num::NonZeroUsize,
+	panic::{catch_unwind, AssertUnwindSafe},
 	process,
-	sync::{
-		atomic::{AtomicBool, Ordering},
-		Arc,
-	},
+	sync::Arc,
 	thread,
 	time::Duration,
 };
 
 use anyhow::{anyhow, Error};
 use ctrlc::set_handler;
-use lazy_static::lazy_static;
-use thread_pool::ThreadPool;
+use mio::{Events, Interest, Poll, Token, Waker};
+use rustls::ServerConfig;
+use socket2::{SockRef, Socket};
+use tokio::{
+	runtime::{Builder, Runtime},
+	select,
+	spawn,
+	task::spawn_blocking,
+	time::interval,
+};
 
 use crate::{
-	budget_chat::BudgetChat,
-	handler::{TcpHandler, UdpHandler},
-	means_to_an_end::MeansToAnEnd,
-	prime_time::PrimeTime,
-	smoke_test::SmokeTest,
-	unusual_database_program::UnusualDatabaseProgram,
+	address::Address,
+	capture::Capture,
+	cli::{Backend, Command, ServeArgs},
+	connections::{ConnectionGuard, ConnectionTracker, DrainReport},
+	context::ConnectionContext,
+	datagram::Datagram,
+	handler::{AsyncTcpHandler, AsyncUdpHandler, CatchUnwind, HandlerPanic, TcpHandler, UdpHandler},
+	job::JobLabel,
+	limits::{SourceLimiter, SourceLimits},
+	listener::Listener,
+	logger::{debug, error, info, payload, warning},
+	metrics::{DatagramMetrics, ServerMetrics},
+	registry::Handler,
+	shutdown::ShutdownSignal,
+	socket::Bind,
+	thread_pool::{Overflow, ThreadPool},
+	timeouts::Timeouts,
 	utils::data_to_hex,
 };
 
-#[derive(Debug, Copy, Clone)]
-enum TcpProblem {
-	None,
-	SmokeTest,
-	PrimeTime,
-	MeansToAnEnd,
-	BudgetChat,
-}
-
-#[derive(Debug, Copy, Clone)]
-enum UdpProblem {
-	None,
-	UnusualDatabaseProgram,
-}
-
-#[derive(Debug, Copy, Clone)]
-enum Type {
-	None,
-	Tcp,
-	Udp,
-}
-
-lazy_static! {
-	static ref TCP_PROBLEMS: [(&'static str, TcpProblem); 4] = [
-		("smoketest", TcpProblem::SmokeTest),
-		("primetime", TcpProblem::PrimeTime),
-		("meanstoanend", TcpProblem::MeansToAnEnd),
-		("budgetchat", TcpProblem::BudgetChat),
-	];
-	static ref UDP_PROBLEMS: [(&'static str, UdpProblem); 1] =
-		[("unusualdatabaseprogram", UdpProblem::UnusualDatabaseProgram)];
-}
+const WAKE_TOKEN: Token = Token(usize::MAX);
 
 #[allow(clippy::exit)]
 fn main() {
@@ -153,165 +164,592 @@ fn main() {
 	}
 }
 
-#[allow(clippy::exit)]
 fn try_main() -> Result<(), Error> {
-	let port = env::var("PORT").unwrap_or_else(|_| String::from("7878"));
-	let shutdown = Arc::new(AtomicBool::new(false));
+	match cli::parse(env::args().skip(1))? {
+		Command::Help => stdout().write_all(cli::usage().as_bytes()).map_err(Error::from),
+		Command::List => {
+			stdout()
+				.write_all(registry::problem_list().as_bytes())
+				.map_err(Error::from)
+		},
+		Command::Serve(args) => try_serve_main(&args),
+		Command::Replay(args) => {
+			let replay = replay::run(&args)?;
+			stdout().write_all(replay.report.as_bytes())?;
+			if replay.differing > 0 {
+				return Err(anyhow!(
+					"{} of {} connections differ from the session",
+					replay.differing,
+					replay.connections
+				));
+			}
+			Ok(())
+		},
+		Command::Client(args) => client::run(&args),
+	}
+}
+
+type Server = (&'static str, Bind, Handler, Timeouts, Option<Arc<Capture>>);
+
+#[allow(clippy::exit)]
+fn try_serve_main(args: &ServeArgs) -> Result<(), Error> {
+	logger::init(args.log_filter.clone(), args.log_format, args.log_payloads);
+	signals::listen()?;
+	if let Some(address) = args.metrics {
+		metrics::serve(address)?;
+	}
+
+	let shutdown = ShutdownSignal::new();
 	let handler_shutdown = Arc::clone(&shutdown);
 
 	set_handler(move || {
-		if shutdown.load(Ordering::Acquire) {
+		if handler_shutdown.is_requested() {
 			process::exit(0);
 		}
-		eprintln!("Shutdown requested. CTRL+C to force.");
-		shutdown.store(true, Ordering::Release);
+		info!("Shutdown requested. CTRL+C to force.");
+		handler_shutdown.request();
 	})?;
 
-	match select_socket_type_from_args() {
-		Type::Tcp => try_tcp_main(port.as_str(), &handler_shutdown),
-		Type::Udp => try_udp_main(port.as_str(), &handler_shutdown),
-		Type::None => {
-			eprintln!("No socket type selected. Available problems: tcp, udp");
-			Ok(())
-		},
+	let servers = args
+		.servers
+		.iter()
+		.map(|server| {
+			Ok((
+				server.problem.name,
+				server.bind.clone(),
+				(server.problem.create)(&server.options)?,
+				server.timeouts,
+				args.capture
+					.as_deref()
+					.map(|directory| Capture::create(directory, server.problem.name))
+					.transpose()?,
+			))
+		})
+		.collect::<Result<Vec<Server>, Error>>()?;
+
+	match args.backend {
+		Backend::ThreadPool => try_thread_pool_main(servers, args, &shutdown),
+		Backend::Async => try_async_main(servers, args, &shutdown),
 	}
 }
 
-fn try_udp_main(port: &str, shutdown_flag: &Arc<AtomicBool>) -> Result<(), Error> {
-	let problem: Arc<Box<dyn UdpHandler>> = Arc::new(match select_udp_problem_from_args() {
-		UdpProblem::None => {
-			eprintln!("No problem selected. Available problems: ");
-			for &(key, _) in UDP_PROBLEMS.iter() {
-				eprintln!("  - {}", key);
-			}
-			return Ok(());
-		},
-		UdpProblem::UnusualDatabaseProgram => Box::new(UnusualDatabaseProgram::new()),
-	});
+fn try_thread_pool_main(servers: Vec<Server>, args: &ServeArgs, shutdown: &Arc<ShutdownSignal>) -> Result<(), Error> {
+	let tls = args.tls.as_ref().map(tls::load_config).transpose()?;
+	let tls = tls.as_ref();
+	thread::scope(|s| {
+		let handles = servers
+			.into_iter()
+			.map(|(name, bind, handler, timeouts, capture)| {
+				s.spawn(move || {
+					let capture = capture.as_ref();
+					let result = match handler {
+						Handler::Tcp(problem, _) => {
+							try_tcp_main(name, &problem, &bind, timeouts, tls, capture, args, shutdown)
+						},
+						Handler::Udp(problem, _) => {
+							try_udp_main(name, &problem, &bind, &args.limits, capture, shutdown)
+						},
+					};
+					stop_on_error(result, shutdown)
+				})
+			})
+			.collect::<Vec<_>>();
 
-	let socket = UdpSocket::bind(format!("0.0.0.0:{port}")).map_err(Error::from)?;
-	socket.set_nonblocking(true).expect("Failed to set nonblocking");
-	eprintln!("Ready to accept UDP messages on {}", socket.local_addr()?);
+		first_error(handles.into_iter().map(|handle| handle.join().unwrap()))
+	})
+}
 
-	let wait_duration = Duration::from_millis(100);
+fn try_async_main(servers: Vec<Server>, args: &ServeArgs, shutdown: &Arc<ShutdownSignal>) -> Result<(), Error> {
+	let drain_timeout = args.drain_timeout;
+	let tls = args.tls.as_ref().map(tls::load_config).transpose()?;
+	async_runtime(args.async_threads)?.block_on(async {
+		let handles = servers
+			.into_iter()
+			.map(|(name, bind, handler, timeouts, capture)| {
+				let server_shutdown = Arc::clone(shutdown);
+				let limits = args.limits.clone();
+				let server_tls = tls.clone();
+				spawn(async move {
+					let result = match handler {
+						Handler::Tcp(_, problem) => {
+							let tcp_main = try_async_tcp_main(
+								name,
+								problem,
+								&bind,
+								drain_timeout,
+								timeouts,
+								server_tls,
+								limits,
+								capture,
+								&server_shutdown,
+							);
+							tcp_main.await
+						},
+						Handler::Udp(_, problem) => {
+							try_async_udp_main(name, problem, &bind, limits, capture.as_ref(), &server_shutdown).await
+						},
+					};
+					stop_on_error(result, &server_shutdown)
+				})
+			})
+			.collect::<Vec<_>>();
 
-	let mut handler_socket = socket.try_clone()?;
+		let mut results = vec![];
+		for handle in handles {
+			results.push(handle.await?);
+		}
+		first_error(results.into_iter())
+	})
+}
 
-	loop {
-		let mut buffer = [0; 1024];
-		match socket.recv_from(&mut buffer) {
-			Ok((size, addr)) => {
-				let data = &buffer[0..size];
-				eprintln!("({addr}) Data: '{}' ", data_to_hex(data));
-
-				if let Err(e) = problem.handler(data, &mut handler_socket, addr) {
-					eprintln!("{}", e);
+fn try_udp_main(
+	name: &str,
+	problem: &Arc<dyn UdpHandler>,
+	bind: &Bind,
+	limits: &SourceLimits,
+	capture: Option<&Arc<Capture>>,
+	shutdown: &ShutdownSignal,
+) -> Result<(), Error> {
+	let mut selector = event_poll(shutdown)?;
+	let mut sockets = vec![];
+	for (index, socket) in Datagram::bind(bind, capture)?.into_iter().enumerate() {
+		socket.set_nonblocking().expect("Failed to set nonblocking");
+		info!(
+			"Ready to accept {} messages on {}",
+			socket.transport(),
+			socket.local_addr()?
+		);
+		let mut source = socket.poll_source()?;
+		selector
+			.registry()
+			.register(&mut *source, Token(index), Interest::READABLE)?;
+		sockets.push((socket, source));
+	}
+
+	let metrics = DatagramMetrics::new(name);
+	let limiter = SourceLimiter::new(limits.clone());
+	let mut events = Events::with_capacity(sockets.len() + 1);
+	let mut buffer = vec![0; problem.max_datagram_size()];
+
+	while !shutdown.is_requested() {
+		wait_for_events(&mut selector, &mut events)?;
+		for event in &events {
+			if let Some((socket, _)) = sockets.get(event.token().0) {
+				// readiness is edge triggered, so read until the socket would block
+				loop {
+					match socket.recv_from(&mut buffer) {
+						Ok((size, addr)) => {
+							if !admit_datagram(&addr, &limiter, &metrics) {
+								continue;
+							}
+							let data = &buffer[0..size];
+							payload!("({addr}) Data: '{}' ", data_to_hex(data));
+							metrics.add_read(size);
+							if let Some(capture) = capture {
+								capture.received(&addr, data);
+							}
+
+							if let Err(e) = problem.handler(data, socket, &addr) {
+								metrics.handler_error(&e);
+								warning!("({addr}) {e}");
+							}
+						},
+						Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+						Err(err) => return Err(Error::from(err)),
+					}
 				}
-			},
-			Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
-				if shutdown_flag.load(Ordering::Acquire) {
-					problem.shutdown();
-					break;
+			}
+		}
+	}
+
+	problem.shutdown();
+	Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn try_tcp_main(
+	name: &str,
+	problem: &Arc<dyn TcpHandler>,
+	bind: &Bind,
+	timeouts: Timeouts,
+	tls: Option<&Arc<ServerConfig>>,
+	capture: Option<&Arc<Capture>>,
+	args: &ServeArgs,
+	shutdown: &Arc<ShutdownSignal>,
+) -> Result<(), Error> {
+	let mut selector = event_poll(shutdown)?;
+	let mut listeners = vec![];
+	for (index, listener) in Listener::bind(bind)?.into_iter().enumerate() {
+		listener.set_nonblocking().expect("Failed to set nonblocking");
+		info!(
+			"Ready to accept {} connections on {}",
+			listener.transport(),
+			listener.local_addr()?
+		);
+		let mut source = listener.poll_source()?;
+		selector
+			.registry()
+			.register(&mut *source, Token(index), Interest::READABLE)?;
+		listeners.push((listener, source));
+	}
+
+	let pool = ThreadPool::new(name, args.pool_size, args.queue_capacity, shutdown.receiver());
+	let connections = ConnectionTracker::new(shutdown, timeouts);
+	connections.expire_on_interval()?;
+	let metrics = ServerMetrics::new(name);
+	let _pool_gauges = metrics.watch_pool(&pool);
+	let limiter = SourceLimiter::new(args.limits.clone());
+
+	let mut events = Events::with_capacity(listeners.len() + 1);
+
+	while !shutdown.is_requested() {
+		wait_for_events(&mut selector, &mut events)?;
+		for event in &events {
+			if let Some((listener, _)) = listeners.get(event.token().0) {
+				// readiness is edge triggered, so accept until the listener would block
+				loop {
+					match listener.accept() {
+						Ok((stream, addr)) => {
+							if !admit_connection(&addr, &limiter, &connections, &metrics) {
+								continue;
+							}
+							if args.overflow != Overflow::Block && pool.is_full() {
+								// a busy response would need a TLS handshake, which could hold up the accept loop
+								let response = problem.busy_response().filter(|_| tls.is_none());
+								reject_connection(&SockRef::from(&stream), &addr, response, args.overflow, &metrics);
+								continue;
+							}
+							let local_addr = stream.local_addr();
+							let (context, guard) =
+								match open_connection(&stream, &addr, local_addr, &connections, &metrics, shutdown) {
+									Ok(connection) => connection,
+									Err(e) => {
+										warning!("({addr}) {e}");
+										continue;
+									},
+								};
+							let thread_problem = Arc::clone(problem);
+							let thread_tls = tls.cloned();
+							let thread_capture = capture.cloned();
+							let queued_context = context.clone();
+							let label = JobLabel {
+								connection: Some(context.id()),
+								problem: Some(String::from(name)),
+								peer: Some(addr),
+							};
+							let queued = pool.execute_with_label(label, move || {
+								let result = stream.into_stream(thread_tls.as_ref()).and_then(|stream| {
+									let stream = match thread_capture {
+										Some(ref capture) => capture.stream(stream, &context),
+										None => stream,
+									};
+									catch_unwind(AssertUnwindSafe(|| thread_problem.handler(stream, context.clone())))
+										.unwrap_or_else(|payload| Err(HandlerPanic::error(payload.as_ref())))
+								});
+								if let Err(e) = result {
+									report_handler_error(&context, &e);
+								}
+								if let Some(ref capture) = thread_capture {
+									capture.closed(&context);
+								}
+								close_connection(&context, guard);
+							});
+							if let Err(e) = queued {
+								// the job was dropped without running, which released the connection
+								debug!(connection: queued_context.id(), "{e}");
+								record_disconnect(&queued_context);
+							}
+						},
+						Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+						Err(err) => return Err(Error::from(err)),
+					}
 				}
-				thread::sleep(wait_duration);
-			},
-			Err(err) => return Err(Error::from(err)),
+			}
 		}
 	}
+
+	problem.shutdown();
+	report_drain(name, connections.drain(args.drain_timeout));
 	Ok(())
 }
 
-fn try_tcp_main(port: &str, shutdown_flag: &Arc<AtomicBool>) -> Result<(), Error> {
-	let problem: Arc<Box<dyn TcpHandler>> = Arc::new(match select_tcp_problem_from_args() {
-		TcpProblem::None => {
-			eprintln!("No problem selected. Available problems: ");
-			for &(key, _) in TCP_PROBLEMS.iter() {
-				eprintln!("  - {}", key);
+async fn try_async_udp_main(
+	name: &str,
+	problem: Arc<dyn AsyncUdpHandler>,
+	bind: &Bind,
+	limits: SourceLimits,
+	capture: Option<&Arc<Capture>>,
+	shutdown: &ShutdownSignal,
+) -> Result<(), Error> {
+	let mut sockets = vec![];
+	for socket in Datagram::bind(bind, capture)? {
+		let socket = socket.into_async()?;
+		info!(
+			"Ready to accept {} messages on {}",
+			socket.transport(),
+			socket.local_addr()?
+		);
+		sockets.push(socket);
+	}
+
+	let metrics = DatagramMetrics::new(name);
+	let limiter = SourceLimiter::new(limits);
+	let mut buffer = vec![0; problem.max_datagram_size()];
+
+	loop {
+		let received = select! {
+			result = poll_fn(|cx| datagram::poll_recv_from_any(&sockets, cx, &mut buffer)) => Some(result?),
+			() = shutdown.requested() => None,
+		};
+
+		if let Some((index, size, addr)) = received {
+			if !admit_datagram(&addr, &limiter, &metrics) {
+				continue;
+			}
+			let data = &buffer[0..size];
+			payload!("({addr}) Data: '{}' ", data_to_hex(data));
+			metrics.add_read(size);
+			if let Some(capture) = capture {
+				capture.received(&addr, data);
 			}
-			return Ok(());
-		},
-		TcpProblem::SmokeTest => Box::new(SmokeTest::new()),
-		TcpProblem::PrimeTime => Box::new(PrimeTime::new()),
-		TcpProblem::MeansToAnEnd => Box::new(MeansToAnEnd::new()),
-		TcpProblem::BudgetChat => Box::new(BudgetChat::new()),
-	});
 
-	let number_workers = concurrency_from_environment()?;
+			if let Err(e) = problem.handler(data, &sockets[index], &addr).await {
+				metrics.handler_error(&e);
+				warning!("({addr}) {e}");
+			}
+		}
+		else {
+			break;
+		}
+	}
 
-	let listener = TcpListener::bind(format!("0.0.0.0:{port}")).map_err(Error::from)?;
-	listener.set_nonblocking(true).expect("Failed to set nonblocking");
-	eprintln!("Ready to accept TCP connections on {}", listener.local_addr()?);
+	problem.shutdown();
+	Ok(())
+}
 
-	let pool = ThreadPool::new(number_workers);
-	let mut connection_id: u32 = 0;
+#[allow(clippy::too_many_arguments)]
+async fn try_async_tcp_main(
+	name: &str,
+	problem: Arc<dyn AsyncTcpHandler>,
+	bind: &Bind,
+	drain_timeout: Duration,
+	timeouts: Timeouts,
+	tls: Option<Arc<ServerConfig>>,
+	limits: SourceLimits,
+	capture: Option<Arc<Capture>>,
+	shutdown: &Arc<ShutdownSignal>,
+) -> Result<(), Error> {
+	let mut listeners = vec![];
+	for listener in Listener::bind(bind)? {
+		let listener = listener.into_async()?;
+		info!(
+			"Ready to accept {} connections on {}",
+			listener.transport(),
+			listener.local_addr()?
+		);
+		listeners.push(listener);
+	}
 
-	let wait_duration = Duration::from_millis(100);
+	let connections = ConnectionTracker::new(shutdown, timeouts);
+	let metrics = ServerMetrics::new(name);
+	let limiter = SourceLimiter::new(limits);
+	let mut timeout_check = interval(timeouts::CHECK_INTERVAL);
 
 	loop {
-		match listener.accept() {
-			Ok((stream, addr)) => {
-				connection_id = connection_id.wrapping_add(1);
-				eprintln!("({connection_id}) Client connected: {addr}");
-				let thread_problem = Arc::clone(&problem);
-				pool.execute(move || {
-					if let Err(e) = thread_problem.handler(stream, connection_id) {
-						eprintln!("{}", e);
+		select! {
+			result = poll_fn(|cx| listener::poll_accept_any(&listeners, cx)) => {
+				let (stream, addr) = result?;
+				if !admit_connection(&addr, &limiter, &connections, &metrics) {
+					continue;
+				}
+				let local_addr = stream.local_addr();
+				let (context, guard) = match open_connection(&stream, &addr, local_addr, &connections, &metrics, shutdown) {
+					Ok(connection) => connection,
+					Err(e) => {
+						warning!("({addr}) {e}");
+						continue;
+					},
+				};
+				let task_problem = Arc::clone(&problem);
+				let task_tls = tls.clone();
+				let task_capture = capture.clone();
+				let _handle = spawn(async move {
+					let result = match stream.into_stream(task_tls.as_ref()).await {
+						Ok(stream) => {
+							let stream = match task_capture {
+								Some(ref capture) => capture.async_stream(stream, &context),
+								None => stream,
+							};
+							CatchUnwind(task_problem.handler(stream, context.clone())).await
+						},
+						Err(e) => Err(e),
+					};
+					if let Err(e) = result {
+						report_handler_error(&context, &e);
+					}
+					if let Some(ref capture) = task_capture {
+						capture.closed(&context);
 					}
+					close_connection(&context, guard);
 				});
 			},
-			Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
-				if shutdown_flag.load(Ordering::Acquire) {
-					problem.shutdown();
-					break;
-				}
-				thread::sleep(wait_duration);
-			},
-			Err(err) => return Err(Error::from(err)),
+			_ = timeout_check.tick() => connections.expire(),
+			() = shutdown.requested() => break,
 		}
 	}
+
+	problem.shutdown();
+	let report = spawn_blocking(move || connections.drain(drain_timeout)).await?;
+	report_drain(name, report);
 	Ok(())
 }
 
-fn select_socket_type_from_args() -> Type {
-	let socket_type = env::args().nth(1).unwrap_or_default().to_lowercase();
+fn open_connection<S>(
+	stream: &S,
+	peer_addr: &Address,
+	local_addr: io::Result<Address>,
+	connections: &Arc<ConnectionTracker>,
+	metrics: &Arc<ServerMetrics>,
+	shutdown: &Arc<ShutdownSignal>,
+) -> Result<(ConnectionContext, ConnectionGuard), Error>
+where
+	for<'s> SockRef<'s>: From<&'s S>,
+{
+	let context = ConnectionContext::new(peer_addr.clone(), local_addr?, shutdown, metrics);
+	info!(connection: context.id(), "Client connected: {peer_addr} on {}", context.local_addr());
+	let guard = connections.track(&context, stream)?;
+	metrics.opened();
+	Ok((context, guard))
+}
 
-	match socket_type.as_str() {
-		"tcp" => Type::Tcp,
-		"udp" => Type::Udp,
-		_ => Type::None,
+// a connection over the limits of its source address is closed as soon as it is dropped
+fn admit_connection(
+	addr: &Address,
+	limiter: &SourceLimiter,
+	connections: &ConnectionTracker,
+	metrics: &ServerMetrics,
+) -> bool {
+	let Some(source) = limits::source_ip(addr)
+	else {
+		return true;
+	};
+	if let Err(refusal) = limiter.check_connection(source, connections.open_from(source)) {
+		metrics.limited(refusal);
+		return false;
 	}
+	true
 }
 
-fn select_udp_problem_from_args() -> UdpProblem {
-	let mut problems = HashMap::from(*UDP_PROBLEMS);
-	problems
-		.remove(env::args().nth(2).unwrap_or_default().to_lowercase().as_str())
-		.unwrap_or(UdpProblem::None)
+fn admit_datagram(addr: &Address, limiter: &SourceLimiter, metrics: &DatagramMetrics) -> bool {
+	let Some(source) = limits::source_ip(addr)
+	else {
+		return true;
+	};
+	if let Err(refusal) = limiter.check_datagram(source) {
+		metrics.limited(refusal);
+		return false;
+	}
+	true
 }
 
-fn select_tcp_problem_from_args() -> TcpProblem {
-	let mut problems = HashMap::from(*TCP_PROBLEMS);
-	problems
-		.remove(
-			env::args()
-				.nth(2)
-				.unwrap_or_default()
-				.to_lowercase()
-				.replace('_', "")
-				.as_str(),
-		)
-		.unwrap_or(TcpProblem::None)
+fn reject_connection(
+	mut socket: &Socket,
+	addr: &Address,
+	response: Option<&[u8]>,
+	overflow: Overflow,
+	metrics: &ServerMetrics,
+) {
+	metrics.rejected(overflow);
+	warning!(
+		"({addr}) Server busy, connection closed by the {} overflow policy",
+		overflow.name()
+	);
+	if overflow == Overflow::Reject {
+		if let Some(response) = response {
+			// the send buffer of a new connection is empty, so the short write does not hold up the accept loop
+			if let Err(e) = socket.write_all(response) {
+				debug!("({addr}) Unable to send busy response: {e}");
+			}
+		}
+	}
+	// the client may already be gone
+	let _result = socket.shutdown(Shutdown::Both);
+}
+
+fn report_handler_error(context: &ConnectionContext, error: &Error) {
+	context.metrics().handler_error(error);
+	if error.is::<HandlerPanic>() {
+		error!(connection: context.id(), "{error}");
+	}
+	else if context.is_timed_out() {
+		debug!(connection: context.id(), "{error}, after the connection timed out");
+	}
+	else {
+		warning!(connection: context.id(), "{error}");
+	}
+}
+
+fn close_connection(context: &ConnectionContext, guard: ConnectionGuard) {
+	record_disconnect(context);
+	drop(guard);
 }
 
-fn concurrency_from_environment() -> Result<NonZeroUsize, Error> {
-	let concurrency = env::var("CONCURRENCY")
-		.unwrap_or_else(|_| String::from("10"))
-		.parse::<usize>()
-		.map_err(|_e| anyhow!("Environment variable CONCURRENCY must be a positive integer"))?;
+fn record_disconnect(context: &ConnectionContext) {
+	info!(
+		connection: context.id(),
+		"Client disconnected: {} after {:.1?}, {} bytes read, {} bytes written",
+		context.peer_addr(),
+		context.elapsed(),
+		context.bytes_read(),
+		context.bytes_written()
+	);
+	context.metrics().closed(context.elapsed());
+}
+
+fn report_drain(name: &str, report: DrainReport) {
+	info!(
+		"Stopped {name}: {} connections drained, {} connections killed",
+		report.drained, report.killed
+	);
+}
 
-	if concurrency < 1 {
-		return Err(anyhow!("Environment variable CONCURRENCY must be a positive integer"));
+fn stop_on_error(result: Result<(), Error>, shutdown: &ShutdownSignal) -> Result<(), Error> {
+	if result.is_err() {
+		shutdown.request();
 	}
+	result
+}
+
+fn first_error(results: impl Iterator<Item = Result<(), Error>>) -> Result<(), Error> {
+	let mut first = Ok(());
+	for result in results {
+		match result {
+			Err(e) if first.is_ok() => first = Err(e),
+			Err(e) => error!("{}", e),
+			Ok(()) => {},
+		}
+	}
+	first
+}
 
-	Ok(NonZeroUsize::new(concurrency).unwrap())
+fn async_runtime(threads: Option<NonZeroUsize>) -> Result<Runtime, Error> {
+	let mut builder = Builder::new_multi_thread();
+	if let Some(threads) = threads {
+		let _builder = builder.worker_threads(threads.get());
+	}
+	builder.enable_all().build().map_err(Error::from)
+}
+
+fn event_poll(shutdown: &ShutdownSignal) -> Result<Poll, Error> {
+	let poll = Poll::new()?;
+	shutdown.wake_on_request(Waker::new(poll.registry(), WAKE_TOKEN)?);
+	Ok(poll)
+}
+
+fn wait_for_events(poll: &mut Poll, events: &mut Events) -> Result<(), Error> {
+	match poll.poll(events, None) {
+		// a signal interrupted the wait, the events are empty and the shutdown flag is checked by the caller
+		Err(ref err) if err.kind() == ErrorKind::Interrupted => Ok(()),
+		result => result.map_err(Error::from),
+	}
 }
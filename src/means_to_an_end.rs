@@ -1,77 +1,394 @@
 use std::{
-	io::{ErrorKind, Read, Write},
-	net::{Shutdown, TcpStream},
-	time::Duration,
+	io::{BufRead, Write},
+	net::Shutdown,
+	time::Instant,
 };
 
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Result};
+use tokio::{io::AsyncWriteExt, select};
 
-use crate::{handler::TcpHandler, utils::data_to_hex};
+use crate::{
+	address::Address,
+	binary_codec::{self, message, Decoder, Field, FieldError, Frame, FrameCodec},
+	client::{self, Load, LoadReport, ProblemClient},
+	context::ConnectionContext,
+	handler::{AsyncTcpHandler, HandlerFuture, TcpHandler},
+	logger::{debug, payload, trace},
+	registry::{Handler, Problem, Transport},
+	stream::{AsyncStream, Stream},
+	timeouts,
+};
+
+message! {
+	#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+	struct Insert {
+		timestamp: i32,
+		price: i32,
+	}
+}
+
+message! {
+	#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+	struct Query {
+		min_time: i32,
+		max_time: i32,
+	}
+}
+
+message! {
+	#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+	struct Mean {
+		mean: i32,
+	}
+}
+
+// every request is nine bytes, a type byte and two i32 fields
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Request {
+	Insert(Insert),
+	Query(Query),
+}
+
+impl Frame for Request {
+	fn decode(decoder: &mut Decoder<'_>) -> Result<Self, FieldError> {
+		match decoder.read::<u8>("Request.type")? {
+			b'I' => Insert::decode(decoder).map(Self::Insert),
+			b'Q' => Query::decode(decoder).map(Self::Query),
+			op_type => {
+				Err(FieldError::invalid(
+					"Request.type",
+					format!("unknown type {op_type:#04x}"),
+				))
+			},
+		}
+	}
+
+	fn encode(&self, buffer: &mut Vec<u8>) -> Result<(), FieldError> {
+		match *self {
+			Self::Insert(ref insert) => {
+				b'I'.encode("Request.type", buffer)?;
+				insert.encode(buffer)
+			},
+			Self::Query(ref query) => {
+				b'Q'.encode("Request.type", buffer)?;
+				query.encode(buffer)
+			},
+		}
+	}
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn handle_request(context: &ConnectionContext, request: Request, values: &mut Vec<(i32, i32)>) -> Option<Mean> {
+	payload!(connection: context.id(), "Request: {request:?}");
+
+	match request {
+		Request::Insert(Insert { timestamp, price }) => {
+			values.push((timestamp, price));
+			debug!(connection: context.id(), "OP: I, Timestamp: {timestamp}, Amount: {price}");
+			None
+		},
+		Request::Query(Query { min_time, max_time }) => {
+			let mut average: f64 = 0.0;
+			let mut count = 0;
+			for &(time, value) in values.iter() {
+				if (min_time..=max_time).contains(&time) {
+					average = (f64::from(count) * average + (f64::from(value))) / (f64::from(count) + 1.0);
+					count += 1;
+				}
+			}
+			let mean = average.round() as i32;
+			debug!(connection: context.id(), "OP: Q, Start: {min_time}, End: {max_time}, Mean: {mean}");
+			Some(Mean { mean })
+		},
+	}
+}
+
+pub(crate) const PROBLEM: Problem = Problem {
+	name: "meanstoanend",
+	aliases: &["2", "means"],
+	transport: Transport::Tcp,
+	description: "Query the mean of inserted timestamped prices (problem 2)",
+	options: &[],
+	timeouts: &timeouts::options("60s", "10s", "600s"),
+	create: |_| Ok(Handler::tcp(MeansToAnEnd::new())),
+	client: ProblemClient {
+		interactive: client,
+		load,
+		requests: "I <timestamp> <price> to insert a price, Q <mintime> <maxtime> to query the mean",
+	},
+};
+
+fn client_request(line: &str) -> Result<Request> {
+	let mut fields = line.split_whitespace();
+	let op_type = fields.next();
+	let mut field = |name: &str| {
+		fields
+			.next()
+			.ok_or_else(|| anyhow!("Missing {name}"))?
+			.parse::<i32>()
+			.map_err(|e| anyhow!("Invalid {name}: {e}"))
+	};
+	let request = match op_type {
+		Some("I" | "i") => {
+			Request::Insert(Insert {
+				timestamp: field("timestamp")?,
+				price: field("price")?,
+			})
+		},
+		Some("Q" | "q") => {
+			Request::Query(Query {
+				min_time: field("mintime")?,
+				max_time: field("maxtime")?,
+			})
+		},
+		_ => return Err(anyhow!("Expected I <timestamp> <price> or Q <mintime> <maxtime>")),
+	};
+	if fields.next().is_some() {
+		return Err(anyhow!("Expected I <timestamp> <price> or Q <mintime> <maxtime>"));
+	}
+	Ok(request)
+}
+
+fn client(address: &Address, input: &mut dyn BufRead, output: &mut (dyn Write + Send)) -> Result<()> {
+	let mut stream = client::connect(address)?;
+	let mut frames = FrameCodec::new();
+	for line in input.lines() {
+		let request = match client_request(&line?) {
+			Ok(request) => request,
+			Err(e) => {
+				writeln!(output, "{e}")?;
+				continue;
+			},
+		};
+		stream.write_all(&binary_codec::encode(&request)?)?;
+		if let Request::Query(_) = request {
+			let Some(Mean { mean }) = frames.read_frame(&mut stream)?
+			else {
+				writeln!(output, "Connection closed by the server")?;
+				break;
+			};
+			writeln!(output, "{mean}")?;
+		}
+	}
+	Ok(())
+}
+
+// every request inserts the next price and queries the mean of the prices inserted
+fn load(address: &Address, load: &Load) -> LoadReport {
+	client::load_connections(load, |_, samples| {
+		let mut stream = client::connect(address)?;
+		let mut frames = FrameCodec::new();
+		for request in 0..load.requests.get() {
+			let value = i32::try_from(request)?;
+			let started = Instant::now();
+			let mut data = binary_codec::encode(&Request::Insert(Insert {
+				timestamp: value,
+				price: value,
+			}))?;
+			data.extend(binary_codec::encode(&Request::Query(Query {
+				min_time: 0,
+				max_time: value,
+			}))?);
+			stream.write_all(&data)?;
+			let Some(Mean { mean }) = frames.read_frame(&mut stream)?
+			else {
+				return Err(anyhow!("Connection closed by the server"));
+			};
+			// the mean of 0 to value is a half when value is odd, which the server may round either way
+			if (2 * i64::from(mean) - i64::from(value)).abs() <= 1 {
+				samples.responded(started.elapsed());
+			}
+			else {
+				samples.failed();
+			}
+		}
+		Ok(())
+	})
+}
 
 #[derive(Debug, Clone)]
 pub(crate) struct MeansToAnEnd;
 
 impl MeansToAnEnd {
-	pub(crate) fn new() -> Self {
+	pub(crate) const fn new() -> Self {
 		Self {}
 	}
 }
 
 impl TcpHandler for MeansToAnEnd {
-	#[allow(clippy::cast_possible_truncation)]
-	fn handler(&self, mut stream: TcpStream, id: u32) -> Result<()> {
-		stream.set_read_timeout(Some(Duration::from_millis(60000)))?;
-		let mut data_read = false;
+	fn handler(&self, stream: Box<dyn Stream>, context: ConnectionContext) -> Result<()> {
+		let mut stream = context.counted(stream);
+		let mut frames = FrameCodec::new();
 		let mut values = vec![];
 
-		'main: loop {
-			let mut buffer = [0; 9];
-			eprintln!("({id}) Reading data");
-			match stream.read_exact(&mut buffer) {
-				Ok(_) => {
-					data_read = true;
+		loop {
+			trace!(connection: context.id(), "Reading data");
+			// the end of the stream is the client closing the connection, or a close by a timeout or on shutdown
+			let request = match frames.read_frame(&mut stream) {
+				Ok(Some(request)) => request,
+				Ok(None) => break,
+				Err(err) if err.is::<FieldError>() => {
+					debug!(connection: context.id(), "Ignoring request: {err}");
+					break;
 				},
-				Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
-					if data_read {
-						break 'main;
-					}
-					continue;
-				},
-				Err(err) => {
-					eprintln!("{}", err);
-					return Err(Error::from(err));
-				},
-			}
-			eprintln!("({id}) Buffer: {}", data_to_hex(&buffer));
-
-			let op_type = buffer[0];
-			let first = i32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
-			let second = i32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
-			if op_type == b'I' {
-				values.push((first, second));
-				eprintln!("({id}) OP: I, Timestamp: {first}, Amount: {second}");
-			}
-			else if op_type == b'Q' {
-				let mut average: f64 = 0.0;
-				let mut count = 0;
-				for &(time, value) in &values {
-					if (first..=second).contains(&time) {
-						average = (f64::from(count) * average + (f64::from(value))) / (f64::from(count) + 1.0);
-						count += 1;
-					}
-				}
-				let mean = average.round() as i32;
-				eprintln!("({id}) OP: Q, Start: {first}, End: {second}, Mean: {mean}");
-				stream.write_all(&mean.to_be_bytes())?;
-			}
-			else {
-				eprintln!("({id}) Ignoring Op: {op_type}");
-				break;
+				Err(err) => return Err(err),
+			};
+
+			if let Some(mean) = handle_request(&context, request, &mut values) {
+				stream.write_all(&binary_codec::encode(&mean)?)?;
 			}
 		}
-		eprintln!("({id}) Shutting down");
+		debug!(connection: context.id(), "Shutting down");
 		stream.flush()?;
-		stream.shutdown(Shutdown::Read)?;
+		stream.get_ref().shutdown(Shutdown::Read)?;
 		Ok(())
 	}
 }
+
+impl AsyncTcpHandler for MeansToAnEnd {
+	fn handler(&self, stream: Box<dyn AsyncStream>, context: ConnectionContext) -> HandlerFuture<'_> {
+		Box::pin(async move {
+			let mut stream = context.counted(stream);
+			let mut frames = FrameCodec::new();
+			let mut values = vec![];
+
+			loop {
+				trace!(connection: context.id(), "Reading data");
+				let read = select! {
+					read = frames.read_frame_async(&mut stream) => read,
+					() = context.cancelled() => break,
+				};
+				let request = match read {
+					Ok(Some(request)) => request,
+					Ok(None) => break,
+					Err(err) if err.is::<FieldError>() => {
+						debug!(connection: context.id(), "Ignoring request: {err}");
+						break;
+					},
+					Err(err) => return Err(err),
+				};
+
+				if let Some(mean) = handle_request(&context, request, &mut values) {
+					stream.write_all(&binary_codec::encode(&mean)?).await?;
+				}
+			}
+			debug!(connection: context.id(), "Shutting down");
+			stream.flush().await?;
+			stream.get_ref().shutdown_socket(Shutdown::Read)?;
+			Ok(())
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Read;
+
+	use tokio::io::{duplex, AsyncReadExt};
+
+	use super::*;
+	use crate::stream::testing::{block_on, context, fragmented, pair};
+
+	fn message(op_type: u8, first: i32, second: i32) -> Vec<u8> {
+		let mut message = vec![op_type];
+		message.extend(first.to_be_bytes());
+		message.extend(second.to_be_bytes());
+		message
+	}
+
+	fn session() -> Vec<u8> {
+		[
+			message(b'I', 12345, 101),
+			message(b'I', 12346, 102),
+			message(b'I', 12347, 100),
+			message(b'I', 40000, 5),
+			message(b'Q', 12000, 16000),
+		]
+		.concat()
+	}
+
+	#[test]
+	fn insert_and_query() {
+		let (mut client, server) = pair();
+		client.write_all(&session()).unwrap();
+		client.shutdown(Shutdown::Write).unwrap();
+		TcpHandler::handler(&MeansToAnEnd::new(), Box::new(server), context()).unwrap();
+		let mut response = vec![];
+		let _size = client.read_to_end(&mut response).unwrap();
+		assert_eq!(response, 101_i32.to_be_bytes());
+	}
+
+	#[test]
+	fn insert_and_query_async() {
+		block_on(async {
+			let (mut client, server) = duplex(1024);
+			client.write_all(&session()).await.unwrap();
+			client.shutdown().await.unwrap();
+			AsyncTcpHandler::handler(&MeansToAnEnd::new(), Box::new(server), context())
+				.await
+				.unwrap();
+			let mut response = vec![];
+			let _size = client.read_to_end(&mut response).await.unwrap();
+			assert_eq!(response, 101_i32.to_be_bytes());
+		});
+	}
+
+	#[test]
+	fn fragmented_requests() {
+		// reads of two bytes split the fields of every request, and the last request is never completed
+		let (mut client, server) = fragmented(2);
+		client.write_all(&session()).unwrap();
+		client.write_all(&message(b'Q', 0, 0)[..5]).unwrap();
+		client.shutdown(Shutdown::Write).unwrap();
+		TcpHandler::handler(&MeansToAnEnd::new(), Box::new(server), context()).unwrap();
+		let mut response = vec![];
+		let _size = client.read_to_end(&mut response).unwrap();
+		assert_eq!(response, 101_i32.to_be_bytes());
+	}
+
+	#[test]
+	fn requests_encode_as_sent() {
+		let insert = Request::Insert(Insert {
+			timestamp: 12345,
+			price: 101,
+		});
+		assert_eq!(binary_codec::encode(&insert).unwrap(), message(b'I', 12345, 101));
+		assert_eq!(
+			Request::decode(&mut Decoder::new(&message(b'X', 1, 2)))
+				.unwrap_err()
+				.to_string(),
+			"Request.type: unknown type 0x58"
+		);
+	}
+
+	#[test]
+	fn client_requests() {
+		assert_eq!(
+			client_request("I 12345 101").unwrap(),
+			Request::Insert(Insert {
+				timestamp: 12345,
+				price: 101
+			})
+		);
+		assert_eq!(
+			client_request("q -5 40000").unwrap(),
+			Request::Query(Query {
+				min_time: -5,
+				max_time: 40000
+			})
+		);
+		assert_eq!(client_request("Q 1").unwrap_err().to_string(), "Missing maxtime");
+		assert_eq!(
+			client_request("I 1 x").unwrap_err().to_string(),
+			"Invalid price: invalid digit found in string"
+		);
+		assert_eq!(
+			client_request("X 1 2").unwrap_err().to_string(),
+			"Expected I <timestamp> <price> or Q <mintime> <maxtime>"
+		);
+		assert_eq!(
+			client_request("I 1 2 3").unwrap_err().to_string(),
+			"Expected I <timestamp> <price> or Q <mintime> <maxtime>"
+		);
+	}
+}
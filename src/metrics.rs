@@ -0,0 +1,562 @@
+use std::{
+	collections::BTreeMap,
+	fmt::{self, Write as _},
+	io::{self, BufRead, BufReader, Write},
+	iter,
+	net::{SocketAddr, TcpListener, TcpStream},
+	sync::{
+		atomic::{AtomicI64, AtomicU64, Ordering},
+		Arc,
+	},
+	thread,
+	time::Duration,
+};
+
+use anyhow::Error;
+use parking_lot::{const_mutex, Mutex};
+
+use crate::{
+	handler::HandlerPanic,
+	limits::Refusal,
+	logger::{info, warning},
+	thread_pool::{Overflow, ThreadPool},
+	timeouts::Timeout,
+};
+
+const CONNECTION_DURATION_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0];
+
+#[derive(Debug, Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+	pub(crate) fn add(&self, value: u64) {
+		let _previous = self.0.fetch_add(value, Ordering::Relaxed);
+	}
+
+	pub(crate) fn increment(&self) {
+		self.add(1);
+	}
+
+	fn get(&self) -> u64 {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Gauge(AtomicI64);
+
+impl Gauge {
+	pub(crate) fn increment(&self) {
+		let _previous = self.0.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub(crate) fn decrement(&self) {
+		let _previous = self.0.fetch_sub(1, Ordering::Relaxed);
+	}
+
+	fn get(&self) -> i64 {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+#[derive(Debug)]
+pub(crate) struct Histogram {
+	bounds: &'static [f64],
+	// counts per bucket, the last entry counts observations above every bound
+	buckets: Vec<AtomicU64>,
+	sum_micros: AtomicU64,
+}
+
+impl Histogram {
+	fn new(bounds: &'static [f64]) -> Self {
+		Self {
+			bounds,
+			buckets: iter::repeat_with(AtomicU64::default).take(bounds.len() + 1).collect(),
+			sum_micros: AtomicU64::new(0),
+		}
+	}
+
+	pub(crate) fn observe(&self, duration: Duration) {
+		let seconds = duration.as_secs_f64();
+		let index = self
+			.bounds
+			.iter()
+			.position(|&bound| seconds <= bound)
+			.unwrap_or(self.bounds.len());
+		let _previous = self.buckets[index].fetch_add(1, Ordering::Relaxed);
+		let _previous = self.sum_micros.fetch_add(
+			u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+			Ordering::Relaxed,
+		);
+	}
+}
+
+type Sample = Box<dyn Fn() -> u64 + Send>;
+type Key = (&'static str, String);
+
+// series are keyed by metric name and rendered labels, so a family is rendered in one contiguous block
+struct Registry {
+	help: BTreeMap<&'static str, &'static str>,
+	counters: BTreeMap<Key, Arc<Counter>>,
+	gauges: BTreeMap<Key, Arc<Gauge>>,
+	// every instance of a problem adds its own sample to a series, a problem served on several ports for one
+	samples: BTreeMap<Key, Vec<(u64, Sample)>>,
+	next_sample: u64,
+	histograms: BTreeMap<Key, Arc<Histogram>>,
+}
+
+static REGISTRY: Mutex<Registry> = const_mutex(Registry {
+	help: BTreeMap::new(),
+	counters: BTreeMap::new(),
+	gauges: BTreeMap::new(),
+	samples: BTreeMap::new(),
+	next_sample: 0,
+	histograms: BTreeMap::new(),
+});
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+	let mut rendered = String::new();
+	for &(name, value) in labels {
+		if !rendered.is_empty() {
+			rendered.push(',');
+		}
+		let _result = write!(rendered, "{name}=\"");
+		for c in value.chars() {
+			match c {
+				'"' => rendered.push_str("\\\""),
+				'\\' => rendered.push_str("\\\\"),
+				'\n' => rendered.push_str("\\n"),
+				c => rendered.push(c),
+			}
+		}
+		rendered.push('"');
+	}
+	rendered
+}
+
+pub(crate) fn counter(name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Arc<Counter> {
+	let mut registry = REGISTRY.lock();
+	let _help = registry.help.insert(name, help);
+	Arc::clone(registry.counters.entry((name, render_labels(labels))).or_default())
+}
+
+pub(crate) fn gauge(name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Arc<Gauge> {
+	let mut registry = REGISTRY.lock();
+	let _help = registry.help.insert(name, help);
+	Arc::clone(registry.gauges.entry((name, render_labels(labels))).or_default())
+}
+
+// a sample of a gauge that is removed from the registry when dropped
+#[derive(Debug)]
+#[must_use]
+pub(crate) struct SampledGauge {
+	key: Key,
+	id: u64,
+}
+
+impl Drop for SampledGauge {
+	fn drop(&mut self) {
+		let mut registry = REGISTRY.lock();
+		if let Some(samples) = registry.samples.get_mut(&self.key) {
+			samples.retain(|&(id, _)| id != self.id);
+			if samples.is_empty() {
+				let _removed = registry.samples.remove(&self.key);
+			}
+		}
+	}
+}
+
+// a gauge read when the metrics are rendered, added to the other samples of the same series
+pub(crate) fn sampled_gauge<F>(
+	name: &'static str,
+	help: &'static str,
+	labels: &[(&str, &str)],
+	sample: F,
+) -> SampledGauge
+where
+	F: Fn() -> u64 + Send + 'static,
+{
+	let mut registry = REGISTRY.lock();
+	let _help = registry.help.insert(name, help);
+	let id = registry.next_sample;
+	registry.next_sample += 1;
+	let key = (name, render_labels(labels));
+	registry
+		.samples
+		.entry(key.clone())
+		.or_default()
+		.push((id, Box::new(sample)));
+	SampledGauge { key, id }
+}
+
+pub(crate) fn histogram(
+	name: &'static str,
+	help: &'static str,
+	labels: &[(&str, &str)],
+	bounds: &'static [f64],
+) -> Arc<Histogram> {
+	let mut registry = REGISTRY.lock();
+	let _help = registry.help.insert(name, help);
+	Arc::clone(
+		registry
+			.histograms
+			.entry((name, render_labels(labels)))
+			.or_insert_with(|| Arc::new(Histogram::new(bounds))),
+	)
+}
+
+fn write_series<V: fmt::Display>(
+	out: &mut String,
+	help: &BTreeMap<&'static str, &'static str>,
+	kind: &str,
+	series: impl Iterator<Item = (&'static str, String, V)>,
+) {
+	let mut family = "";
+	for (name, labels, value) in series {
+		if name != family {
+			family = name;
+			let _result = writeln!(out, "# HELP {name} {}", help.get(name).unwrap_or(&""));
+			let _result = writeln!(out, "# TYPE {name} {kind}");
+		}
+		if labels.is_empty() {
+			let _result = writeln!(out, "{name} {value}");
+		}
+		else {
+			let _result = writeln!(out, "{name}{{{labels}}} {value}");
+		}
+	}
+}
+
+fn with_label(labels: &str, label: &str) -> String {
+	if labels.is_empty() {
+		String::from(label)
+	}
+	else {
+		format!("{labels},{label}")
+	}
+}
+
+pub(crate) fn render() -> String {
+	let registry = REGISTRY.lock();
+	let mut out = String::new();
+
+	write_series(
+		&mut out,
+		&registry.help,
+		"counter",
+		registry
+			.counters
+			.iter()
+			.map(|(&(name, ref labels), counter)| (name, labels.clone(), counter.get())),
+	);
+	write_series(
+		&mut out,
+		&registry.help,
+		"gauge",
+		registry
+			.gauges
+			.iter()
+			.map(|(&(name, ref labels), gauge)| (name, labels.clone(), gauge.get())),
+	);
+	write_series(
+		&mut out,
+		&registry.help,
+		"gauge",
+		registry.samples.iter().map(|(&(name, ref labels), samples)| {
+			(
+				name,
+				labels.clone(),
+				samples.iter().map(|(_, sample)| sample()).sum::<u64>(),
+			)
+		}),
+	);
+
+	let mut family = "";
+	for (&(name, ref labels), histogram) in &registry.histograms {
+		if name != family {
+			family = name;
+			let _result = writeln!(out, "# HELP {name} {}", registry.help.get(name).unwrap_or(&""));
+			let _result = writeln!(out, "# TYPE {name} histogram");
+		}
+		let mut count = 0;
+		for (index, bucket) in histogram.buckets.iter().enumerate() {
+			count += bucket.load(Ordering::Relaxed);
+			let bound = histogram
+				.bounds
+				.get(index)
+				.map_or_else(|| String::from("+Inf"), ToString::to_string);
+			let _result = writeln!(
+				out,
+				"{name}_bucket{{{}}} {count}",
+				with_label(labels, format!("le=\"{bound}\"").as_str())
+			);
+		}
+		let braced = if labels.is_empty() {
+			String::new()
+		}
+		else {
+			format!("{{{labels}}}")
+		};
+		let sum = Duration::from_micros(histogram.sum_micros.load(Ordering::Relaxed)).as_secs_f64();
+		let _result = writeln!(out, "{name}_sum{braced} {sum}");
+		let _result = writeln!(out, "{name}_count{braced} {count}");
+	}
+	out
+}
+
+// the metrics for one problem server, shared by all of its connections
+#[derive(Debug)]
+pub(crate) struct ServerMetrics {
+	problem: String,
+	accepted: Arc<Counter>,
+	active: Arc<Gauge>,
+	bytes_read: Arc<Counter>,
+	bytes_written: Arc<Counter>,
+	duration: Arc<Histogram>,
+}
+
+impl ServerMetrics {
+	pub(crate) fn new(problem: &str) -> Arc<Self> {
+		let labels = [("problem", problem)];
+		Arc::new(Self {
+			problem: String::from(problem),
+			accepted: counter(
+				"protohackers_connections_accepted_total",
+				"Connections accepted",
+				&labels,
+			),
+			active: gauge("protohackers_connections_active", "Connections currently open", &labels),
+			bytes_read: bytes_read_counter(problem),
+			bytes_written: counter(
+				"protohackers_bytes_written_total",
+				"Bytes written to connections",
+				&labels,
+			),
+			duration: histogram(
+				"protohackers_connection_duration_seconds",
+				"Time connections were open",
+				&labels,
+				CONNECTION_DURATION_BUCKETS,
+			),
+		})
+	}
+
+	pub(crate) fn opened(&self) {
+		self.accepted.increment();
+		self.active.increment();
+	}
+
+	pub(crate) fn closed(&self, open_for: Duration) {
+		self.active.decrement();
+		self.duration.observe(open_for);
+	}
+
+	pub(crate) fn add_read(&self, size: usize) {
+		self.bytes_read.add(size as u64);
+	}
+
+	pub(crate) fn add_written(&self, size: usize) {
+		self.bytes_written.add(size as u64);
+	}
+
+	pub(crate) fn handler_error(&self, error: &Error) {
+		count_handler_error(self.problem.as_str(), error);
+	}
+
+	pub(crate) fn rejected(&self, overflow: Overflow) {
+		counter(
+			"protohackers_connections_rejected_total",
+			"Connections closed because the thread pool queue was full",
+			&[("problem", self.problem.as_str()), ("policy", overflow.name())],
+		)
+		.increment();
+	}
+
+	pub(crate) fn limited(&self, refusal: Refusal) {
+		counter(
+			"protohackers_connections_limited_total",
+			"Connections refused by the limits on a single source address",
+			&[("problem", self.problem.as_str()), ("limit", refusal.name())],
+		)
+		.increment();
+	}
+
+	pub(crate) fn timed_out(&self, timeout: Timeout) {
+		counter(
+			"protohackers_connections_timed_out_total",
+			"Connections closed by a connection timeout",
+			&[("problem", self.problem.as_str()), ("timeout", timeout.name())],
+		)
+		.increment();
+	}
+
+	// the pool is watched until the returned gauges are dropped
+	pub(crate) fn watch_pool(&self, pool: &ThreadPool) -> [SampledGauge; 3] {
+		let labels = [("problem", self.problem.as_str())];
+		let stats = pool.stats();
+		let queue_depth = sampled_gauge(
+			"protohackers_thread_pool_queue_depth",
+			"Connections waiting for a thread pool worker",
+			&labels,
+			move || stats.queued() as u64,
+		);
+		let stats = pool.stats();
+		let workers = sampled_gauge(
+			"protohackers_thread_pool_workers",
+			"Thread pool workers running",
+			&labels,
+			move || stats.workers() as u64,
+		);
+		let stats = pool.stats();
+		let busy_workers = sampled_gauge(
+			"protohackers_thread_pool_busy_workers",
+			"Thread pool workers running a connection",
+			&labels,
+			move || stats.busy() as u64,
+		);
+		[queue_depth, workers, busy_workers]
+	}
+}
+
+// the metrics for one UDP problem server, which has no connections and writes from within the handlers
+#[derive(Debug)]
+pub(crate) struct DatagramMetrics {
+	problem: String,
+	bytes_read: Arc<Counter>,
+}
+
+impl DatagramMetrics {
+	pub(crate) fn new(problem: &str) -> Self {
+		Self {
+			problem: String::from(problem),
+			bytes_read: bytes_read_counter(problem),
+		}
+	}
+
+	pub(crate) fn add_read(&self, size: usize) {
+		self.bytes_read.add(size as u64);
+	}
+
+	pub(crate) fn handler_error(&self, error: &Error) {
+		count_handler_error(self.problem.as_str(), error);
+	}
+
+	pub(crate) fn limited(&self, refusal: Refusal) {
+		counter(
+			"protohackers_datagrams_limited_total",
+			"Datagrams dropped by the limits on a single source address",
+			&[("problem", self.problem.as_str()), ("limit", refusal.name())],
+		)
+		.increment();
+	}
+}
+
+fn bytes_read_counter(problem: &str) -> Arc<Counter> {
+	counter(
+		"protohackers_bytes_read_total",
+		"Bytes read from connections and datagrams",
+		&[("problem", problem)],
+	)
+}
+
+fn count_handler_error(problem: &str, error: &Error) {
+	counter("protohackers_handler_errors_total", "Handler errors by kind", &[
+		("problem", problem),
+		("kind", error_kind(error).as_str()),
+	])
+	.increment();
+}
+
+// io errors are labelled by their kind in snake case, e.g. timed_out, panics as panic and anything else as other
+fn error_kind(error: &Error) -> String {
+	if error.is::<HandlerPanic>() {
+		return String::from("panic");
+	}
+	error.downcast_ref::<io::Error>().map_or_else(
+		|| String::from("other"),
+		|err| {
+			let mut kind = String::new();
+			for c in format!("{:?}", err.kind()).chars() {
+				if c.is_uppercase() && !kind.is_empty() {
+					kind.push('_');
+				}
+				kind.push(c.to_ascii_lowercase());
+			}
+			kind
+		},
+	)
+}
+
+// serves the metrics over HTTP on a background thread, that stops with the process
+pub(crate) fn serve(address: SocketAddr) -> Result<(), Error> {
+	let listener = TcpListener::bind(address)?;
+	info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+	let _handle = thread::Builder::new().name(String::from("metrics")).spawn(move || {
+		for stream in listener.incoming() {
+			if let Err(e) = stream.map_err(Error::from).and_then(respond) {
+				warning!("Metrics request failed: {e}");
+			}
+		}
+	})?;
+	Ok(())
+}
+
+fn respond(mut stream: TcpStream) -> Result<(), Error> {
+	stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+	let mut reader = BufReader::new(&stream);
+	let mut request = String::new();
+	let _size = reader.read_line(&mut request)?;
+
+	// read the headers, so the request is fully consumed before the connection is closed
+	let mut header = String::new();
+	while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+		header.clear();
+	}
+
+	let mut parts = request.split_whitespace();
+	let (status, body) = match (parts.next(), parts.next().and_then(|path| path.split('?').next())) {
+		(Some("GET"), Some("/metrics")) => ("200 OK", render()),
+		(Some("GET"), _) => {
+			(
+				"404 Not Found",
+				String::from("Not found, metrics are served on /metrics\n"),
+			)
+		},
+		_ => ("405 Method Not Allowed", String::from("Method not allowed\n")),
+	};
+
+	write!(
+		stream,
+		"HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: \
+		 close\r\n\r\n{body}",
+		body.len()
+	)?;
+	stream.flush()?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sampled(name: &str) -> Option<String> {
+		render().lines().find(|line| line.starts_with(name)).map(String::from)
+	}
+
+	#[test]
+	fn samples_of_a_series_are_added() {
+		let first = sampled_gauge("protohackers_test_samples", "Test samples", &[], || 2);
+		let second = sampled_gauge("protohackers_test_samples", "Test samples", &[], || 3);
+		assert_eq!(
+			sampled("protohackers_test_samples").unwrap(),
+			"protohackers_test_samples 5"
+		);
+		drop(first);
+		assert_eq!(
+			sampled("protohackers_test_samples").unwrap(),
+			"protohackers_test_samples 3"
+		);
+		drop(second);
+		assert_eq!(sampled("protohackers_test_samples"), None);
+	}
+}
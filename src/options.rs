@@ -0,0 +1,195 @@
+use std::{collections::HashMap, num::NonZeroUsize, time::Duration};
+
+use anyhow::{anyhow, Error};
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum OptionKind {
+	// a duration, or 0 or off for none
+	Timeout,
+	Size,
+	Count,
+	Choice(&'static [&'static str]),
+}
+
+impl OptionKind {
+	fn validate(self, value: &str) -> Result<(), Error> {
+		match self {
+			Self::Timeout => parse_timeout(value).map(|_| ()),
+			Self::Size => parse_size(value).map(|_| ()),
+			Self::Count => parse_count(value).map(|_| ()),
+			Self::Choice(choices) => {
+				if choices.contains(&value) {
+					Ok(())
+				}
+				else {
+					Err(anyhow!("'{value}' must be one of: {}", choices.join(", ")))
+				}
+			},
+		}
+	}
+
+	pub(crate) fn hint(self) -> String {
+		match self {
+			Self::Timeout => String::from("<duration|off>"),
+			Self::Size => String::from("<size>"),
+			Self::Count => String::from("<count>"),
+			Self::Choice(choices) => format!("<{}>", choices.join("|")),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub(crate) struct ProblemOption {
+	pub(crate) name: &'static str,
+	pub(crate) kind: OptionKind,
+	pub(crate) default: &'static str,
+	pub(crate) description: &'static str,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Options {
+	values: HashMap<&'static str, String>,
+}
+
+impl Options {
+	pub(crate) fn resolve(
+		problem: &str,
+		declared: &[&'static ProblemOption],
+		provided: &[(String, String)],
+	) -> Result<Self, Error> {
+		let mut values = HashMap::new();
+
+		for option in declared {
+			let _prev = values.insert(option.name, String::from(option.default));
+		}
+
+		for (name, value) in provided {
+			let option = declared.iter().find(|option| option.name == name).ok_or_else(|| {
+				anyhow!(
+					"Unknown option '{name}' for {problem}, available options: {}",
+					available_options(declared)
+				)
+			})?;
+			option
+				.kind
+				.validate(value.as_str())
+				.map_err(|e| anyhow!("Invalid value for option {problem}.{name}: {e}"))?;
+			let _prev = values.insert(option.name, value.clone());
+		}
+
+		Ok(Self { values })
+	}
+
+	fn value(&self, name: &str) -> Result<&str, Error> {
+		self.values
+			.get(name)
+			.map(String::as_str)
+			.ok_or_else(|| anyhow!("Option {name} is not defined"))
+	}
+
+	pub(crate) fn timeout(&self, name: &str) -> Result<Option<Duration>, Error> {
+		parse_timeout(self.value(name)?)
+	}
+
+	pub(crate) fn size(&self, name: &str) -> Result<NonZeroUsize, Error> {
+		parse_size(self.value(name)?)
+	}
+
+	pub(crate) fn count(&self, name: &str) -> Result<usize, Error> {
+		parse_count(self.value(name)?)
+	}
+
+	pub(crate) fn choice(&self, name: &str) -> Result<&str, Error> {
+		self.value(name)
+	}
+}
+
+fn available_options(declared: &[&ProblemOption]) -> String {
+	if declared.is_empty() {
+		return String::from("none");
+	}
+	declared
+		.iter()
+		.map(|option| option.name)
+		.collect::<Vec<&str>>()
+		.join(", ")
+}
+
+pub(crate) fn parse_duration(value: &str) -> Result<Duration, Error> {
+	let (number, millis_per_unit) = if let Some(number) = value.strip_suffix("ms") {
+		(number, 1)
+	}
+	else {
+		(value.strip_suffix('s').unwrap_or(value), 1000)
+	};
+
+	let not_a_duration = || anyhow!("'{value}' is not a duration, expected seconds or milliseconds, e.g. 5s or 500ms");
+	let number = number.parse::<u64>().map_err(|_e| not_a_duration())?;
+
+	if number == 0 {
+		return Err(anyhow!("'{value}' must be greater than zero"));
+	}
+
+	let millis = number.checked_mul(millis_per_unit).ok_or_else(not_a_duration)?;
+	Ok(Duration::from_millis(millis))
+}
+
+pub(crate) fn parse_timeout(value: &str) -> Result<Option<Duration>, Error> {
+	if value == "0" || value == "off" {
+		return Ok(None);
+	}
+	parse_duration(value).map(Some)
+}
+
+// a duration in the form parse_duration accepts
+pub(crate) fn format_duration(duration: Duration) -> String {
+	if duration.subsec_millis() == 0 {
+		format!("{}s", duration.as_secs())
+	}
+	else {
+		format!("{}ms", duration.as_millis())
+	}
+}
+
+pub(crate) fn parse_size(value: &str) -> Result<NonZeroUsize, Error> {
+	value
+		.parse::<NonZeroUsize>()
+		.map_err(|_e| anyhow!("'{value}' must be a positive integer"))
+}
+
+pub(crate) fn parse_count(value: &str) -> Result<usize, Error> {
+	value
+		.parse::<usize>()
+		.map_err(|_e| anyhow!("'{value}' must be zero or a positive integer"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn durations() {
+		assert_eq!(parse_duration("5s").unwrap(), Duration::from_secs(5));
+		assert_eq!(parse_duration("5").unwrap(), Duration::from_secs(5));
+		assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+	}
+
+	#[test]
+	fn timeouts() {
+		assert_eq!(parse_timeout("5s").unwrap(), Some(Duration::from_secs(5)));
+		assert_eq!(parse_timeout("0").unwrap(), None);
+		assert_eq!(parse_timeout("off").unwrap(), None);
+		assert_eq!(
+			parse_timeout("0ms").unwrap_err().to_string(),
+			"'0ms' must be greater than zero"
+		);
+	}
+
+	#[test]
+	fn duration_that_overflows() {
+		assert_eq!(
+			parse_duration("99999999999999999s").unwrap_err().to_string(),
+			"'99999999999999999s' is not a duration, expected seconds or milliseconds, e.g. 5s or 500ms"
+		);
+	}
+}
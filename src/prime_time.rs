@@ -1,15 +1,38 @@
 use std::{
-	io::{Read, Write},
+	io::{BufRead, BufReader, Write},
 	iter::Peekable,
-	net::{Shutdown, TcpStream},
+	net::Shutdown,
+	num::NonZeroUsize,
 	str::Chars,
-	time::Duration,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Result};
+use crossbeam::channel::never;
 use num::{BigUint, Integer, Zero};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+	address::Address,
+	client::{self, Load, LoadReport, ProblemClient},
+	context::ConnectionContext,
+	handler::{AsyncTcpHandler, HandlerFuture, TcpHandler},
+	job::JobHandle,
+	line_codec::{self, LineCodec, LineError, LineFormat},
+	logger::{debug, payload, trace, warning},
+	metrics,
+	options::{OptionKind, ProblemOption},
+	registry::{Handler, Problem, Transport},
+	stream::{AsyncStream, Stream},
+	thread_pool::{PoolSize, ThreadPool},
+	timeouts,
+};
 
-use crate::handler::TcpHandler;
+const PRIME_WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Eq, PartialEq)]
 struct Request {
@@ -17,6 +40,24 @@ struct Request {
 	number: String,
 }
 
+#[derive(Debug, Eq, PartialEq)]
+enum Number {
+	// negative and fractional numbers are never prime
+	NotWhole,
+	Small(u128),
+	Big(BigUint),
+}
+
+impl Number {
+	fn is_prime(&self) -> bool {
+		match *self {
+			Self::NotWhole => false,
+			Self::Small(number) => is_prime(number),
+			Self::Big(ref number) => is_prime_big_int(number),
+		}
+	}
+}
+
 #[derive(Debug, Eq, PartialEq)]
 enum ParseState {
 	Key,
@@ -58,6 +99,105 @@ fn is_prime_big_int(x: &BigUint) -> bool {
 	true
 }
 
+// the odd divisors of a big number split between a number of jobs, that all stop once any of them finds a divisor
+#[derive(Debug)]
+struct DivisorSearch {
+	number: BigUint,
+	limit: BigUint,
+	jobs: usize,
+	found: AtomicBool,
+	stopped: AtomicBool,
+}
+
+impl DivisorSearch {
+	fn new(number: &BigUint, jobs: usize) -> Arc<Self> {
+		Arc::new(Self {
+			number: number.clone(),
+			limit: number.sqrt(),
+			jobs,
+			found: AtomicBool::new(false),
+			stopped: AtomicBool::new(false),
+		})
+	}
+
+	// checks every odd number from 3 + 2 * job, stepping over the numbers checked by the other jobs
+	fn run(&self, job: usize) {
+		let step = BigUint::from(2 * self.jobs);
+		let mut next = BigUint::from(3 + 2 * job);
+		while next <= self.limit && !self.found.load(Ordering::Relaxed) && !self.stopped.load(Ordering::Relaxed) {
+			if self.number.mod_floor(&next).is_zero() {
+				self.found.store(true, Ordering::Relaxed);
+				return;
+			}
+			next += &step;
+		}
+	}
+
+	fn is_prime(&self) -> bool {
+		!self.found.load(Ordering::Relaxed)
+	}
+
+	fn stop(&self) {
+		self.stopped.store(true, Ordering::Relaxed);
+	}
+}
+
+// the jobs of a search, which are cancelled or stopped when a check is dropped before they finish, e.g. with its
+// connection, so they do not hold workers needed by other connections
+#[derive(Debug)]
+struct SearchJobs {
+	search: Arc<DivisorSearch>,
+	handles: Vec<JobHandle<()>>,
+}
+
+impl Drop for SearchJobs {
+	fn drop(&mut self) {
+		for handle in &self.handles {
+			let _cancelled = handle.cancel();
+		}
+		self.search.stop();
+	}
+}
+
+// the divisors are split between a job for every worker of the pool
+fn is_prime_big_int_parallel(x: &BigUint, pool: &ThreadPool) -> Result<bool> {
+	if x.is_even() {
+		return Ok(false);
+	}
+
+	let jobs = pool.max_workers();
+	let search = DivisorSearch::new(x, jobs);
+	let _finished = pool.scope(|scope| {
+		for job in 0..jobs {
+			let search = Arc::clone(&search);
+			scope.execute(move || search.run(job))?;
+		}
+		Ok(())
+	})?;
+	Ok(search.is_prime())
+}
+
+async fn is_prime_big_int_async(x: &BigUint, pool: &ThreadPool) -> Result<bool> {
+	if x.is_even() {
+		return Ok(false);
+	}
+
+	let jobs = pool.max_workers();
+	let search = DivisorSearch::new(x, jobs);
+	let mut search_jobs = SearchJobs {
+		search: Arc::clone(&search),
+		handles: vec![],
+	};
+	for job in 0..jobs {
+		let search = Arc::clone(&search);
+		search_jobs.handles.push(pool.execute(move || search.run(job))?);
+	}
+	for handle in &mut search_jobs.handles {
+		handle.await?;
+	}
+	Ok(search.is_prime())
+}
+
 fn skip_whitespace(chars: &mut Peekable<Chars<'_>>) {
 	while let Some(c) = chars.peek() {
 		if !c.is_whitespace() {
@@ -204,7 +344,39 @@ fn parse_json(line: &str) -> Result<Request> {
 	Ok(Request { method, number })
 }
 
-fn handle_request_data(request: Result<Request>) -> Result<String> {
+fn handle_request_data(request: Result<Request>, pool: Option<&ThreadPool>) -> Result<String> {
+	respond(is_prime_request(request, pool))
+}
+
+fn respond(prime: Result<bool>) -> Result<String> {
+	let result = match prime {
+		Ok(true) => "prime",
+		Ok(false) => "composite",
+		Err(_) => "malformed",
+	};
+	metrics::counter(
+		"protohackers_primetime_requests_total",
+		"Prime time requests by result",
+		&[("result", result)],
+	)
+	.increment();
+
+	prime.map(|prime| format!("{{\"method\": \"isPrime\", \"prime\": {}}}\n", prime))
+}
+
+fn is_prime_request(request: Result<Request>, pool: Option<&ThreadPool>) -> Result<bool> {
+	match (parse_number(request)?, pool) {
+		(Number::Big(number), Some(pool)) => {
+			Ok(is_prime_big_int_parallel(&number, pool).unwrap_or_else(|e| {
+				warning!("Parallel prime check failed, checking on the connection thread: {e}");
+				is_prime_big_int(&number)
+			}))
+		},
+		(number, _) => Ok(number.is_prime()),
+	}
+}
+
+fn parse_number(request: Result<Request>) -> Result<Number> {
 	let r = request?;
 	if r.method != "isPrime" {
 		return Err(anyhow!("Malformed request: invalid method {}", r.method));
@@ -214,77 +386,278 @@ fn handle_request_data(request: Result<Request>) -> Result<String> {
 		return Err(anyhow!("Malformed request: invalid number {}", r.number));
 	}
 
-	let prime = if r.number.contains('.') || r.number.contains('-') {
-		"false"
+	if r.number.contains('.') || r.number.contains('-') {
+		Ok(Number::NotWhole)
 	}
 	else if let Ok(number) = r.number.parse::<u128>() {
-		if is_prime(number) { "true" } else { "false" }
+		Ok(Number::Small(number))
 	}
 	else {
-		let number = BigUint::parse_bytes(r.number.as_bytes(), 10).unwrap();
-		if is_prime_big_int(&number) { "true" } else { "false" }
-	};
+		Ok(Number::Big(BigUint::parse_bytes(r.number.as_bytes(), 10).unwrap()))
+	}
+}
 
-	Ok(format!("{{\"method\": \"isPrime\", \"prime\": {}}}\n", prime))
+pub(crate) const PROBLEM: Problem = Problem {
+	name: "primetime",
+	aliases: &["1", "isprime"],
+	transport: Transport::Tcp,
+	description: "Respond to JSON isPrime requests (problem 1)",
+	options: &[
+		ProblemOption {
+			name: "prime-workers",
+			kind: OptionKind::Count,
+			default: "4",
+			description: "Workers checking a big number for divisors in parallel, 0 checks on the connection thread",
+		},
+		line_codec::max_length_option("1048576"),
+		line_codec::UTF8_OPTION,
+	],
+	timeouts: &timeouts::options("5s", "10s", "600s"),
+	create: |options| {
+		Ok(Handler::tcp(PrimeTime::new(
+			options.count("prime-workers")?,
+			LineFormat::from_options(options)?,
+		)))
+	},
+	client: ProblemClient {
+		interactive: client,
+		load,
+		requests: "a number, sent in an isPrime request, any other line is sent as it is",
+	},
+};
+
+// a number is sent in an isPrime request, anything else is sent as it is to try out malformed requests
+fn client_request(line: &str) -> String {
+	let number = line.trim();
+	let is_number = number.bytes().any(|byte| byte.is_ascii_digit())
+		&& number
+			.bytes()
+			.all(|byte| byte.is_ascii_digit() || b"+-.eE".contains(&byte))
+		&& number.parse::<f64>().is_ok();
+	if is_number {
+		format!("{{\"method\":\"isPrime\",\"number\":{number}}}\n")
+	}
+	else {
+		format!("{line}\n")
+	}
+}
+
+fn client(address: &Address, input: &mut dyn BufRead, output: &mut (dyn Write + Send)) -> Result<()> {
+	let mut stream = client::connect(address)?;
+	let mut responses = BufReader::new(stream.try_clone()?);
+	let mut response = String::new();
+	for line in input.lines() {
+		stream.write_all(client_request(&line?).as_bytes())?;
+		response.clear();
+		if responses.read_line(&mut response)? == 0 {
+			writeln!(output, "Connection closed by the server")?;
+			break;
+		}
+		output.write_all(response.as_bytes())?;
+	}
+	Ok(())
+}
+
+fn load(address: &Address, load: &Load) -> LoadReport {
+	client::load_connections(load, |index, samples| {
+		let mut stream = client::connect(address)?;
+		let mut responses = BufReader::new(stream.try_clone()?);
+		let mut response = String::new();
+		for request in 0..load.requests.get() {
+			let number = index * load.requests.get() + request;
+			let started = Instant::now();
+			stream.write_all(client_request(&number.to_string()).as_bytes())?;
+			response.clear();
+			if responses.read_line(&mut response)? == 0 {
+				return Err(anyhow!("Connection closed by the server"));
+			}
+			if response.starts_with("{\"method\": \"isPrime\"") {
+				samples.responded(started.elapsed());
+			}
+			else {
+				samples.failed();
+			}
+		}
+		Ok(())
+	})
 }
 
 #[derive(Debug, Clone)]
-pub(crate) struct PrimeTime;
+pub(crate) struct PrimeTime {
+	// checks numbers too big for a u128 in parallel, shared by all connections
+	pool: Option<Arc<ThreadPool>>,
+	line_format: LineFormat,
+}
 
 impl PrimeTime {
-	pub(crate) fn new() -> Self {
-		Self {}
+	pub(crate) fn new(prime_workers: usize, line_format: LineFormat) -> Self {
+		let pool = NonZeroUsize::new(prime_workers).map(|max_workers| {
+			let size = PoolSize {
+				min_workers: 0,
+				max_workers,
+				idle_timeout: PRIME_WORKER_IDLE_TIMEOUT,
+				client_workers: None,
+			};
+			Arc::new(ThreadPool::new("primetime-divisors", size, None, never()))
+		});
+		Self { pool, line_format }
+	}
+
+	// big numbers are awaited, so the checks do not hold up the other connections of the runtime
+	async fn handle_line_async(&self, line: &str) -> Result<String> {
+		let number = parse_number(parse_json(line));
+		if let (Ok(Number::Big(ref number)), Some(pool)) = (&number, self.pool.as_deref()) {
+			let prime = match is_prime_big_int_async(number, pool).await {
+				Ok(prime) => prime,
+				Err(e) => {
+					warning!("Parallel prime check failed, checking on the runtime thread: {e}");
+					is_prime_big_int(number)
+				},
+			};
+			return respond(Ok(prime));
+		}
+		respond(number.map(|number| number.is_prime()))
 	}
 }
 
 impl TcpHandler for PrimeTime {
-	fn handler(&self, mut stream: TcpStream, id: u32) -> Result<()> {
-		let mut buffer = [0; 4068];
-		stream.set_read_timeout(Some(Duration::new(5, 0)))?;
-
-		'main: loop {
-			eprintln!("({id}) Reading data");
-			let mut data = String::new();
-			while let Ok(size) = stream.read(&mut buffer) {
-				data.push_str(String::from_utf8_lossy(&buffer[0..size]).as_ref());
-
-				if size == 0 || data.ends_with('\n') {
+	fn handler(&self, stream: Box<dyn Stream>, context: ConnectionContext) -> Result<()> {
+		let mut stream = context.counted(stream);
+		let mut lines = LineCodec::new(self.line_format);
+
+		while !context.is_cancelled() {
+			trace!(connection: context.id(), "Reading data");
+			let line = match lines.read_line(&mut stream) {
+				Ok(Some(line)) => line,
+				Ok(None) => break,
+				Err(err) => {
+					debug!(connection: context.id(), "Read failed: {err}");
+					if err.is::<LineError>() {
+						stream.write_all(format!("Malformed request: {err}").as_bytes())?;
+					}
 					break;
-				}
-			}
+				},
+			};
 
-			eprintln!("({id}) Data: '{}' ", data.trim());
+			payload!(connection: context.id(), "Data: '{}' ", line.trim());
 
-			if data.trim().is_empty() {
+			if line.trim().is_empty() {
 				stream.write_all("MALFORMED: Empty".as_bytes())?;
 				break;
 			}
 
-			for line in data.lines() {
-				match handle_request_data(parse_json(line)) {
+			match handle_request_data(parse_json(&line), self.pool.as_deref()) {
+				Ok(out) => {
+					debug!(connection: context.id(), "Data: {line} Result: {out}");
+					stream.write_all(out.as_bytes())?;
+				},
+				Err(err) => {
+					debug!(connection: context.id(), "Data: {line} Error: {}", err);
+					stream.write_all(err.to_string().as_bytes())?;
+					break;
+				},
+			}
+
+			stream.flush()?;
+		}
+		debug!(connection: context.id(), "Shutting down");
+		stream.get_ref().shutdown(Shutdown::Read)?;
+		Ok(())
+	}
+
+	fn busy_response(&self) -> Option<&'static [u8]> {
+		Some(b"BUSY: Server busy, try again later\n")
+	}
+}
+
+impl AsyncTcpHandler for PrimeTime {
+	fn handler(&self, stream: Box<dyn AsyncStream>, context: ConnectionContext) -> HandlerFuture<'_> {
+		Box::pin(async move {
+			let mut stream = context.counted(stream);
+			let mut lines = LineCodec::new(self.line_format);
+
+			while !context.is_cancelled() {
+				trace!(connection: context.id(), "Reading data");
+				let line = match lines.read_line_async(&mut stream).await {
+					Ok(Some(line)) => line,
+					Ok(None) => break,
+					Err(err) => {
+						debug!(connection: context.id(), "Read failed: {err}");
+						if err.is::<LineError>() {
+							stream.write_all(format!("Malformed request: {err}").as_bytes()).await?;
+						}
+						break;
+					},
+				};
+
+				payload!(connection: context.id(), "Data: '{}' ", line.trim());
+
+				if line.trim().is_empty() {
+					stream.write_all("MALFORMED: Empty".as_bytes()).await?;
+					break;
+				}
+
+				match self.handle_line_async(&line).await {
 					Ok(out) => {
-						eprintln!("({id}) Data: {data} Result: {out}");
-						stream.write_all(out.as_bytes())?;
+						debug!(connection: context.id(), "Data: {line} Result: {out}");
+						stream.write_all(out.as_bytes()).await?;
 					},
 					Err(err) => {
-						eprintln!("({id}) Data: {data} Error: {}", err);
-						stream.write_all(err.to_string().as_bytes())?;
-						break 'main;
+						debug!(connection: context.id(), "Data: {line} Error: {}", err);
+						stream.write_all(err.to_string().as_bytes()).await?;
+						break;
 					},
 				}
 
-				stream.flush()?;
+				stream.flush().await?;
 			}
-		}
-		eprintln!("({id}) Shutting down");
-		stream.shutdown(Shutdown::Read)?;
-		Ok(())
+			debug!(connection: context.id(), "Shutting down");
+			stream.get_ref().shutdown_socket(Shutdown::Read)?;
+			Ok(())
+		})
 	}
 }
 
 #[cfg(test)]
 mod tests {
+	use std::thread;
+
+	use tokio::{
+		io::{duplex, split, AsyncReadExt},
+		join,
+		time::timeout,
+	};
+
 	use super::*;
+	use crate::{
+		line_codec::Utf8,
+		stream::testing::{block_on, context, fragmented, pair},
+	};
+
+	const FORMAT: LineFormat = LineFormat {
+		max_length: 0,
+		utf8: Utf8::Lossy,
+	};
+
+	const REQUESTS: &str = "{\"method\":\"isPrime\",\"number\":7}\n{\"method\":\"isPrime\",\"number\":8}\n{}\n";
+	const RESPONSES: &str = concat!(
+		"{\"method\": \"isPrime\", \"prime\": true}\n",
+		"{\"method\": \"isPrime\", \"prime\": false}\n",
+		"Malformed request: invalid method "
+	);
+
+	// a request longer than a read, padded with a field that is skipped
+	fn long_requests() -> String {
+		format!(
+			"{{\"method\":\"isPrime\",\"padding\":\"{}\",\"number\":7}}\r\n{{\"method\":\"isPrime\",\"number\":9}}\n",
+			"x".repeat(10_000)
+		)
+	}
+
+	const LONG_RESPONSES: &str = concat!(
+		"{\"method\": \"isPrime\", \"prime\": true}\n",
+		"{\"method\": \"isPrime\", \"prime\": false}\n"
+	);
 
 	#[test]
 	fn tests() {
@@ -337,17 +710,141 @@ mod tests {
 	#[test]
 	fn handle_request_big_number() {
 		assert_eq!(
-			handle_request_data(Ok(Request {
-				method: String::from("isPrime"),
-				number: String::from("465664798725654230307600049329275256128334622729792136683073")
-			}))
+			handle_request_data(
+				Ok(Request {
+					method: String::from("isPrime"),
+					number: String::from("465664798725654230307600049329275256128334622729792136683073")
+				}),
+				None
+			)
 			.unwrap(),
 			"{\"method\": \"isPrime\", \"prime\": false}\n"
 		);
 	}
 
+	#[test]
+	fn handle_request_big_number_parallel() {
+		let prime_time = PrimeTime::new(3, FORMAT);
+		let pool = prime_time.pool.as_deref();
+		assert_eq!(
+			handle_request_data(
+				parse_json(
+					"{\"method\": \"isPrime\", \"number\": \
+					 465664798725654230307600049329275256128334622729792136683073}"
+				),
+				pool
+			)
+			.unwrap(),
+			"{\"method\": \"isPrime\", \"prime\": false}\n"
+		);
+		// 2^127 - 1 times 2^13 - 1 is too big for a u128, and its smallest divisor is checked by the last job
+		assert!(!is_prime_big_int_parallel(
+			&(BigUint::from(u128::MAX >> 1) * BigUint::from(8191_u32)),
+			pool.unwrap()
+		)
+		.unwrap());
+	}
+
 	#[test]
 	fn prime_test() {
 		assert!(is_prime(2));
 	}
+
+	#[test]
+	fn handler() {
+		let (mut client, server) = pair();
+		client.write_all(REQUESTS.as_bytes()).unwrap();
+		TcpHandler::handler(&PrimeTime::new(0, FORMAT), Box::new(server), context()).unwrap();
+		assert_eq!(client.read_string(), RESPONSES);
+	}
+
+	#[test]
+	fn handler_async() {
+		block_on(async {
+			let (mut client, server) = duplex(1024);
+			client.write_all(REQUESTS.as_bytes()).await.unwrap();
+			AsyncTcpHandler::handler(&PrimeTime::new(0, FORMAT), Box::new(server), context())
+				.await
+				.unwrap();
+			let mut data = String::new();
+			let _size = client.read_to_string(&mut data).await.unwrap();
+			assert_eq!(data, RESPONSES);
+		});
+	}
+
+	#[test]
+	fn fragmented_long_line() {
+		let (mut client, server) = fragmented(1000);
+		client.write_all(long_requests().as_bytes()).unwrap();
+		client.shutdown(Shutdown::Write).unwrap();
+		TcpHandler::handler(&PrimeTime::new(0, FORMAT), Box::new(server), context()).unwrap();
+		assert_eq!(client.read_string(), LONG_RESPONSES);
+	}
+
+	#[test]
+	fn fragmented_long_line_async() {
+		block_on(async {
+			let prime_time = PrimeTime::new(0, FORMAT);
+			let (client, server) = duplex(1000);
+			let (mut reader, mut writer) = split(client);
+			let send = async {
+				writer.write_all(long_requests().as_bytes()).await.unwrap();
+				writer.shutdown().await.unwrap();
+			};
+			let mut data = String::new();
+			let (_sent, result, _size) = join!(
+				send,
+				AsyncTcpHandler::handler(&prime_time, Box::new(server), context()),
+				reader.read_to_string(&mut data)
+			);
+			result.unwrap();
+			assert_eq!(data, LONG_RESPONSES);
+		});
+	}
+
+	#[test]
+	fn line_too_long() {
+		let format = LineFormat {
+			max_length: 100,
+			utf8: Utf8::Strict,
+		};
+		let (mut client, server) = fragmented(64);
+		client.write_all(long_requests().as_bytes()).unwrap();
+		TcpHandler::handler(&PrimeTime::new(0, format), Box::new(server), context()).unwrap();
+		assert_eq!(client.read_string(), "Malformed request: line longer than 100 bytes");
+	}
+
+	#[test]
+	fn client_requests() {
+		assert_eq!(client_request("7"), "{\"method\":\"isPrime\",\"number\":7}\n");
+		assert_eq!(
+			client_request(" -1.5e3 "),
+			"{\"method\":\"isPrime\",\"number\":-1.5e3}\n"
+		);
+		assert_eq!(client_request("{}"), "{}\n");
+		assert_eq!(client_request("e"), "e\n");
+	}
+
+	#[test]
+	fn dropped_check_stops_its_jobs() {
+		let size = PoolSize {
+			min_workers: 0,
+			max_workers: NonZeroUsize::new(2).unwrap(),
+			idle_timeout: Duration::from_secs(10),
+			client_workers: None,
+		};
+		let pool = ThreadPool::new("test-divisors", size, None, never());
+		// a prime with too many divisors to check before the check is dropped
+		let prime = BigUint::from(2_u8).pow(89) - 1_u8;
+		let _elapsed =
+			block_on(async { timeout(Duration::from_millis(50), is_prime_big_int_async(&prime, &pool)).await })
+				.unwrap_err();
+
+		let stats = pool.stats();
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while stats.busy() > 0 && Instant::now() < deadline {
+			thread::sleep(Duration::from_millis(10));
+		}
+		assert_eq!(stats.busy(), 0);
+	}
 }
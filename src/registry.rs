@@ -0,0 +1,114 @@
+use std::{fmt::Write as _, sync::Arc};
+
+use anyhow::Error;
+
+use crate::{
+	budget_chat,
+	client::ProblemClient,
+	handler::{AsyncTcpHandler, AsyncUdpHandler, TcpHandler, UdpHandler},
+	means_to_an_end,
+	options::{Options, ProblemOption},
+	prime_time,
+	smoke_test,
+	unusual_database_program,
+};
+
+pub(crate) static PROBLEMS: &[Problem] = &[
+	smoke_test::PROBLEM,
+	prime_time::PROBLEM,
+	means_to_an_end::PROBLEM,
+	budget_chat::PROBLEM,
+	unusual_database_program::PROBLEM,
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Transport {
+	Tcp,
+	Udp,
+}
+
+impl Transport {
+	pub(crate) const fn name(self) -> &'static str {
+		match self {
+			Self::Tcp => "tcp",
+			Self::Udp => "udp",
+		}
+	}
+}
+
+pub(crate) enum Handler {
+	Tcp(Arc<dyn TcpHandler>, Arc<dyn AsyncTcpHandler>),
+	Udp(Arc<dyn UdpHandler>, Arc<dyn AsyncUdpHandler>),
+}
+
+impl Handler {
+	pub(crate) fn tcp<T: TcpHandler + AsyncTcpHandler + 'static>(handler: T) -> Self {
+		let handler = Arc::new(handler);
+		Self::Tcp(Arc::<T>::clone(&handler), handler)
+	}
+
+	pub(crate) fn udp<T: UdpHandler + AsyncUdpHandler + 'static>(handler: T) -> Self {
+		let handler = Arc::new(handler);
+		Self::Udp(Arc::<T>::clone(&handler), handler)
+	}
+}
+
+#[derive(Debug)]
+pub(crate) struct Problem {
+	pub(crate) name: &'static str,
+	pub(crate) aliases: &'static [&'static str],
+	pub(crate) transport: Transport,
+	pub(crate) description: &'static str,
+	pub(crate) options: &'static [ProblemOption],
+	// the connection timeouts of a TCP problem, applied by the server and set like any other option
+	pub(crate) timeouts: &'static [ProblemOption],
+	pub(crate) create: fn(&Options) -> Result<Handler, Error>,
+	pub(crate) client: ProblemClient,
+}
+
+impl Problem {
+	pub(crate) fn options(&self) -> impl Iterator<Item = &'static ProblemOption> {
+		self.options.iter().chain(self.timeouts.iter())
+	}
+
+	fn matches(&self, name: &str) -> bool {
+		normalize(self.name) == name || self.aliases.iter().any(|alias| normalize(alias) == name)
+	}
+}
+
+fn normalize(name: &str) -> String {
+	name.to_lowercase().replace(['_', '-'], "")
+}
+
+pub(crate) fn find(name: &str) -> Option<&'static Problem> {
+	let name = normalize(name);
+	PROBLEMS.iter().find(|problem| problem.matches(name.as_str()))
+}
+
+pub(crate) fn problem_list() -> String {
+	let mut list = String::new();
+	for problem in PROBLEMS {
+		let _result = write!(
+			list,
+			"  - {} ({}) {}",
+			problem.name,
+			problem.transport.name(),
+			problem.description
+		);
+		if !problem.aliases.is_empty() {
+			let _result = write!(list, " [aliases: {}]", problem.aliases.join(", "));
+		}
+		list.push('\n');
+		for option in problem.options() {
+			let _result = writeln!(
+				list,
+				"      {:<34} {} [default: {}]",
+				format!("{} {}", option.name, option.kind.hint()),
+				option.description,
+				option.default
+			);
+		}
+		let _result = writeln!(list, "      {:<34} {}", "client requests", problem.client.requests);
+	}
+	list
+}
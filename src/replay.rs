@@ -0,0 +1,397 @@
+use std::{
+	collections::BTreeMap,
+	fmt::Write as _,
+	io::{ErrorKind, Read, Write},
+	net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+	panic::{catch_unwind, AssertUnwindSafe},
+	sync::Arc,
+	thread::{self, ScopedJoinHandle},
+	time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+
+use crate::{
+	address::Address,
+	capture::{Event, EventKind, Session},
+	cli::ReplayArgs,
+	context::ConnectionContext,
+	datagram::Datagram,
+	handler::{TcpHandler, UdpHandler},
+	logger::{self, error, warning, Filter, Format},
+	metrics::ServerMetrics,
+	options::{format_duration, Options},
+	registry::{self, Handler},
+	shutdown::ShutdownSignal,
+	socket::Bind,
+	utils::panic_message,
+};
+
+// the bytes of a difference shown in the report
+const EXCERPT_LENGTH: usize = 40;
+
+#[derive(Debug)]
+pub(crate) struct Replay {
+	pub(crate) report: String,
+	pub(crate) connections: usize,
+	pub(crate) differing: usize,
+}
+
+// what the problem sent a connection, a stream is compared as a whole and datagrams one by one
+#[derive(Debug, Default)]
+struct Output {
+	peer: String,
+	recorded: Vec<Vec<u8>>,
+	replayed: Vec<Vec<u8>>,
+	timed_out: bool,
+}
+
+// a replayed connection, the problem handles it on one thread while another reads what the problem sends
+#[derive(Debug)]
+struct Client<'scope> {
+	stream: TcpStream,
+	handler: ScopedJoinHandle<'scope, ()>,
+	received: ScopedJoinHandle<'scope, Vec<u8>>,
+}
+
+pub(crate) fn run(args: &ReplayArgs) -> Result<Replay, Error> {
+	logger::init(Filter::parse("warn")?, Format::Text, false);
+	let session = Session::read(&args.session)?;
+	replay(&session, &args.options, args.wait)
+}
+
+pub(crate) fn replay(session: &Session, options: &[(String, String)], wait: Duration) -> Result<Replay, Error> {
+	let problem =
+		registry::find(&session.problem).ok_or_else(|| anyhow!("Unknown problem in session: {}", session.problem))?;
+	let declared = problem.options().collect::<Vec<_>>();
+	let options = Options::resolve(problem.name, &declared, options)?;
+
+	let mut outputs = BTreeMap::<u64, Output>::new();
+	for event in &session.events {
+		match event.kind {
+			EventKind::Opened(ref peer) => {
+				peer.clone_into(&mut outputs.entry(event.connection).or_default().peer);
+			},
+			EventKind::Written(ref data) => {
+				outputs.entry(event.connection).or_default().recorded.push(data.clone());
+			},
+			EventKind::Read(_) | EventKind::Closed => {},
+		}
+	}
+
+	match (problem.create)(&options)? {
+		Handler::Tcp(handler, _) => {
+			replay_tcp(problem.name, &handler, &session.events, wait, &mut outputs)?;
+			for output in outputs.values_mut() {
+				output.recorded = vec![output.recorded.concat()];
+				output.replayed = vec![output.replayed.concat()];
+			}
+		},
+		Handler::Udp(handler, _) => replay_udp(&handler, &session.events, &mut outputs)?,
+	}
+
+	let mut report = String::new();
+	let _result = writeln!(
+		report,
+		"Replayed {} connections of the {} session",
+		outputs.len(),
+		problem.name
+	);
+	let mut differing = 0;
+	for (connection, output) in &outputs {
+		let difference = compare(output);
+		let _result = write!(report, "connection {connection} ({}): ", output.peer);
+		let _result = match difference {
+			None => writeln!(report, "matches, {} bytes", output.recorded.concat().len()),
+			Some(ref difference) => writeln!(report, "{difference}"),
+		};
+		if output.timed_out {
+			let _result = writeln!(
+				report,
+				"  the problem was still handling the connection {} after the session ended",
+				format_duration(wait)
+			);
+		}
+		if difference.is_some() {
+			differing += 1;
+		}
+	}
+
+	Ok(Replay {
+		report,
+		connections: outputs.len(),
+		differing,
+	})
+}
+
+// the connections of the session are opened to the problem at the times they were recorded, skipping the time when
+// no connection was open
+fn replay_tcp(
+	name: &str,
+	handler: &Arc<dyn TcpHandler>,
+	events: &[Event],
+	wait: Duration,
+	outputs: &mut BTreeMap<u64, Output>,
+) -> Result<(), Error> {
+	let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+	let local_addr = listener.local_addr()?;
+	let shutdown = ShutdownSignal::new();
+	let metrics = ServerMetrics::new(name);
+
+	thread::scope(|s| {
+		let mut clients = BTreeMap::new();
+		let start = Instant::now();
+		let mut skipped = Duration::ZERO;
+		let mut open = 0_usize;
+
+		for event in events {
+			if open == 0 && matches!(event.kind, EventKind::Opened(_)) {
+				skipped = event.time.saturating_sub(start.elapsed());
+			}
+			thread::sleep(event.time.saturating_sub(skipped).saturating_sub(start.elapsed()));
+
+			match event.kind {
+				EventKind::Opened(_) => {
+					let client = TcpStream::connect(local_addr)?;
+					let (stream, addr) = listener.accept()?;
+					let context =
+						ConnectionContext::new(Address::from(addr), Address::from(local_addr), &shutdown, &metrics);
+					let handler = Arc::clone(handler);
+					let handler = s.spawn(move || {
+						let id = context.id();
+						let result = catch_unwind(AssertUnwindSafe(|| handler.handler(Box::new(stream), context)));
+						match result {
+							Ok(Ok(())) => {},
+							Ok(Err(e)) => warning!(connection: id, "{e}"),
+							Err(payload) => error!(connection: id, "{}", panic_message(payload.as_ref())),
+						}
+					});
+					let mut reader = client.try_clone()?;
+					let received = s.spawn(move || {
+						let mut received = vec![];
+						// a reset connection ends what was received like a closed one
+						let _result = reader.read_to_end(&mut received);
+						received
+					});
+					let _previous = clients.insert(event.connection, Client {
+						stream: client,
+						handler,
+						received,
+					});
+					open += 1;
+				},
+				EventKind::Read(ref data) => {
+					if let Some(client) = clients.get_mut(&event.connection) {
+						// the problem may have closed the connection, as it did when the session was captured
+						let _result = client.stream.write_all(data);
+					}
+				},
+				EventKind::Closed => {
+					if let Some(client) = clients.get(&event.connection) {
+						let _result = client.stream.shutdown(Shutdown::Write);
+						open = open.saturating_sub(1);
+					}
+				},
+				EventKind::Written(_) => {},
+			}
+		}
+
+		for client in clients.values() {
+			let _result = client.stream.shutdown(Shutdown::Write);
+		}
+		let deadline = Instant::now() + wait;
+		while Instant::now() < deadline && clients.values().any(|client| !client.handler.is_finished()) {
+			thread::sleep(Duration::from_millis(10));
+		}
+		for (connection, client) in &clients {
+			if !client.handler.is_finished() {
+				outputs.entry(*connection).or_default().timed_out = true;
+				let _result = client.stream.shutdown(Shutdown::Both);
+			}
+		}
+		shutdown.request();
+		handler.shutdown();
+
+		for (connection, client) in clients {
+			client
+				.handler
+				.join()
+				.map_err(|_e| anyhow!("Connection {connection} handler panicked"))?;
+			let received = client
+				.received
+				.join()
+				.map_err(|_e| anyhow!("Connection {connection} reader panicked"))?;
+			outputs.entry(connection).or_default().replayed = vec![received];
+		}
+		Ok(())
+	})
+}
+
+// datagrams are handled one at a time by the server, so they are replayed in order without waiting
+fn replay_udp(
+	handler: &Arc<dyn UdpHandler>,
+	events: &[Event],
+	outputs: &mut BTreeMap<u64, Output>,
+) -> Result<(), Error> {
+	let server = Datagram::bind(&Bind::Ip(vec![SocketAddr::from(([127, 0, 0, 1], 0))]), None)?
+		.pop()
+		.ok_or_else(|| anyhow!("Unable to bind a UDP socket"))?;
+	let Address::Ip(server_addr) = server.local_addr()?
+	else {
+		return Err(anyhow!("Unable to bind a UDP socket"));
+	};
+	let mut buffer = vec![0; handler.max_datagram_size()];
+	let mut clients = BTreeMap::new();
+
+	for event in events {
+		match event.kind {
+			EventKind::Opened(_) => {
+				let client = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+				client.set_nonblocking(true)?;
+				let _previous = clients.insert(event.connection, client);
+			},
+			EventKind::Read(ref data) => {
+				let Some(client) = clients.get(&event.connection)
+				else {
+					continue;
+				};
+				let _size = client.send_to(data, server_addr)?;
+				let (size, addr) = server.recv_from(&mut buffer)?;
+				if let Err(e) = handler.handler(&buffer[..size], &server, &addr) {
+					warning!("({addr}) {e}");
+				}
+
+				// datagrams sent over the loopback are received as soon as they are sent
+				let output = outputs.entry(event.connection).or_default();
+				loop {
+					match client.recv_from(&mut buffer) {
+						Ok((size, _)) => output.replayed.push(buffer[..size].to_vec()),
+						Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+						Err(err) => return Err(Error::from(err)),
+					}
+				}
+			},
+			// a peer the capture forgot, which is opened again as a new connection if it sends again
+			EventKind::Closed => {
+				let _client = clients.remove(&event.connection);
+			},
+			EventKind::Written(_) => {},
+		}
+	}
+
+	handler.shutdown();
+	Ok(())
+}
+
+fn compare(output: &Output) -> Option<String> {
+	if output.recorded.len() != output.replayed.len() {
+		return Some(format!(
+			"{} datagrams were recorded, {} were replayed",
+			output.recorded.len(),
+			output.replayed.len()
+		));
+	}
+	for (index, (recorded, replayed)) in output.recorded.iter().zip(&output.replayed).enumerate() {
+		let Some(position) = first_difference(recorded, replayed)
+		else {
+			continue;
+		};
+		let datagram = if output.recorded.len() > 1 {
+			format!("datagram {} ", index + 1)
+		}
+		else {
+			String::new()
+		};
+		return Some(format!(
+			"{datagram}differs at byte {position}\n  recorded: {}\n  replayed: {}",
+			excerpt(recorded, position),
+			excerpt(replayed, position)
+		));
+	}
+	None
+}
+
+fn first_difference(recorded: &[u8], replayed: &[u8]) -> Option<usize> {
+	recorded
+		.iter()
+		.zip(replayed)
+		.position(|(recorded, replayed)| recorded != replayed)
+		.or_else(|| (recorded.len() != replayed.len()).then(|| recorded.len().min(replayed.len())))
+}
+
+fn excerpt(data: &[u8], position: usize) -> String {
+	if position >= data.len() {
+		return String::from("(end of data)");
+	}
+	let end = data.len().min(position + EXCERPT_LENGTH);
+	let ellipsis = if end < data.len() { "..." } else { "" };
+	format!("'{}'{ellipsis}", data[position..end].escape_ascii())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn event(connection: u64, millis: u64, kind: EventKind) -> Event {
+		Event {
+			connection,
+			time: Duration::from_millis(millis),
+			kind,
+		}
+	}
+
+	fn prime_time(response: &[u8]) -> Session {
+		Session {
+			problem: String::from("primetime"),
+			events: vec![
+				event(1, 0, EventKind::Opened(String::from("127.0.0.1:1000"))),
+				event(
+					1,
+					1,
+					EventKind::Read(b"{\"method\":\"isPrime\",\"number\":7}\n".to_vec()),
+				),
+				event(1, 2, EventKind::Written(response.to_vec())),
+				event(1, 3, EventKind::Closed),
+			],
+		}
+	}
+
+	#[test]
+	fn tcp_session_matches() {
+		let session = prime_time(b"{\"method\": \"isPrime\", \"prime\": true}\n");
+		let replay = replay(&session, &[], Duration::from_secs(5)).unwrap();
+		assert_eq!(replay.differing, 0, "{}", replay.report);
+		assert_eq!(replay.connections, 1);
+	}
+
+	#[test]
+	fn tcp_session_differs() {
+		let session = prime_time(b"{\"method\": \"isPrime\", \"prime\": false}\n");
+		let replay = replay(&session, &[], Duration::from_secs(5)).unwrap();
+		assert_eq!(replay.differing, 1);
+		assert!(
+			replay
+				.report
+				.contains("differs at byte 31\n  recorded: 'false}\\n'\n  replayed: 'true}\\n'"),
+			"{}",
+			replay.report
+		);
+	}
+
+	#[test]
+	fn udp_session_is_compared_by_datagram() {
+		let session = Session {
+			problem: String::from("unusualdatabaseprogram"),
+			events: vec![
+				event(1, 0, EventKind::Opened(String::from("127.0.0.1:1000"))),
+				event(1, 0, EventKind::Read(b"key=value".to_vec())),
+				event(1, 1, EventKind::Read(b"key".to_vec())),
+				event(1, 1, EventKind::Written(b"key=value".to_vec())),
+				event(1, 2, EventKind::Read(b"missing".to_vec())),
+				event(1, 2, EventKind::Written(b"missing=".to_vec())),
+			],
+		};
+		let replay = replay(&session, &[], Duration::from_secs(5)).unwrap();
+		assert_eq!(replay.differing, 0, "{}", replay.report);
+	}
+}
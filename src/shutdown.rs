@@ -0,0 +1,69 @@
+use std::sync::Arc;
+
+use crossbeam::channel::{bounded, Receiver, Sender as ChannelSender};
+use mio::Waker;
+use parking_lot::Mutex;
+use tokio::sync::watch::{channel, Sender};
+
+use crate::logger::error;
+
+#[derive(Debug)]
+pub(crate) struct ShutdownSignal {
+	sender: Sender<bool>,
+	wakers: Mutex<Vec<Waker>>,
+	// never sent on, it is dropped on request which disconnects the receivers
+	closer: Mutex<Option<ChannelSender<()>>>,
+	closed: Receiver<()>,
+}
+
+impl ShutdownSignal {
+	pub(crate) fn new() -> Arc<Self> {
+		let (sender, _receiver) = channel(false);
+		let (close_sender, close_receiver) = bounded(0);
+		Arc::new(Self {
+			sender,
+			wakers: Mutex::new(vec![]),
+			closer: Mutex::new(Some(close_sender)),
+			closed: close_receiver,
+		})
+	}
+
+	pub(crate) fn is_requested(&self) -> bool {
+		*self.sender.borrow()
+	}
+
+	pub(crate) fn request(&self) {
+		let wakers = self.wakers.lock();
+		let _previous = self.sender.send_replace(true);
+		drop(self.closer.lock().take());
+		for waker in wakers.iter() {
+			if let Err(e) = waker.wake() {
+				error!("Unable to wake event loop: {e}");
+			}
+		}
+	}
+
+	pub(crate) async fn requested(&self) {
+		let mut receiver = self.sender.subscribe();
+		while !*receiver.borrow_and_update() {
+			if receiver.changed().await.is_err() {
+				break;
+			}
+		}
+	}
+
+	// a receiver that disconnects on request, to wait on shutdown in a crossbeam select
+	pub(crate) fn receiver(&self) -> Receiver<()> {
+		self.closed.clone()
+	}
+
+	pub(crate) fn wake_on_request(&self, waker: Waker) {
+		let mut wakers = self.wakers.lock();
+		if self.is_requested() {
+			if let Err(e) = waker.wake() {
+				error!("Unable to wake event loop: {e}");
+			}
+		}
+		wakers.push(waker);
+	}
+}
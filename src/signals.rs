@@ -0,0 +1,40 @@
+use anyhow::Error;
+
+// SIGUSR1 toggles payload dumps and SIGUSR2 prints the status of the thread pool workers while the server is running
+#[cfg(unix)]
+pub(crate) fn listen() -> Result<(), Error> {
+	use std::{
+		io::{stderr, Write},
+		thread,
+	};
+
+	use signal_hook::{
+		consts::{SIGUSR1, SIGUSR2},
+		iterator::Signals,
+	};
+
+	use crate::{
+		logger::{self, info},
+		thread_pool,
+	};
+
+	let mut signals = Signals::new([SIGUSR1, SIGUSR2])?;
+	let _handle = thread::Builder::new().name(String::from("signals")).spawn(move || {
+		for signal in signals.forever() {
+			if signal == SIGUSR2 {
+				// a failed write to stderr has nowhere to be reported
+				let _result = stderr().lock().write_all(thread_pool::status_table().as_bytes());
+				continue;
+			}
+			let enabled = logger::toggle_payloads();
+			info!("Payload logging {}", if enabled { "enabled" } else { "disabled" });
+		}
+	})?;
+	Ok(())
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+pub(crate) fn listen() -> Result<(), Error> {
+	Ok(())
+}
@@ -1,11 +1,70 @@
 use std::{
-	io::{Read, Write},
-	net::{Shutdown, TcpStream},
+	io::{BufRead, Read, Write},
+	net::Shutdown,
+	time::Instant,
 };
 
 use anyhow::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::handler::TcpHandler;
+use crate::{
+	address::Address,
+	client::{self, Load, LoadReport, ProblemClient},
+	context::ConnectionContext,
+	handler::{AsyncTcpHandler, HandlerFuture, TcpHandler},
+	registry::{Handler, Problem, Transport},
+	stream::{AsyncStream, Stream},
+	timeouts,
+};
+
+pub(crate) const PROBLEM: Problem = Problem {
+	name: "smoketest",
+	aliases: &["0", "echo"],
+	transport: Transport::Tcp,
+	description: "Echo back all data received (problem 0)",
+	options: &[],
+	timeouts: &timeouts::options("60s", "10s", "600s"),
+	create: |_| Ok(Handler::tcp(SmokeTest::new())),
+	client: ProblemClient {
+		interactive: client,
+		load,
+		requests: "any text, which is echoed back",
+	},
+};
+
+fn client(address: &Address, input: &mut dyn BufRead, output: &mut (dyn Write + Send)) -> Result<(), Error> {
+	let mut stream = client::connect(address)?;
+	let mut echo = vec![];
+	for line in input.lines() {
+		let line = format!("{}\n", line?);
+		stream.write_all(line.as_bytes())?;
+		echo.resize(line.len(), 0);
+		stream.read_exact(&mut echo)?;
+		output.write_all(&echo)?;
+	}
+	Ok(())
+}
+
+fn load(address: &Address, load: &Load) -> LoadReport {
+	client::load_connections(load, |index, samples| {
+		let mut stream = client::connect(address)?;
+		let mut echo = vec![];
+		for request in 0..load.requests.get() {
+			let data = format!("connection {index} request {request}\n");
+			let started = Instant::now();
+			stream.write_all(data.as_bytes())?;
+			echo.resize(data.len(), 0);
+			stream.read_exact(&mut echo)?;
+			if echo == data.as_bytes() {
+				samples.responded(started.elapsed());
+			}
+			else {
+				samples.failed();
+			}
+		}
+		Ok(())
+	})
+}
 
 #[derive(Debug, Clone)]
 pub(crate) struct SmokeTest;
@@ -17,7 +76,8 @@ impl SmokeTest {
 }
 
 impl TcpHandler for SmokeTest {
-	fn handler(&self, mut stream: TcpStream, _id: u32) -> Result<(), Error> {
+	fn handler(&self, stream: Box<dyn Stream>, context: ConnectionContext) -> Result<(), Error> {
+		let mut stream = context.counted(stream);
 		let mut buffer = [0; 128];
 
 		while let Ok(size) = stream.read(&mut buffer) {
@@ -27,7 +87,58 @@ impl TcpHandler for SmokeTest {
 				break;
 			}
 		}
-		stream.shutdown(Shutdown::Read)?;
+		stream.get_ref().shutdown(Shutdown::Read)?;
 		Ok(())
 	}
 }
+
+impl AsyncTcpHandler for SmokeTest {
+	fn handler(&self, stream: Box<dyn AsyncStream>, context: ConnectionContext) -> HandlerFuture<'_> {
+		Box::pin(async move {
+			let mut stream = context.counted(stream);
+			let mut buffer = [0; 128];
+
+			while let Ok(size) = stream.read(&mut buffer).await {
+				stream.write_all(&buffer[0..size]).await?;
+				stream.flush().await?;
+				if size == 0 {
+					break;
+				}
+			}
+			stream.get_ref().shutdown_socket(Shutdown::Read)?;
+			Ok(())
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::duplex;
+
+	use super::*;
+	use crate::stream::testing::{block_on, context, pair};
+
+	#[test]
+	fn echo() {
+		let (mut client, server) = pair();
+		client.write_all(b"hello\nworld").unwrap();
+		client.shutdown(Shutdown::Write).unwrap();
+		TcpHandler::handler(&SmokeTest::new(), Box::new(server), context()).unwrap();
+		assert_eq!(client.read_string(), "hello\nworld");
+	}
+
+	#[test]
+	fn echo_async() {
+		block_on(async {
+			let (mut client, server) = duplex(1024);
+			client.write_all(b"hello\nworld").await.unwrap();
+			client.shutdown().await.unwrap();
+			AsyncTcpHandler::handler(&SmokeTest::new(), Box::new(server), context())
+				.await
+				.unwrap();
+			let mut data = String::new();
+			let _size = client.read_to_string(&mut data).await.unwrap();
+			assert_eq!(data, "hello\nworld");
+		});
+	}
+}
@@ -0,0 +1,65 @@
+use std::{
+	io,
+	net::{SocketAddr, TcpListener, UdpSocket},
+	path::PathBuf,
+};
+
+use anyhow::{anyhow, Error};
+use socket2::{Domain, Protocol, Socket, Type};
+
+const LISTEN_BACKLOG: i32 = 128;
+
+// where a server is bound, a port on every bind address or a unix socket path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Bind {
+	Ip(Vec<SocketAddr>),
+	Unix(PathBuf),
+}
+
+// An unspecified IPv6 address is bound dual-stack, so it also accepts IPv4 clients, unless an IPv4 address is bound
+// separately on the same port, in which case the two sockets would collide.
+fn only_v6(address: SocketAddr, addresses: &[SocketAddr]) -> bool {
+	!address.ip().is_unspecified()
+		|| addresses
+			.iter()
+			.any(|other| other.is_ipv4() && other.port() == address.port())
+}
+
+fn bind_socket(
+	address: SocketAddr,
+	addresses: &[SocketAddr],
+	socket_type: Type,
+	protocol: Protocol,
+) -> io::Result<Socket> {
+	let socket = Socket::new(Domain::for_address(address), socket_type, Some(protocol))?;
+	if address.is_ipv6() {
+		socket.set_only_v6(only_v6(address, addresses))?;
+	}
+	#[cfg(unix)]
+	socket.set_reuse_address(true)?;
+	socket.bind(&address.into())?;
+	Ok(socket)
+}
+
+pub(crate) fn bind_tcp(addresses: &[SocketAddr]) -> Result<Vec<TcpListener>, Error> {
+	addresses
+		.iter()
+		.map(|&address| {
+			let socket = bind_socket(address, addresses, Type::STREAM, Protocol::TCP)
+				.map_err(|e| anyhow!("Unable to bind TCP listener on {address}: {e}"))?;
+			socket.listen(LISTEN_BACKLOG)?;
+			Ok(TcpListener::from(socket))
+		})
+		.collect()
+}
+
+pub(crate) fn bind_udp(addresses: &[SocketAddr]) -> Result<Vec<UdpSocket>, Error> {
+	addresses
+		.iter()
+		.map(|&address| {
+			let socket = bind_socket(address, addresses, Type::DGRAM, Protocol::UDP)
+				.map_err(|e| anyhow!("Unable to bind UDP socket on {address}: {e}"))?;
+			Ok(UdpSocket::from(socket))
+		})
+		.collect()
+}
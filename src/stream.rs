@@ -0,0 +1,215 @@
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+	fmt::Debug,
+	io::{self, Read, Write},
+	net::{Shutdown, TcpStream},
+};
+
+use socket2::SockRef;
+#[cfg(unix)]
+use tokio::net::UnixStream as AsyncUnixStream;
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	net::TcpStream as AsyncTcpStream,
+};
+
+// a connection a handler reads from and writes to, which may be a plain socket or a layer over one, like TLS
+pub(crate) trait Stream: Read + Write + Send + Debug {
+	// another handle to the same connection, so one thread can read while another writes
+	fn try_clone(&self) -> io::Result<Box<dyn Stream>>;
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+	fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+		Ok(Box::new(Self::try_clone(self)?))
+	}
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+		Self::shutdown(self, how)
+	}
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+	fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+		Ok(Box::new(Self::try_clone(self)?))
+	}
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+		Self::shutdown(self, how)
+	}
+}
+
+// the async counterpart of Stream, a task reads while another writes with the halves of tokio::io::split
+pub(crate) trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin + Debug {
+	// named apart from AsyncWriteExt::shutdown, which flushes and only shuts down writing
+	fn shutdown_socket(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl AsyncStream for AsyncTcpStream {
+	fn shutdown_socket(&self, how: Shutdown) -> io::Result<()> {
+		SockRef::from(self).shutdown(how)
+	}
+}
+
+#[cfg(unix)]
+impl AsyncStream for AsyncUnixStream {
+	fn shutdown_socket(&self, how: Shutdown) -> io::Result<()> {
+		SockRef::from(self).shutdown(how)
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+	use std::{
+		collections::VecDeque,
+		future::Future,
+		io::{self, Read, Write},
+		net::{Shutdown, SocketAddr},
+		sync::Arc,
+	};
+
+	use parking_lot::{Condvar, Mutex};
+	use tokio::{io::DuplexStream, runtime::Builder};
+
+	use super::{AsyncStream, Stream};
+	use crate::{address::Address, context::ConnectionContext, metrics::ServerMetrics, shutdown::ShutdownSignal};
+
+	#[derive(Debug, Default)]
+	struct Pipe {
+		// the bytes written and not yet read, and whether the pipe is closed
+		state: Mutex<(VecDeque<u8>, bool)>,
+		readable: Condvar,
+	}
+
+	impl Pipe {
+		fn close(&self) {
+			self.state.lock().1 = true;
+			let _woken = self.readable.notify_all();
+		}
+	}
+
+	// one end of an in-memory connection, what is written to one end is read from the other, the connection is
+	// closed when the last handle to an end is dropped
+	#[derive(Debug)]
+	pub(crate) struct MemoryStream {
+		incoming: Arc<Pipe>,
+		outgoing: Arc<Pipe>,
+		handles: Arc<()>,
+		max_read: usize,
+	}
+
+	impl MemoryStream {
+		pub(crate) fn read_string(&mut self) -> String {
+			let mut data = String::new();
+			let _size = self.read_to_string(&mut data).unwrap();
+			data
+		}
+	}
+
+	impl Read for MemoryStream {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			let mut state = self.incoming.state.lock();
+			while state.0.is_empty() && !state.1 {
+				self.incoming.readable.wait(&mut state);
+			}
+			let size = buf.len().min(state.0.len()).min(self.max_read);
+			for (byte, data) in buf.iter_mut().zip(state.0.drain(..size)) {
+				*byte = data;
+			}
+			Ok(size)
+		}
+	}
+
+	impl Write for MemoryStream {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			let mut state = self.outgoing.state.lock();
+			if state.1 {
+				return Err(io::ErrorKind::BrokenPipe.into());
+			}
+			state.0.extend(buf);
+			let _woken = self.outgoing.readable.notify_all();
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl Stream for MemoryStream {
+		fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+			Ok(Box::new(Self {
+				incoming: Arc::clone(&self.incoming),
+				outgoing: Arc::clone(&self.outgoing),
+				handles: Arc::clone(&self.handles),
+				max_read: self.max_read,
+			}))
+		}
+
+		fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+			if how != Shutdown::Write {
+				self.incoming.close();
+			}
+			if how != Shutdown::Read {
+				self.outgoing.close();
+			}
+			Ok(())
+		}
+	}
+
+	impl Drop for MemoryStream {
+		fn drop(&mut self) {
+			if Arc::strong_count(&self.handles) == 1 {
+				self.incoming.close();
+				self.outgoing.close();
+			}
+		}
+	}
+
+	// the client and server ends of an in-memory connection
+	pub(crate) fn pair() -> (MemoryStream, MemoryStream) {
+		fragmented(usize::MAX)
+	}
+
+	// a connection where a read of the server end returns at most max_read bytes, as if the data arrived in pieces
+	pub(crate) fn fragmented(max_read: usize) -> (MemoryStream, MemoryStream) {
+		let (to_server, to_client) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+		let client = MemoryStream {
+			incoming: Arc::clone(&to_client),
+			outgoing: Arc::clone(&to_server),
+			handles: Arc::new(()),
+			max_read: usize::MAX,
+		};
+		let server = MemoryStream {
+			incoming: to_server,
+			outgoing: to_client,
+			handles: Arc::new(()),
+			max_read,
+		};
+		(client, server)
+	}
+
+	// the end of a tokio duplex stream cannot be shut down for reading, the client shuts down its end for writing
+	impl AsyncStream for DuplexStream {
+		fn shutdown_socket(&self, _how: Shutdown) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+		Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(future)
+	}
+
+	pub(crate) fn context() -> ConnectionContext {
+		let addr = Address::from(SocketAddr::from(([127, 0, 0, 1], 0)));
+		ConnectionContext::new(addr.clone(), addr, &ShutdownSignal::new(), &ServerMetrics::new("test"))
+	}
+}
@@ -1,48 +1,448 @@
-use std::num::NonZeroUsize;
+use std::{
+	collections::BTreeMap,
+	fmt::Write as _,
+	num::NonZeroUsize,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+		Weak,
+	},
+	time::{Duration, Instant},
+};
 
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use anyhow::{anyhow, Error};
+use crossbeam::channel::Receiver;
+use parking_lot::{const_mutex, Mutex};
 
-use crate::{job::Job, worker::Worker};
+use crate::{
+	job::{self, JobHandle, JobLabel},
+	job_queue::JobQueue,
+	logger::{debug, info},
+	worker::{Worker, WorkerStatus},
+};
 
+// pools are listed in the worker status table until they are dropped
+static POOLS: Mutex<Vec<(String, Weak<PoolStats>)>> = const_mutex(Vec::new());
+
+// workers are started while jobs wait and every worker is busy, up to the maximum, and workers above the minimum
+// stop after waiting for a job for the idle timeout
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct PoolSize {
+	pub(crate) min_workers: usize,
+	pub(crate) max_workers: NonZeroUsize,
+	pub(crate) idle_timeout: Duration,
+	// the most workers running jobs for connections from one IP address
+	pub(crate) client_workers: Option<NonZeroUsize>,
+}
+
+// what the accept loop does with a connection when the job queue is full
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Overflow {
+	// wait for room in the queue, leaving new connections in the listen backlog
+	Block,
+	// send the problem's busy response and close the connection
+	Reject,
+	// close the connection without a response
+	Close,
+}
+
+impl Overflow {
+	pub(crate) fn parse(value: &str) -> Result<Self, Error> {
+		match value.to_lowercase().as_str() {
+			"block" => Ok(Self::Block),
+			"reject" => Ok(Self::Reject),
+			"close" => Ok(Self::Close),
+			_ => {
+				Err(anyhow!(
+					"'{value}' is not an overflow policy, expected block, reject or close"
+				))
+			},
+		}
+	}
+
+	pub(crate) const fn name(self) -> &'static str {
+		match self {
+			Self::Block => "block",
+			Self::Reject => "reject",
+			Self::Close => "close",
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PoolStats {
+	queued: AtomicUsize,
+	busy: AtomicUsize,
+	workers: AtomicUsize,
+	statuses: Mutex<BTreeMap<usize, WorkerStatus>>,
+}
+
+impl PoolStats {
+	pub(crate) fn workers(&self) -> usize {
+		self.workers.load(Ordering::SeqCst)
+	}
+
+	pub(crate) fn queued(&self) -> usize {
+		self.queued.load(Ordering::SeqCst)
+	}
+
+	pub(crate) fn busy(&self) -> usize {
+		self.busy.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn job_started(&self, id: usize, label: &JobLabel) {
+		let _previous = self.queued.fetch_sub(1, Ordering::SeqCst);
+		let _previous = self.busy.fetch_add(1, Ordering::Relaxed);
+		if let Some(status) = self.statuses.lock().get_mut(&id) {
+			status.job = Some((label.clone(), Instant::now()));
+		}
+	}
+
+	pub(crate) fn job_finished(&self, id: usize) {
+		let _previous = self.busy.fetch_sub(1, Ordering::Relaxed);
+		if let Some(status) = self.statuses.lock().get_mut(&id) {
+			if let Some((_, started)) = status.job.take() {
+				status.busy_time += started.elapsed();
+			}
+			status.jobs_completed += 1;
+		}
+	}
+
+	pub(crate) fn add_status(&self, id: usize) {
+		let _previous = self.statuses.lock().insert(id, WorkerStatus::new(id));
+	}
+
+	pub(crate) fn remove_status(&self, id: usize) {
+		let _previous = self.statuses.lock().remove(&id);
+	}
+
+	// the status of every running worker, ordered by id, with the time spent on running jobs added to busy time
+	pub(crate) fn snapshot(&self) -> Vec<WorkerStatus> {
+		self.statuses
+			.lock()
+			.values()
+			.map(|status| {
+				let mut status = status.clone();
+				if let Some((_, started)) = status.job {
+					status.busy_time += started.elapsed();
+				}
+				status
+			})
+			.collect()
+	}
+
+	fn update_workers<F>(&self, update: F) -> bool
+	where F: Fn(usize) -> Option<usize> {
+		let mut workers = self.workers();
+		while let Some(next) = update(workers) {
+			match self
+				.workers
+				.compare_exchange_weak(workers, next, Ordering::SeqCst, Ordering::SeqCst)
+			{
+				Ok(_) => return true,
+				Err(current) => workers = current,
+			}
+		}
+		false
+	}
+
+	// counts one more worker, unless the maximum is already running
+	fn add_worker(&self, max_workers: NonZeroUsize) -> bool {
+		self.update_workers(|workers| (workers < max_workers.get()).then_some(workers + 1))
+	}
+
+	// counts one less worker for an idle worker, unless only the minimum is running
+	pub(crate) fn retire_worker(&self, size: PoolSize) -> bool {
+		let retired = self.update_workers(|workers| (workers > size.min_workers).then(|| workers - 1));
+
+		// a job queued while the worker was retiring may have seen it as running, so the worker stays for it,
+		// unless a new worker was started for the job in the meantime
+		if retired && self.queued() > 0 && self.add_worker(size.max_workers) {
+			return false;
+		}
+		retired
+	}
+
+	pub(crate) fn worker_stopped(&self) {
+		let _previous = self.workers.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+#[derive(Debug)]
 pub(crate) struct ThreadPool {
-	workers: Vec<Worker>,
-	sender: Option<Sender<Job>>,
-	receiver: Option<Receiver<Job>>,
+	size: PoolSize,
+	stop: Receiver<()>,
+	workers: Mutex<Vec<Worker>>,
+	next_id: AtomicUsize,
+	queue: Arc<JobQueue>,
+	stats: Arc<PoolStats>,
 }
 
 impl ThreadPool {
-	pub(crate) fn new(size: NonZeroUsize) -> ThreadPool {
-		let (sender, receiver) = unbounded();
+	// a queue capacity of None leaves the job queue unbounded, and waiting for room in a full queue ends when the
+	// stop receiver disconnects
+	pub(crate) fn new(name: &str, size: PoolSize, capacity: Option<NonZeroUsize>, stop: Receiver<()>) -> ThreadPool {
+		let stats = Arc::new(PoolStats::default());
+		POOLS.lock().push((String::from(name), Arc::downgrade(&stats)));
 
-		let mut workers = Vec::with_capacity(size.get());
+		let pool = ThreadPool {
+			size,
+			stop,
+			workers: Mutex::new(Vec::with_capacity(size.max_workers.get())),
+			next_id: AtomicUsize::new(0),
+			queue: Arc::new(JobQueue::new(capacity, size.client_workers)),
+			stats,
+		};
 
-		for id in 0..size.get() {
-			workers.push(Worker::new(id, receiver.clone()));
+		for _ in 0..size.min_workers {
+			if pool.stats.add_worker(size.max_workers) {
+				let _id = pool.start_worker();
+			}
 		}
 
-		ThreadPool {
-			workers,
-			sender: Some(sender),
-			receiver: Some(receiver),
+		pool
+	}
+
+	fn start_worker(&self) -> usize {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		let mut workers = self.workers.lock();
+		// retired workers have stopped, so their threads are joined without waiting
+		workers.retain_mut(|worker| {
+			if !worker.is_finished() {
+				return true;
+			}
+			worker.join();
+			false
+		});
+		workers.push(Worker::new(
+			id,
+			Arc::clone(&self.queue),
+			Arc::clone(&self.stats),
+			self.size,
+		));
+		id
+	}
+
+	pub(crate) fn execute<T, F>(&self, f: F) -> Result<JobHandle<T>, Error>
+	where
+		T: Send + 'static,
+		F: FnOnce() -> T + Send + 'static,
+	{
+		self.execute_with_label(JobLabel::default(), f)
+	}
+
+	// queues the job, waiting for room in the queue while it is full, unless the pool is stopped
+	pub(crate) fn execute_with_label<T, F>(&self, label: JobLabel, f: F) -> Result<JobHandle<T>, Error>
+	where
+		T: Send + 'static,
+		F: FnOnce() -> T + Send + 'static,
+	{
+		let (job, handle) = job::with_handle(label, f);
+		let _previous = self.stats.queued.fetch_add(1, Ordering::SeqCst);
+		if let Err(e) = self.queue.push(job, &self.stop) {
+			let _previous = self.stats.queued.fetch_sub(1, Ordering::SeqCst);
+			return Err(e);
+		}
+		if self.queue.needs_worker(self.stats.workers()) && self.stats.add_worker(self.size.max_workers) {
+			let id = self.start_worker();
+			info!("Started worker {id}, {} workers running", self.stats.workers());
 		}
+		Ok(handle)
+	}
+
+	// queues the jobs added by the closure and waits for all of them, returning their results in the order they
+	// were added, jobs that have not started are cancelled when adding a job fails
+	pub(crate) fn scope<T, F>(&self, f: F) -> Result<Vec<T>, Error>
+	where
+		T: Send + 'static,
+		F: FnOnce(&mut JobScope<'_, T>) -> Result<(), Error>,
+	{
+		let mut scope = JobScope {
+			pool: self,
+			handles: vec![],
+		};
+		let queued = f(&mut scope);
+		if queued.is_err() {
+			for handle in &scope.handles {
+				let _cancelled = handle.cancel();
+			}
+		}
+
+		let results = scope.handles.into_iter().map(JobHandle::join).collect::<Vec<_>>();
+		queued?;
+		results.into_iter().collect()
+	}
+
+	// only the accept loop queues connection jobs, so a queue that is not full has room for the next job
+	pub(crate) fn is_full(&self) -> bool {
+		self.queue.is_full()
+	}
+
+	pub(crate) const fn max_workers(&self) -> usize {
+		self.size.max_workers.get()
 	}
 
-	pub(crate) fn execute<F>(&self, f: F)
-	where F: FnOnce() + Send + 'static {
-		self.sender.as_ref().unwrap().send(Box::new(f)).unwrap();
+	pub(crate) fn stats(&self) -> Arc<PoolStats> {
+		Arc::clone(&self.stats)
 	}
 }
 
+#[derive(Debug)]
+pub(crate) struct JobScope<'pool, T> {
+	pool: &'pool ThreadPool,
+	handles: Vec<JobHandle<T>>,
+}
+
+impl<T: Send + 'static> JobScope<'_, T> {
+	pub(crate) fn execute<F>(&mut self, f: F) -> Result<(), Error>
+	where F: FnOnce() -> T + Send + 'static {
+		self.handles.push(self.pool.execute(f)?);
+		Ok(())
+	}
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+	value.map_or_else(|| String::from("-"), |value| value.to_string())
+}
+
+// a table of the workers of every pool, with the job each worker is running
+pub(crate) fn status_table() -> String {
+	let mut table = format!(
+		"{:<20} {:>6} {:<8} {:>10} {:<24} {:<22} {:>10} {:>8} {:>10}\n",
+		"POOL", "WORKER", "STATE", "CONNECTION", "PROBLEM", "PEER", "RUNNING", "JOBS", "BUSY"
+	);
+	let mut pools = POOLS.lock();
+	pools.retain(|(_, stats)| stats.strong_count() > 0);
+	for (name, pool) in pools.iter() {
+		let Some(pool) = pool.upgrade()
+		else {
+			continue;
+		};
+		for status in pool.snapshot() {
+			let (state, label, running) = status.job.as_ref().map_or(("idle", None, None), |(label, started)| {
+				("running", Some(label), Some(format!("{:.1?}", started.elapsed())))
+			});
+			let _result = writeln!(
+				table,
+				"{name:<20} {:>6} {state:<8} {:>10} {:<24} {:<22} {:>10} {:>8} {:>10}",
+				status.id,
+				optional(label.and_then(|label| label.connection)),
+				optional(label.and_then(|label| label.problem.as_ref())),
+				optional(label.and_then(|label| label.peer.as_ref())),
+				optional(running),
+				status.jobs_completed,
+				format!("{:.1?}", status.busy_time),
+			);
+		}
+	}
+	table
+}
+
 impl Drop for ThreadPool {
 	fn drop(&mut self) {
-		drop(self.sender.take());
-		drop(self.receiver.take());
+		self.queue.close();
+
+		for worker in self.workers.get_mut() {
+			debug!("Waiting for worker {} to stop", worker.id());
+			worker.join();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{iter, thread};
 
-		for worker in &mut self.workers {
-			if let Some(thread) = worker.take() {
-				eprintln!("Waiting for worker {} to stop", worker.id());
-				thread.join().unwrap();
+	use crossbeam::channel::{never, unbounded};
+
+	use super::*;
+
+	fn pool(min_workers: usize, max_workers: usize) -> ThreadPool {
+		limited_pool(min_workers, max_workers, None)
+	}
+
+	fn limited_pool(min_workers: usize, max_workers: usize, client_workers: Option<NonZeroUsize>) -> ThreadPool {
+		let size = PoolSize {
+			min_workers,
+			max_workers: NonZeroUsize::new(max_workers).unwrap(),
+			idle_timeout: Duration::from_millis(50),
+			client_workers,
+		};
+		ThreadPool::new("test", size, None, never())
+	}
+
+	fn wait_for(condition: impl Fn() -> bool) -> bool {
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while !condition() {
+			if Instant::now() > deadline {
+				return false;
 			}
+			thread::sleep(Duration::from_millis(10));
+		}
+		true
+	}
+
+	#[test]
+	fn grows_to_max_workers_and_shrinks_to_min_workers() {
+		let pool = pool(1, 3);
+		assert_eq!(pool.stats.workers(), 1);
+
+		let (release, released) = unbounded::<()>();
+		let handles = iter::repeat_with(|| {
+			let released = released.clone();
+			pool.execute(move || {
+				let _released = released.recv();
+			})
+			.unwrap()
+		})
+		.take(4)
+		.collect::<Vec<_>>();
+		assert!(wait_for(|| pool.stats.busy() == 3));
+		assert_eq!(pool.stats.workers(), 3);
+		assert_eq!(pool.stats.queued(), 1);
+
+		for _ in 0..4 {
+			release.send(()).unwrap();
+		}
+		for handle in handles {
+			handle.join().unwrap();
+		}
+
+		assert!(wait_for(|| pool.stats.workers() == 1));
+		thread::sleep(Duration::from_millis(150));
+		assert_eq!(pool.stats.workers(), 1);
+	}
+
+	#[test]
+	fn starts_a_worker_after_shrinking_to_none() {
+		let pool = pool(0, 2);
+		for value in 0..2 {
+			assert_eq!(pool.execute(move || value).unwrap().join().unwrap(), value);
+			assert!(wait_for(|| pool.stats.workers() == 0));
+		}
+	}
+
+	#[test]
+	fn no_worker_for_jobs_waiting_on_the_client_limit() {
+		let pool = limited_pool(0, 4, NonZeroUsize::new(1));
+		let (release, released) = unbounded::<()>();
+		let handles = iter::repeat_with(|| {
+			let released = released.clone();
+			pool.execute(move || {
+				let _released = released.recv();
+			})
+			.unwrap()
+		})
+		.take(3)
+		.collect::<Vec<_>>();
+		assert!(wait_for(|| pool.stats.busy() == 1));
+		assert_eq!(pool.stats.workers(), 1);
+
+		for _ in 0..3 {
+			release.send(()).unwrap();
+		}
+		for handle in handles {
+			handle.join().unwrap();
 		}
 	}
 }
@@ -0,0 +1,80 @@
+use std::{
+	fmt::{self, Display, Formatter},
+	time::Duration,
+};
+
+use anyhow::Error;
+
+use crate::options::{format_duration, OptionKind, Options, ProblemOption};
+
+// how often open connections are checked for timeouts, so a timeout can be up to this late
+pub(crate) const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// the timeout options of a TCP problem, with the defaults for the problem
+pub(crate) const fn options(idle: &'static str, write: &'static str, session: &'static str) -> [ProblemOption; 3] {
+	[
+		ProblemOption {
+			name: "idle-timeout",
+			kind: OptionKind::Timeout,
+			default: idle,
+			description: "Time to wait for data from the client before closing the connection",
+		},
+		ProblemOption {
+			name: "write-timeout",
+			kind: OptionKind::Timeout,
+			default: write,
+			description: "Time a write to the client may block before closing the connection",
+		},
+		ProblemOption {
+			name: "session-timeout",
+			kind: OptionKind::Timeout,
+			default: session,
+			description: "Time a connection may stay open",
+		},
+	]
+}
+
+// the timeouts applied to every connection of a server, None for no timeout
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Timeouts {
+	pub(crate) idle: Option<Duration>,
+	pub(crate) write: Option<Duration>,
+	pub(crate) session: Option<Duration>,
+}
+
+impl Timeouts {
+	pub(crate) fn from_options(options: &Options) -> Result<Self, Error> {
+		Ok(Self {
+			idle: options.timeout("idle-timeout")?,
+			write: options.timeout("write-timeout")?,
+			session: options.timeout("session-timeout")?,
+		})
+	}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Timeout {
+	Idle(Duration),
+	Write(Duration),
+	Session(Duration),
+}
+
+impl Timeout {
+	pub(crate) const fn name(self) -> &'static str {
+		match self {
+			Self::Idle(_) => "idle",
+			Self::Write(_) => "write",
+			Self::Session(_) => "session",
+		}
+	}
+}
+
+impl Display for Timeout {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		match *self {
+			Self::Idle(timeout) => write!(f, "no data received for {}", format_duration(timeout)),
+			Self::Write(timeout) => write!(f, "write blocked for {}", format_duration(timeout)),
+			Self::Session(timeout) => write!(f, "open for {}", format_duration(timeout)),
+		}
+	}
+}
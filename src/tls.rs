@@ -0,0 +1,488 @@
+use std::{
+	fs::File,
+	future::poll_fn,
+	io::{self, BufReader, ErrorKind, Read, Write},
+	net::{Shutdown, TcpStream},
+	path::{Path, PathBuf},
+	pin::Pin,
+	sync::Arc,
+	task::{ready, Context, Poll},
+};
+
+use anyhow::{anyhow, Error};
+use parking_lot::Mutex;
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection};
+use rustls_pemfile::Item;
+use socket2::SockRef;
+use tokio::{
+	io::{AsyncRead, AsyncWrite, ReadBuf},
+	net::TcpStream as AsyncTcpStream,
+};
+
+use crate::stream::{AsyncStream, Stream};
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+// the PEM encoded certificate chain and private key of the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TlsFiles {
+	pub(crate) cert: PathBuf,
+	pub(crate) key: PathBuf,
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, Error> {
+	File::open(path)
+		.map(BufReader::new)
+		.map_err(|e| anyhow!("Unable to open {}: {e}", path.display()))
+}
+
+pub(crate) fn load_config(files: &TlsFiles) -> Result<Arc<ServerConfig>, Error> {
+	let certs = rustls_pemfile::certs(&mut open(&files.cert)?)
+		.map_err(|e| anyhow!("Unable to read certificates from {}: {e}", files.cert.display()))?;
+	if certs.is_empty() {
+		return Err(anyhow!("No certificates found in {}", files.cert.display()));
+	}
+
+	let mut keys = open(&files.key)?;
+	let key = loop {
+		let item = rustls_pemfile::read_one(&mut keys)
+			.map_err(|e| anyhow!("Unable to read a private key from {}: {e}", files.key.display()))?;
+		match item {
+			Some(Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key)) => break key,
+			Some(_) => {},
+			None => return Err(anyhow!("No private key found in {}", files.key.display())),
+		}
+	};
+
+	let config = ServerConfig::builder()
+		.with_safe_defaults()
+		.with_no_client_auth()
+		.with_single_cert(certs.into_iter().map(Certificate).collect(), PrivateKey(key))
+		.map_err(|e| anyhow!("Invalid certificate or private key: {e}"))?;
+	Ok(Arc::new(config))
+}
+
+// the stream handed to a handler, a TLS session over the socket when TLS is configured
+pub(crate) fn wrap(socket: TcpStream, config: Option<&Arc<ServerConfig>>) -> Result<Box<dyn Stream>, Error> {
+	Ok(match config {
+		Some(config) => Box::new(TlsStream::accept(socket, config)?),
+		None => Box::new(socket),
+	})
+}
+
+// the session is shared by the clones of the stream and the socket is read without holding the session lock, so a
+// clone can write while another waits for data, only one clone should read at a time
+#[derive(Debug)]
+pub(crate) struct TlsStream {
+	session: Arc<Mutex<ServerConnection>>,
+	socket: TcpStream,
+}
+
+impl TlsStream {
+	// completes the handshake, which blocks until the client has taken part
+	fn accept(mut socket: TcpStream, config: &Arc<ServerConfig>) -> Result<Self, Error> {
+		let mut session = ServerConnection::new(Arc::clone(config))?;
+		while session.is_handshaking() {
+			let _transferred = session
+				.complete_io(&mut socket)
+				.map_err(|e| anyhow!("TLS handshake failed: {e}"))?;
+		}
+		Ok(Self {
+			session: Arc::new(Mutex::new(session)),
+			socket,
+		})
+	}
+
+	fn send_pending(&self, session: &mut ServerConnection) -> io::Result<()> {
+		while session.wants_write() {
+			let _written = session.write_tls(&mut &self.socket)?;
+		}
+		Ok(())
+	}
+
+	fn receive(&self, session: &mut ServerConnection, mut data: &[u8]) -> io::Result<()> {
+		// an empty read records the end of the stream, so the session is given every read, even an empty one
+		loop {
+			let _read = session.read_tls(&mut data)?;
+			if let Err(e) = session.process_new_packets() {
+				// the session queues an alert for the client
+				let _result = self.send_pending(session);
+				return Err(io::Error::new(ErrorKind::InvalidData, e));
+			}
+			if data.is_empty() {
+				return Ok(());
+			}
+		}
+	}
+}
+
+impl Read for TlsStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let mut encrypted = [0; READ_BUFFER_SIZE];
+		loop {
+			{
+				let mut session = self.session.lock();
+				match session.reader().read(buf) {
+					// many clients close the socket without a close notify, the problems served frame their own
+					// messages, so this ends the stream like any other close
+					Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+					Err(ref err) if err.kind() == ErrorKind::WouldBlock => {},
+					result => return result,
+				}
+				self.send_pending(&mut session)?;
+			}
+			let size = (&self.socket).read(&mut encrypted)?;
+			self.receive(&mut self.session.lock(), &encrypted[..size])?;
+		}
+	}
+}
+
+impl Write for TlsStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let mut session = self.session.lock();
+		let size = session.writer().write(buf)?;
+		self.send_pending(&mut session)?;
+		Ok(size)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		let mut session = self.session.lock();
+		session.writer().flush()?;
+		self.send_pending(&mut session)
+	}
+}
+
+impl Stream for TlsStream {
+	fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+		Ok(Box::new(Self {
+			session: Arc::clone(&self.session),
+			socket: self.socket.try_clone()?,
+		}))
+	}
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+		if how != Shutdown::Read {
+			let mut session = self.session.lock();
+			session.send_close_notify();
+			self.send_pending(&mut session)?;
+		}
+		self.socket.shutdown(how)
+	}
+}
+
+impl Drop for TlsStream {
+	// handlers only shut down the read side of a connection, so the session is closed when its last stream is dropped
+	fn drop(&mut self) {
+		if Arc::strong_count(&self.session) == 1 {
+			let mut session = self.session.lock();
+			session.send_close_notify();
+			// the client may already be gone
+			let _result = self.send_pending(&mut session);
+		}
+	}
+}
+
+// the async counterpart of wrap, the handshake is completed before the stream is returned
+pub(crate) async fn wrap_async(
+	socket: AsyncTcpStream,
+	config: Option<&Arc<ServerConfig>>,
+) -> Result<Box<dyn AsyncStream>, Error> {
+	Ok(match config {
+		Some(config) => Box::new(AsyncTlsStream::accept(socket, config).await?),
+		None => Box::new(socket),
+	})
+}
+
+// the socket of an async stream seen as a blocking socket by the session, a poll that is not ready is a would block
+// error, and the task is woken when the socket is ready
+struct PollSocket<'stream, 'cx, 'waker> {
+	socket: &'stream mut AsyncTcpStream,
+	cx: &'cx mut Context<'waker>,
+}
+
+impl Read for PollSocket<'_, '_, '_> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let mut buf = ReadBuf::new(buf);
+		match Pin::new(&mut *self.socket).poll_read(self.cx, &mut buf) {
+			Poll::Ready(result) => result.map(|()| buf.filled().len()),
+			Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+		}
+	}
+}
+
+impl Write for PollSocket<'_, '_, '_> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match Pin::new(&mut *self.socket).poll_write(self.cx, buf) {
+			Poll::Ready(result) => result,
+			Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+// async handlers split a stream into halves with tokio::io::split, so unlike TlsStream the session is not shared
+#[derive(Debug)]
+pub(crate) struct AsyncTlsStream {
+	session: ServerConnection,
+	socket: AsyncTcpStream,
+	close_notify_sent: bool,
+}
+
+impl AsyncTlsStream {
+	async fn accept(socket: AsyncTcpStream, config: &Arc<ServerConfig>) -> Result<Self, Error> {
+		let mut stream = Self {
+			session: ServerConnection::new(Arc::clone(config))?,
+			socket,
+			close_notify_sent: false,
+		};
+		poll_fn(|cx| stream.poll_handshake(cx))
+			.await
+			.map_err(|e| anyhow!("TLS handshake failed: {e}"))?;
+		Ok(stream)
+	}
+
+	fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		while self.session.is_handshaking() {
+			ready!(self.poll_send_pending(cx))?;
+			if ready!(self.poll_receive(cx))? == 0 {
+				return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+			}
+		}
+		self.poll_send_pending(cx)
+	}
+
+	fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		while self.session.wants_write() {
+			let mut socket = PollSocket {
+				socket: &mut self.socket,
+				cx,
+			};
+			match self.session.write_tls(&mut socket) {
+				Ok(_) => {},
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Poll::Pending,
+				Err(err) => return Poll::Ready(Err(err)),
+			}
+		}
+		Poll::Ready(Ok(()))
+	}
+
+	// reads from the socket into the session, zero bytes at the end of the stream, which the session records
+	fn poll_receive(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+		let mut socket = PollSocket {
+			socket: &mut self.socket,
+			cx,
+		};
+		let read = match self.session.read_tls(&mut socket) {
+			Ok(read) => read,
+			Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Poll::Pending,
+			Err(err) => return Poll::Ready(Err(err)),
+		};
+		if let Err(e) = self.session.process_new_packets() {
+			// the session queues an alert for the client
+			let _result = self.poll_send_pending(cx);
+			return Poll::Ready(Err(io::Error::new(ErrorKind::InvalidData, e)));
+		}
+		Poll::Ready(Ok(read))
+	}
+}
+
+impl AsyncRead for AsyncTlsStream {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		loop {
+			match this.session.reader().read(buf.initialize_unfilled()) {
+				Ok(size) => {
+					buf.advance(size);
+					return Poll::Ready(Ok(()));
+				},
+				// many clients close the socket without a close notify, as with TlsStream this ends the stream
+				Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => return Poll::Ready(Ok(())),
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => {},
+				Err(err) => return Poll::Ready(Err(err)),
+			}
+			// messages the session sends in reply to what it reads are sent as the socket has room, without holding up
+			// the reader
+			if let Poll::Ready(Err(e)) = this.poll_send_pending(cx) {
+				return Poll::Ready(Err(e));
+			}
+			let _read = ready!(this.poll_receive(cx))?;
+		}
+	}
+}
+
+impl AsyncWrite for AsyncTlsStream {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		// earlier writes are sent first, so a slow client holds up the writer rather than growing the session buffer
+		ready!(this.poll_send_pending(cx))?;
+		let size = this.session.writer().write(buf)?;
+		// what the socket has no room for now is sent by the next write or flush
+		if let Poll::Ready(Err(e)) = this.poll_send_pending(cx) {
+			return Poll::Ready(Err(e));
+		}
+		Poll::Ready(Ok(size))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		this.session.writer().flush()?;
+		this.poll_send_pending(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		if !this.close_notify_sent {
+			this.session.send_close_notify();
+			this.close_notify_sent = true;
+		}
+		ready!(this.poll_send_pending(cx))?;
+		Pin::new(&mut this.socket).poll_shutdown(cx)
+	}
+}
+
+impl AsyncStream for AsyncTlsStream {
+	// the close notify needs the session, which is only sent by poll_shutdown, or on drop
+	fn shutdown_socket(&self, how: Shutdown) -> io::Result<()> {
+		SockRef::from(&self.socket).shutdown(how)
+	}
+}
+
+impl Drop for AsyncTlsStream {
+	// a handler may drop the stream without shutting it down, what is left to send is sent if the socket has room
+	fn drop(&mut self) {
+		if !self.close_notify_sent {
+			self.session.send_close_notify();
+		}
+		let socket = SockRef::from(&self.socket);
+		while self.session.wants_write() {
+			// the client may already be gone
+			if !matches!(self.session.write_tls(&mut &*socket), Ok(size) if size > 0) {
+				break;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{io::BufRead, net::TcpListener, thread};
+
+	use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerName, StreamOwned};
+	use tokio::io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+
+	use super::*;
+	use crate::stream::testing::block_on;
+
+	// a self-signed certificate for localhost, generated with:
+	// openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -days 36500 -subj "/CN=localhost" \
+	//     -addext "subjectAltName=DNS:localhost" -addext "basicConstraints=critical,CA:FALSE" \
+	//     -keyout key.pem -out cert.pem
+	fn fixture(name: &str) -> PathBuf {
+		Path::new(env!("CARGO_MANIFEST_DIR"))
+			.join("tests/fixtures/tls")
+			.join(name)
+	}
+
+	fn client(socket: TcpStream) -> StreamOwned<ClientConnection, TcpStream> {
+		let mut roots = RootCertStore::empty();
+		for cert in rustls_pemfile::certs(&mut open(&fixture("cert.pem")).unwrap()).unwrap() {
+			roots.add(&Certificate(cert)).unwrap();
+		}
+		let config = ClientConfig::builder()
+			.with_safe_defaults()
+			.with_root_certificates(roots)
+			.with_no_client_auth();
+		let name = ServerName::try_from("localhost").unwrap();
+		StreamOwned::new(ClientConnection::new(Arc::new(config), name).unwrap(), socket)
+	}
+
+	#[test]
+	fn echo_over_tls() {
+		let config = load_config(&TlsFiles {
+			cert: fixture("cert.pem"),
+			key: fixture("key.pem"),
+		})
+		.unwrap();
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		let server = thread::spawn(move || {
+			let (socket, _) = listener.accept().unwrap();
+			let mut stream = TlsStream::accept(socket, &config).unwrap();
+			let mut line = String::new();
+			let _read = BufReader::new(stream.try_clone().unwrap())
+				.read_line(&mut line)
+				.unwrap();
+			stream.write_all(line.as_bytes()).unwrap();
+			stream.shutdown(Shutdown::Write).unwrap();
+		});
+
+		let mut client = client(TcpStream::connect(addr).unwrap());
+		client.write_all(b"hello\n").unwrap();
+		let mut response = vec![];
+		let _read = client.read_to_end(&mut response).unwrap();
+		server.join().unwrap();
+		assert_eq!(response, b"hello\n");
+	}
+
+	#[test]
+	fn echo_over_async_tls() {
+		let config = load_config(&TlsFiles {
+			cert: fixture("cert.pem"),
+			key: fixture("key.pem"),
+		})
+		.unwrap();
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		let server = thread::spawn(move || {
+			block_on(async {
+				listener.set_nonblocking(true).unwrap();
+				let (socket, _) = tokio::net::TcpListener::from_std(listener)
+					.unwrap()
+					.accept()
+					.await
+					.unwrap();
+				let stream = wrap_async(socket, Some(&config)).await.unwrap();
+				let (reader, mut writer) = split(stream);
+				let mut line = String::new();
+				let _read = AsyncBufReader::new(reader).read_line(&mut line).await.unwrap();
+				writer.write_all(line.as_bytes()).await.unwrap();
+				writer.shutdown().await.unwrap();
+			});
+		});
+
+		let mut client = client(TcpStream::connect(addr).unwrap());
+		client.write_all(b"hello\n").unwrap();
+		let mut response = vec![];
+		let _read = client.read_to_end(&mut response).unwrap();
+		server.join().unwrap();
+		assert_eq!(response, b"hello\n");
+	}
+
+	#[test]
+	fn async_handshake_fails_without_tls() {
+		let config = load_config(&TlsFiles {
+			cert: fixture("cert.pem"),
+			key: fixture("key.pem"),
+		})
+		.unwrap();
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		client.write_all(b"hello\n").unwrap();
+
+		let error = block_on(async {
+			listener.set_nonblocking(true).unwrap();
+			let (socket, _) = tokio::net::TcpListener::from_std(listener)
+				.unwrap()
+				.accept()
+				.await
+				.unwrap();
+			wrap_async(socket, Some(&config)).await.unwrap_err()
+		});
+		assert!(error.to_string().starts_with("TLS handshake failed: "), "{error}");
+	}
+}
@@ -0,0 +1,86 @@
+use std::{
+	env,
+	fs,
+	io::ErrorKind,
+	os::unix::{
+		fs::FileTypeExt,
+		net::{UnixDatagram, UnixListener, UnixStream},
+	},
+	path::{Path, PathBuf},
+	process,
+	sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{anyhow, Error};
+
+use crate::logger::{info, warning};
+
+// the file of a bound unix socket, which is removed when the socket is closed
+#[derive(Debug)]
+pub(crate) struct SocketFile {
+	path: PathBuf,
+}
+
+impl Drop for SocketFile {
+	fn drop(&mut self) {
+		match fs::remove_file(&self.path) {
+			Ok(()) => {},
+			Err(ref err) if err.kind() == ErrorKind::NotFound => {},
+			Err(err) => warning!("Unable to remove unix socket {}: {err}", self.path.display()),
+		}
+	}
+}
+
+// a socket file left behind by a server that did not shut down cleanly refuses connections and is removed, a path that
+// is not a socket, or a socket another server is still listening on, is left alone
+fn remove_stale(path: &Path, listening: fn(&Path) -> bool) -> Result<(), Error> {
+	let metadata = match fs::symlink_metadata(path) {
+		Ok(metadata) => metadata,
+		Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(anyhow!("Unable to bind unix socket {}: {err}", path.display())),
+	};
+	if !metadata.file_type().is_socket() {
+		return Err(anyhow!(
+			"Unable to bind unix socket {}: the path exists and is not a socket",
+			path.display()
+		));
+	}
+	if listening(path) {
+		return Err(anyhow!(
+			"Unable to bind unix socket {}: another server is listening on it",
+			path.display()
+		));
+	}
+	info!("Removing stale unix socket {}", path.display());
+	fs::remove_file(path).map_err(|e| anyhow!("Unable to remove stale unix socket {}: {e}", path.display()))
+}
+
+pub(crate) fn bind_listener(path: &Path) -> Result<(UnixListener, SocketFile), Error> {
+	remove_stale(path, |path| UnixStream::connect(path).is_ok())?;
+	let listener =
+		UnixListener::bind(path).map_err(|e| anyhow!("Unable to bind unix socket {}: {e}", path.display()))?;
+	Ok((listener, SocketFile {
+		path: PathBuf::from(path),
+	}))
+}
+
+pub(crate) fn bind_datagram(path: &Path) -> Result<(UnixDatagram, SocketFile), Error> {
+	remove_stale(path, |path| {
+		UnixDatagram::unbound().and_then(|socket| socket.connect(path)).is_ok()
+	})?;
+	let socket = UnixDatagram::bind(path).map_err(|e| anyhow!("Unable to bind unix socket {}: {e}", path.display()))?;
+	Ok((socket, SocketFile {
+		path: PathBuf::from(path),
+	}))
+}
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+// a client of a unix datagram server needs a path of its own for the server to reply to
+pub(crate) fn bind_client_datagram() -> Result<(UnixDatagram, SocketFile), Error> {
+	let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+	let path = env::temp_dir().join(format!("mitmaro-protohackers-client-{}-{id}.sock", process::id()));
+	let socket =
+		UnixDatagram::bind(&path).map_err(|e| anyhow!("Unable to bind unix socket {}: {e}", path.display()))?;
+	Ok((socket, SocketFile { path }))
+}
@@ -1,54 +1,181 @@
 use std::{
 	collections::HashMap,
-	net::{SocketAddr, UdpSocket},
+	io::{BufRead, Write},
+	sync::Arc,
+	time::Instant,
 };
 
 use anyhow::Error;
 use parking_lot::Mutex;
 
-use crate::UdpHandler;
+use crate::{
+	address::Address,
+	client::{self, DatagramClient, Load, LoadReport, ProblemClient},
+	datagram::{AsyncDatagram, Datagram},
+	handler::{AsyncUdpHandler, HandlerFuture, UdpHandler},
+	logger::debug,
+	metrics::{self, SampledGauge},
+	options::{OptionKind, ProblemOption},
+	registry::{Handler, Problem, Transport},
+};
 
 const VERSION: &str = "MM Key-Value Store: 1.0.0";
 
+pub(crate) const PROBLEM: Problem = Problem {
+	name: "unusualdatabaseprogram",
+	aliases: &["4", "kv"],
+	transport: Transport::Udp,
+	description: "Key-value store over UDP (problem 4)",
+	options: &[ProblemOption {
+		name: "max-datagram-size",
+		kind: OptionKind::Size,
+		default: "1024",
+		description: "Largest datagram accepted, longer datagrams are truncated",
+	}],
+	timeouts: &[],
+	create: |options| {
+		Ok(Handler::udp(UnusualDatabaseProgram::new(
+			options.size("max-datagram-size")?.get(),
+		)))
+	},
+	client: ProblemClient {
+		interactive: client,
+		load,
+		requests: "<key>=<value> to insert a value, <key> to retrieve it, version for the server version",
+	},
+};
+
+// the largest datagram of the protocol
+const CLIENT_BUFFER_SIZE: usize = 1000;
+
+// an insert has no response, anything else is a retrieve, which is waited for
+fn client(address: &Address, input: &mut dyn BufRead, output: &mut (dyn Write + Send)) -> Result<(), Error> {
+	let socket = DatagramClient::connect(address)?;
+	let mut buffer = [0; CLIENT_BUFFER_SIZE];
+	for line in input.lines() {
+		let line = line?;
+		let _size = socket.send(line.as_bytes())?;
+		if line.contains('=') {
+			continue;
+		}
+		if let Some(size) = socket.receive(&mut buffer)? {
+			output.write_all(&buffer[..size])?;
+			output.write_all(b"\n")?;
+		}
+		else {
+			writeln!(
+				output,
+				"No response within {}s, the request or response may have been lost",
+				client::DATAGRAM_TIMEOUT.as_secs()
+			)?;
+		}
+	}
+	Ok(())
+}
+
+// every request inserts a key of the connection and retrieves it, a lost datagram is an error
+fn load(address: &Address, load: &Load) -> LoadReport {
+	client::load_connections(load, |index, samples| {
+		let socket = DatagramClient::connect(address)?;
+		let mut buffer = [0; CLIENT_BUFFER_SIZE];
+		for request in 0..load.requests.get() {
+			let key = format!("connection{index}-{request}");
+			let insert = format!("{key}=value{request}");
+			let started = Instant::now();
+			let _size = socket.send(insert.as_bytes())?;
+			let _size = socket.send(key.as_bytes())?;
+			match socket.receive(&mut buffer)? {
+				Some(size) if buffer[..size] == *insert.as_bytes() => samples.responded(started.elapsed()),
+				_ => samples.failed(),
+			}
+		}
+		Ok(())
+	})
+}
+
 pub(crate) struct UnusualDatabaseProgram {
-	data: Mutex<HashMap<String, String>>,
+	max_datagram_size: usize,
+	data: Arc<Mutex<HashMap<String, String>>>,
+	// the store leaves the metrics once the problem is dropped
+	#[allow(dead_code)]
+	keys: SampledGauge,
 }
 
 impl UnusualDatabaseProgram {
-	pub(crate) fn new() -> Self {
+	pub(crate) fn new(max_datagram_size: usize) -> Self {
+		let data = Arc::new(Mutex::new(HashMap::<String, String>::new()));
+		let keys = Arc::clone(&data);
+		let keys = metrics::sampled_gauge(
+			"protohackers_unusualdatabaseprogram_keys",
+			"Keys stored in the key-value stores",
+			&[],
+			move || keys.lock().len() as u64,
+		);
 		Self {
-			data: Mutex::new(HashMap::new()),
+			max_datagram_size,
+			data,
+			keys,
 		}
 	}
-}
 
-impl UdpHandler for UnusualDatabaseProgram {
-	fn handler(&self, data: &[u8], socket: &mut UdpSocket, addr: SocketAddr) -> Result<(), Error> {
+	fn respond(&self, data: &[u8]) -> Option<String> {
 		let message = String::from(String::from_utf8_lossy(data));
 
 		if message == "version" {
-			eprintln!("Write: {}", VERSION);
-			let _ = socket.send_to(format!("version={}", VERSION).as_bytes(), addr)?;
-			return Ok(());
+			debug!("Write: {}", VERSION);
+			return Some(format!("version={}", VERSION));
 		}
 
 		if message.contains('=') {
 			let mut message_parsed = message.splitn(2, '=');
 			let key = message_parsed.next().unwrap_or_default();
 			let value = message_parsed.next().unwrap_or_default();
-			eprintln!("Write: {key} = '{value}'");
+			debug!("Write: {key} = '{value}'");
 			let _prev = self.data.lock().insert(String::from(key), String::from(value));
+			None
 		}
 		else {
 			let data_hashmap = self.data.lock();
-			eprintln!("Get: {message}");
+			debug!("Get: {message}");
 			if let Some(value) = data_hashmap.get(&message) {
-				let _ = socket.send_to(format!("{message}={value}").as_bytes(), addr)?;
+				Some(format!("{message}={value}"))
 			}
 			else {
-				let _ = socket.send_to(format!("{message}=").as_bytes(), addr)?;
+				Some(format!("{message}="))
 			}
 		}
+	}
+}
+
+impl UdpHandler for UnusualDatabaseProgram {
+	fn max_datagram_size(&self) -> usize {
+		self.max_datagram_size
+	}
+
+	fn handler(&self, data: &[u8], socket: &Datagram, addr: &Address) -> Result<(), Error> {
+		if let Some(response) = self.respond(data) {
+			let _ = socket.send_to(response.as_bytes(), addr)?;
+		}
 		Ok(())
 	}
 }
+
+impl AsyncUdpHandler for UnusualDatabaseProgram {
+	fn max_datagram_size(&self) -> usize {
+		self.max_datagram_size
+	}
+
+	fn handler<'handler>(
+		&'handler self,
+		data: &'handler [u8],
+		socket: &'handler AsyncDatagram,
+		addr: &'handler Address,
+	) -> HandlerFuture<'handler> {
+		Box::pin(async move {
+			if let Some(response) = self.respond(data) {
+				let _ = socket.send_to(response.as_bytes(), addr).await?;
+			}
+			Ok(())
+		})
+	}
+}
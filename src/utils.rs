@@ -1,3 +1,17 @@
+use std::any::Any;
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+	if let Some(message) = payload.downcast_ref::<&str>() {
+		message
+	}
+	else if let Some(message) = payload.downcast_ref::<String>() {
+		message.as_str()
+	}
+	else {
+		"unknown panic"
+	}
+}
+
 pub(crate) fn data_to_hex(data: &[u8]) -> String {
 	let mut hex = String::new();
 
@@ -1,31 +1,78 @@
-use std::thread::{spawn, JoinHandle};
+use std::{
+	sync::Arc,
+	thread::{spawn, JoinHandle},
+	time::{Duration, Instant},
+};
 
-use captur::capture;
-use crossbeam::channel::Receiver;
+use crate::{
+	job::JobLabel,
+	job_queue::{JobQueue, Next},
+	logger::{error, info, trace},
+	thread_pool::{PoolSize, PoolStats},
+	utils::panic_message,
+};
 
-use crate::job::Job;
+#[derive(Debug, Clone)]
+pub(crate) struct WorkerStatus {
+	pub(crate) id: usize,
+	// the running job and when it started, or None while the worker waits for a job
+	pub(crate) job: Option<(JobLabel, Instant)>,
+	pub(crate) jobs_completed: u64,
+	// time spent running jobs, including the running job
+	pub(crate) busy_time: Duration,
+}
 
+impl WorkerStatus {
+	pub(crate) const fn new(id: usize) -> Self {
+		Self {
+			id,
+			job: None,
+			jobs_completed: 0,
+			busy_time: Duration::ZERO,
+		}
+	}
+}
+
+#[derive(Debug)]
 pub(crate) struct Worker {
 	id: usize,
 	thread: Option<JoinHandle<()>>,
 }
 
 impl Worker {
-	pub(crate) fn new(id: usize, receiver: Receiver<Job>) -> Worker {
+	pub(crate) fn new(id: usize, queue: Arc<JobQueue>, stats: Arc<PoolStats>, size: PoolSize) -> Worker {
+		stats.add_status(id);
 		let thread = spawn(move || {
 			loop {
-				capture!(receiver);
-				eprintln!("Worker waiting: {}", id);
-
-				match receiver.recv() {
-					Ok(job) => {
-						eprintln!("Starting job on worker: {}", id);
-						job();
-						eprintln!("Ending job on worker: {}", id);
+				trace!("Worker waiting: {}", id);
+
+				match queue.pop(size.idle_timeout) {
+					Next::Job(job) => {
+						trace!("Starting job on worker: {}", id);
+						let client = job.label().client();
+						stats.job_started(id, job.label());
+						job.run();
+						stats.job_finished(id);
+						queue.finished(client);
+						trace!("Ending job on worker: {}", id);
+					},
+					Next::Timeout => {
+						if stats.retire_worker(size) {
+							info!(
+								"Retired worker {id} after {:?} idle, {} workers running",
+								size.idle_timeout,
+								stats.workers()
+							);
+							break;
+						}
+					},
+					Next::Closed => {
+						stats.worker_stopped();
+						break;
 					},
-					Err(_) => break,
 				}
 			}
+			stats.remove_status(id);
 		});
 
 		Worker {
@@ -38,7 +85,15 @@ impl Worker {
 		self.id
 	}
 
-	pub(crate) fn take(&mut self) -> Option<JoinHandle<()>> {
-		self.thread.take()
+	pub(crate) fn is_finished(&self) -> bool {
+		self.thread.as_ref().map_or(true, JoinHandle::is_finished)
+	}
+
+	pub(crate) fn join(&mut self) {
+		if let Some(thread) = self.thread.take() {
+			if let Err(payload) = thread.join() {
+				error!("Worker {} panicked: {}", self.id, panic_message(payload.as_ref()));
+			}
+		}
 	}
 }